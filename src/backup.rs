@@ -0,0 +1,634 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    error::Error,
+    node::{LeafValue, NodeKind},
+    node_view::NodeView,
+    options::Options,
+    page::{read_usize, Page},
+    page_layout::{NODE_GENERATION_OFFSET, NODE_GENERATION_SIZE, PAGE_SIZE, PTR_SIZE},
+    pager::{CommitPin, Config, Offset, Pager},
+};
+
+/// A copy of the database in progress, made from a pinned commit.
+///
+/// Only the pages reachable from the pinned commit are copied. A compacted
+/// copy is a database file of its own, with its pages one after the other.
+/// Pages are given their offset in the copy as soon as they are found, which
+/// lets every page be written once with the offsets of the pages it
+/// references.
+///
+/// A page backup instead keeps the pages as they are, along with their
+/// offset in the database file, leaving out the subtrees which did not
+/// change since the generation of its base backup. Page backups are
+/// restored by `BTree::restore_backups`.
+///
+/// Dropping a backup before it is complete gives it up like
+/// `BTree::abort_backup`, the pages of its commit being reused from the
+/// next commit on.
+pub struct Backup {
+    file: File,
+    /// Pin on the commit being copied, released once the copy is complete
+    pub(crate) pin: Option<CommitPin>,
+    /// Config of the copy, only written once every page is
+    config: Config,
+    /// Pages found but not copied yet
+    pending: Vec<PendingPage>,
+    /// Offset of the next page found
+    next_offset: usize,
+    /// Number of pages copied so far
+    copied: usize,
+    /// Generation of the base backup, for page backups
+    since: Option<u64>,
+}
+
+struct PendingPage {
+    kind: PageKind,
+    source: Offset,
+    copy: Offset,
+}
+
+#[derive(Clone, Copy)]
+enum PageKind {
+    Node,
+    /// A node of the catalog, whose values are the roots of other trees
+    CatalogNode,
+    /// An object or an overflowing key, copied as is
+    Object,
+}
+
+// Page backups start with a header page, followed by the config of the
+// commit they hold and by one record per page. Records are the offset of the
+// page in the database file, followed by the page.
+const PAGE_BACKUP_MAGIC: &[u8; 8] = b"ixpages1";
+const SINCE_OFFSET: usize = PAGE_BACKUP_MAGIC.len();
+const GENERATION_OFFSET: usize = SINCE_OFFSET + PTR_SIZE;
+const RECORD_COUNT_OFFSET: usize = GENERATION_OFFSET + PTR_SIZE;
+const RECORDS_OFFSET: usize = 2 * PAGE_SIZE;
+const RECORD_SIZE: usize = PTR_SIZE + PAGE_SIZE;
+
+/// Header of a complete page backup
+struct PageBackupHeader {
+    /// Generation of its base backup, or zero for a full backup
+    since: u64,
+    /// Generation of the commit it holds
+    generation: u64,
+    record_count: usize,
+}
+
+impl PageBackupHeader {
+    fn read(file: &mut File) -> Result<Self, Error> {
+        let mut page = [0; PAGE_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut page)
+            .map_err(|_| not_a_page_backup())?;
+        if &page[..SINCE_OFFSET] != PAGE_BACKUP_MAGIC {
+            return Err(not_a_page_backup());
+        }
+
+        Ok(Self {
+            since: read_usize(&page, SINCE_OFFSET)? as u64,
+            generation: read_usize(&page, GENERATION_OFFSET)? as u64,
+            record_count: read_usize(&page, RECORD_COUNT_OFFSET)?,
+        })
+    }
+
+    fn to_page(&self) -> Page {
+        let mut data = [0; PAGE_SIZE];
+        data[..SINCE_OFFSET].clone_from_slice(PAGE_BACKUP_MAGIC);
+        data[SINCE_OFFSET..GENERATION_OFFSET].clone_from_slice(&self.since.to_be_bytes());
+        data[GENERATION_OFFSET..RECORD_COUNT_OFFSET]
+            .clone_from_slice(&self.generation.to_be_bytes());
+        data[RECORD_COUNT_OFFSET..RECORD_COUNT_OFFSET + PTR_SIZE]
+            .clone_from_slice(&self.record_count.to_be_bytes());
+
+        Page::new(data)
+    }
+}
+
+fn not_a_page_backup() -> Error {
+    Error::InvalidBackup("Not a complete page backup".to_string())
+}
+
+/// Generation of the commit held by a page backup, to be used as the base of
+/// another one
+pub(crate) fn backup_generation(path: &Path) -> Result<u64, Error> {
+    Ok(PageBackupHeader::read(&mut File::open(path)?)?.generation)
+}
+
+impl Backup {
+    /// Start a copy of the commit of `config` to a new file. Page backups
+    /// leave out the nodes of generation `since` and older, along with
+    /// their subtrees.
+    pub(crate) fn create(
+        path: &Path,
+        config: &Config,
+        pin: CommitPin,
+        since: Option<u64>,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut backup = Self {
+            file,
+            pin: Some(pin),
+            config: Config::default(),
+            pending: vec![],
+            next_offset: PAGE_SIZE,
+            copied: 0,
+            since,
+        };
+        let root_page = config
+            .root_page
+            .to_owned()
+            .map(|offset| backup.add(PageKind::Node, offset));
+        let catalog_page = config
+            .catalog_page
+            .to_owned()
+            .map(|offset| backup.add(PageKind::CatalogNode, offset));
+        backup.config = match since {
+            Some(_) => config.clone(),
+            None => config.relocated(root_page, catalog_page),
+        };
+
+        Ok(backup)
+    }
+
+    /// Whether every page was copied
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Number of pages copied so far, leaving out the config
+    pub fn page_count(&self) -> usize {
+        self.copied
+    }
+
+    /// Give a page found in the commit its offset in the copy. Page backups
+    /// keep the offsets of the database file.
+    fn add(&mut self, kind: PageKind, source: Offset) -> Offset {
+        let copy = match self.since {
+            Some(_) => source.to_owned(),
+            None => {
+                self.next_offset += PAGE_SIZE;
+                Offset(self.next_offset - PAGE_SIZE)
+            }
+        };
+        self.pending.push(PendingPage {
+            kind,
+            source,
+            copy: copy.to_owned(),
+        });
+
+        copy
+    }
+
+    /// Copy up to `pages` pages, returning whether the copy is complete.
+    /// The config is written last, with the whole copy synced to disk.
+    pub(crate) fn step(&mut self, pager: &Pager, pages: usize) -> Result<bool, Error> {
+        for _ in 0..pages {
+            let Some(page) = self.pending.pop() else {
+                break;
+            };
+            self.copy_page(pager, page)?;
+        }
+
+        if self.is_done() {
+            self.write_config()?;
+            self.file.sync_all()?;
+        }
+
+        Ok(self.is_done())
+    }
+
+    fn write_config(&mut self) -> Result<(), Error> {
+        let config = Page::from(&self.config);
+        match self.since {
+            Some(since) => {
+                let header = PageBackupHeader {
+                    since,
+                    generation: self.config.generation,
+                    record_count: self.copied,
+                };
+                self.write_page(&Offset(PAGE_SIZE), config.as_bytes())?;
+                self.write_page(&Offset(0), header.to_page().as_bytes())
+            }
+            None => self.write_page(&Offset(0), config.as_bytes()),
+        }
+    }
+
+    fn copy_page(&mut self, pager: &Pager, page: PendingPage) -> Result<(), Error> {
+        let source = pager.get_page(&page.source)?;
+        let (mut node, generation) = match page.kind {
+            PageKind::Object => return self.write_copy(&page, &source),
+            PageKind::Node | PageKind::CatalogNode => {
+                let view = NodeView::new(source)?;
+                let generation = view.generation()?;
+                if matches!(self.since, Some(since) if generation <= since) {
+                    return Ok(());
+                }
+                (view.to_node(pager)?, generation)
+            }
+        };
+
+        match &mut node.node_kind {
+            NodeKind::Internal { children, .. } => {
+                for child in children {
+                    *child = self.add(page.kind, child.to_owned());
+                }
+            }
+            NodeKind::Leaf {
+                key_value_pairs, ..
+            } => {
+                for kv in key_value_pairs {
+                    if let Some(overflow) = &mut kv.key_overflow {
+                        *overflow = self.add(PageKind::Object, overflow.to_owned());
+                    }
+                    match (&mut kv.value, page.kind) {
+                        (LeafValue::Object(object), _) => {
+                            *object = self.add(PageKind::Object, object.to_owned());
+                        }
+                        (LeafValue::Number(root), PageKind::CatalogNode) => {
+                            let copy = self.add(PageKind::Node, Offset(*root as usize));
+                            *root = copy.0 as u64;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        match self.since {
+            Some(_) => self.write_copy(&page, &pager.get_page(&page.source)?),
+            None => {
+                // Nodes keep their generation in compacted copies as well
+                let mut data = Page::try_from(&node)?.get_data();
+                data[NODE_GENERATION_OFFSET..NODE_GENERATION_OFFSET + NODE_GENERATION_SIZE]
+                    .clone_from_slice(&generation.to_be_bytes());
+                self.write_copy(&page, &data)
+            }
+        }
+    }
+
+    /// Write the copy of a page, as a record of page backups
+    fn write_copy(&mut self, page: &PendingPage, data: &[u8]) -> Result<(), Error> {
+        match self.since {
+            Some(_) => {
+                let record = Offset(RECORDS_OFFSET + self.copied * RECORD_SIZE);
+                let mut bytes = Vec::with_capacity(RECORD_SIZE);
+                bytes.extend_from_slice(&page.copy.0.to_be_bytes());
+                bytes.extend_from_slice(data);
+                self.write_page(&record, &bytes)?;
+            }
+            None => self.write_page(&page.copy, data)?,
+        }
+        self.copied += 1;
+
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: &Offset, page: &[u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
+        self.file.write_all(page)?;
+
+        Ok(())
+    }
+}
+
+/// Restore a database from a chain of page backups, starting with a full
+/// backup followed by incremental ones, each based on the one before.
+///
+/// The pages of every backup are written at their offset in turn, leaving
+/// the database as of the last one. The pages which are not reachable from
+/// it are then freed.
+pub(crate) fn restore(chain: &[&Path], path: &Path, options: &Options) -> Result<(), Error> {
+    let mut backups = Vec::with_capacity(chain.len());
+    let mut generation = 0;
+    for (idx, backup_path) in chain.iter().enumerate() {
+        let mut file = File::open(backup_path)?;
+        let header = PageBackupHeader::read(&mut file)?;
+        if (idx == 0 && header.since != 0) || (idx > 0 && header.since != generation) {
+            return Err(Error::InvalidBackup(format!(
+                "{} is not based on the backup before it",
+                backup_path.display()
+            )));
+        }
+        generation = header.generation;
+        backups.push((file, header));
+    }
+
+    if backups.is_empty() {
+        return Err(Error::InvalidBackup("No backup to restore".to_string()));
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    let mut config = vec![0; PAGE_SIZE];
+    let mut record = vec![0; RECORD_SIZE];
+    for (backup, header) in &mut backups {
+        // The records follow the config
+        backup.seek(SeekFrom::Start(PAGE_SIZE as u64))?;
+        backup.read_exact(&mut config)?;
+        for _ in 0..header.record_count {
+            backup.read_exact(&mut record)?;
+            file.seek(SeekFrom::Start(read_usize(&record, 0)? as u64))?;
+            file.write_all(&record[PTR_SIZE..])?;
+        }
+    }
+
+    // The free list of the backed up file is not part of the backups
+    let config = Config::try_from(&config[..])?;
+    let config = config.relocated(config.root_page.to_owned(), config.catalog_page.to_owned());
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(Page::from(&config).as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    // Free the pages in reverse, each becoming the first free page
+    let mut pager = Pager::open(path, options)?;
+    let reachable = reachable_pages(&pager)?;
+    for (idx, _) in reachable
+        .iter()
+        .enumerate()
+        .skip(1)
+        .rev()
+        .filter(|(_, r)| !**r)
+    {
+        pager.free_page(&Offset(idx * PAGE_SIZE))?;
+    }
+    pager.write_config()?;
+    pager.sync()
+}
+
+/// Tell which pages of the file are reachable from its last commit
+fn reachable_pages(pager: &Pager) -> Result<Vec<bool>, Error> {
+    let mut reachable = vec![false; pager.get_file_size()? as usize / PAGE_SIZE];
+    let mut pending = vec![];
+    if let Some(root_page) = &pager.config.root_page {
+        pending.push((PageKind::Node, root_page.to_owned()));
+    }
+    if let Some(catalog_page) = &pager.config.catalog_page {
+        pending.push((PageKind::CatalogNode, catalog_page.to_owned()));
+    }
+
+    while let Some((kind, offset)) = pending.pop() {
+        reachable[offset.0 / PAGE_SIZE] = true;
+        if let PageKind::Object = kind {
+            continue;
+        }
+
+        let node = NodeView::new(pager.get_page(&offset)?)?;
+        if !node.is_leaf() {
+            pending.extend(node.children()?.into_iter().map(|child| (kind, child)));
+            continue;
+        }
+        for idx in 0..node.key_count() {
+            if let Some(overflow) = node.key_overflow(idx)? {
+                pending.push((PageKind::Object, overflow));
+            }
+            match (node.value(idx)?, kind) {
+                (LeafValue::Object(object), _) => pending.push((PageKind::Object, object)),
+                (LeafValue::Number(root), PageKind::CatalogNode) => {
+                    pending.push((PageKind::Node, Offset(root as usize)))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_backup_to() {
+        use crate::Database;
+
+        let path = std::env::temp_dir().join("inefficax_test_backup_source.db");
+        let copy_path = std::env::temp_dir().join("inefficax_test_backup_copy.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path).unwrap();
+
+        db.default_tree().enable_change_log().unwrap();
+        for i in 0..4000u64 {
+            db.default_tree().insert(format!("key{:05}", i), i).unwrap();
+        }
+        db.default_tree()
+            .delete_range("key01000".to_string().."key03000".to_string())
+            .unwrap();
+        let long_key = "k".repeat(500);
+        db.default_tree().insert(long_key.clone(), 7).unwrap();
+        let mut objects = db.open_tree("objects").unwrap();
+        for i in 0..100u64 {
+            objects
+                .insert_object(format!("object{:03}", i), vec![i as u8; 1000])
+                .unwrap();
+        }
+        drop(objects);
+
+        db.default_tree().backup_to(&copy_path).unwrap();
+        let size = db.default_tree().get_file_size().unwrap();
+        drop(db);
+
+        // The copy holds every tree, without the free pages
+        let mut copy = Database::open(&copy_path).unwrap();
+        assert!(copy.default_tree().get_file_size().unwrap() < size);
+        assert_eq!(copy.tree_names().unwrap(), vec!["objects"]);
+        assert_eq!(copy.default_tree().len().unwrap(), 2001);
+        assert_eq!(copy.default_tree().search("key00999").unwrap(), Some(999));
+        assert_eq!(copy.default_tree().search("key01000").unwrap(), None);
+        assert_eq!(copy.default_tree().search(long_key).unwrap(), Some(7));
+        assert_eq!(
+            copy.open_tree("objects")
+                .unwrap()
+                .search_object("object042")
+                .unwrap(),
+            Some(vec![42; 1000])
+        );
+        assert_eq!(
+            copy.default_tree().changes_since(0).unwrap().count(),
+            4000 + 2000 + 1 + 100
+        );
+
+        // The copy is a database of its own
+        copy.default_tree().insert("key01000", 1000).unwrap();
+        assert_eq!(copy.default_tree().len().unwrap(), 2002);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&copy_path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_with_writes() {
+        use crate::BTree;
+
+        let path = std::env::temp_dir().join("inefficax_test_backup_writes.db");
+        let copy_path = std::env::temp_dir().join("inefficax_test_backup_writes_copy.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db = BTree::open(&path).unwrap();
+
+        for i in 0..3000u64 {
+            db.insert(format!("key{:05}", i), i).unwrap();
+        }
+
+        // Writes go on while the backup is copied, without reaching it
+        let mut backup = db.start_backup(&copy_path).unwrap();
+        let mut i = 3000u64;
+        while !db.continue_backup(&mut backup, 1).unwrap() {
+            db.delete(format!("key{:05}", i - 3000)).unwrap();
+            db.insert(format!("key{:05}", i), i).unwrap();
+            i += 1;
+        }
+        assert!(i > 3000);
+
+        let mut copy = BTree::open(&copy_path).unwrap();
+        assert_eq!(copy.len().unwrap(), 3000);
+        assert_eq!(copy.search("key00000").unwrap(), Some(0));
+        assert_eq!(copy.search(format!("key{:05}", 3000)).unwrap(), None);
+        assert_eq!(
+            copy.get_file_size().unwrap(),
+            ((backup.page_count() + 1) * crate::PAGE_SIZE) as u64
+        );
+        assert_eq!(db.len().unwrap(), 3000);
+        assert_eq!(db.search("key00000").unwrap(), None);
+
+        // An aborted backup releases its commit as well
+        let backup = db.start_backup(&copy_path).unwrap();
+        db.abort_backup(backup).unwrap();
+        db.delete("key03000").unwrap();
+        assert_eq!(db.len().unwrap(), 2999);
+
+        // So does a dropped one, its pages being reused from the next commit
+        let overwrite = |db: &mut BTree| {
+            let size = db.get_file_size().unwrap();
+            for i in 0..100 {
+                db.insert("overwritten", i).unwrap();
+            }
+            db.get_file_size().unwrap() - size
+        };
+        overwrite(&mut db);
+        let backup = db.start_backup(&copy_path).unwrap();
+        assert!(overwrite(&mut db) > 0);
+        drop(backup);
+        overwrite(&mut db);
+        assert_eq!(overwrite(&mut db), 0);
+
+        // Backups are only continued by the database they were started from
+        let other_path = std::env::temp_dir().join("inefficax_test_backup_writes_other.db");
+        let _ = std::fs::remove_file(&other_path);
+        let mut other = BTree::open(&other_path).unwrap();
+        other.insert("other", 1).unwrap();
+        let mut backup = other.start_backup(&copy_path).unwrap();
+        assert!(matches!(
+            db.continue_backup(&mut backup, 1),
+            Err(crate::Error::InvalidBackup(_))
+        ));
+        assert!(other.continue_backup(&mut backup, usize::MAX).unwrap());
+        assert_eq!(
+            BTree::open(&copy_path).unwrap().search("other").unwrap(),
+            Some(1)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&copy_path).unwrap();
+        std::fs::remove_file(&other_path).unwrap();
+    }
+
+    #[test]
+    fn test_incremental_backups() {
+        use crate::{BTree, Database, Error};
+        use std::path::Path;
+
+        let dir = std::env::temp_dir();
+        let path = dir.join("inefficax_test_incremental.db");
+        let full = dir.join("inefficax_test_incremental_full.bak");
+        let first = dir.join("inefficax_test_incremental_1.bak");
+        let second = dir.join("inefficax_test_incremental_2.bak");
+        let restored = dir.join("inefficax_test_incremental_restored.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path).unwrap();
+
+        let key = |i: u64| format!("key{:05}", i);
+        for i in 0..5000u64 {
+            db.default_tree().insert(key(i), i).unwrap();
+        }
+        let mut objects = db.open_tree("objects").unwrap();
+        for i in 0..50u64 {
+            objects
+                .insert_object(format!("object{:03}", i), vec![i as u8; 1000])
+                .unwrap();
+        }
+        drop(objects);
+        db.default_tree().backup_pages_to(&full, None).unwrap();
+
+        // Incremental backups only hold the pages written since their base
+        for i in 0..10u64 {
+            db.default_tree().delete(key(i * 500)).unwrap();
+        }
+        db.default_tree()
+            .backup_pages_to(&first, Some(&full))
+            .unwrap();
+        let full_size = std::fs::metadata(&full).unwrap().len();
+        assert!(std::fs::metadata(&first).unwrap().len() * 5 < full_size);
+
+        db.default_tree().insert("new", 1).unwrap();
+        db.open_tree("objects")
+            .unwrap()
+            .insert_object("object999", vec![9; 1000])
+            .unwrap();
+        db.default_tree()
+            .backup_pages_to(&second, Some(&first))
+            .unwrap();
+
+        // A chain has to start with a full backup, each one based on the last
+        let result = BTree::restore_backups(&[&full, &second], &restored);
+        assert!(matches!(result, Err(Error::InvalidBackup(_))));
+        let result = BTree::restore_backups(&[&first, &second], &restored);
+        assert!(matches!(result, Err(Error::InvalidBackup(_))));
+
+        let chain: [&Path; 3] = [&full, &first, &second];
+        BTree::restore_backups(&chain, &restored).unwrap();
+        let mut copy = Database::open(&restored).unwrap();
+        let entries = |db: &mut Database| {
+            db.default_tree()
+                .range::<&[u8]>(..)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(entries(&mut copy), entries(&mut db));
+        assert_eq!(copy.default_tree().len().unwrap(), 4991);
+        assert_eq!(
+            copy.open_tree("objects")
+                .unwrap()
+                .search_object("object999")
+                .unwrap(),
+            Some(vec![9; 1000])
+        );
+        assert_eq!(copy.open_tree("objects").unwrap().len().unwrap(), 51);
+
+        // The pages which are not reachable are free to be reused
+        let size = copy.default_tree().get_file_size().unwrap();
+        for i in 0..10u64 {
+            copy.default_tree().delete(key(i * 500 + 1)).unwrap();
+        }
+        assert_eq!(copy.default_tree().get_file_size().unwrap(), size);
+
+        drop(db);
+        drop(copy);
+        for path in [path, full, first, second, restored] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/// A set of puts and deletes, applied together by `BTree::apply_batch` in a
+/// single commit.
+///
+/// Keys take up to `KEY_MAX_SIZE` bytes and objects up to `OBJECT_MAX_SIZE`
+/// bytes, like in `BTree::insert_object`. A batch with a longer key fails as
+/// a whole with `Error::KeyOverflowError`.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, BatchOp)>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Put(u64),
+    PutObject(Vec<u8>),
+    Delete,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of a key, replacing its current value if any
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: u64) -> &mut Self {
+        self.ops.push((key.into(), BatchOp::Put(value)));
+        self
+    }
+
+    /// Set the object of a key, replacing its current value if any
+    pub fn put_object(&mut self, key: impl Into<Vec<u8>>, object: Vec<u8>) -> &mut Self {
+        self.ops.push((key.into(), BatchOp::PutObject(object)));
+        self
+    }
+
+    /// Delete a key along with its object. Unlike `BTree::delete`, keys
+    /// which don't exist are ignored.
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push((key.into(), BatchOp::Delete));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<(Vec<u8>, BatchOp)> {
+        self.ops
+    }
+}
@@ -1,78 +1,335 @@
-use std::{path::Path, vec};
+use std::{
+    cmp::Ordering,
+    mem,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{mpsc, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+    vec,
+};
 
 use crate::{
+    backup::{self, Backup},
+    batch::{BatchOp, WriteBatch},
+    change_log::{decode_change, encode_change, Change, ChangeKind},
+    comparator::{Bytewise, Comparator},
+    cursor::Cursor,
+    encoding::Key,
     error::Error,
-    node::{KeyValuePair, Node, NodeKind},
+    node::{
+        internal_key_size, leaf_size, prefixed_leaf_size, shared_prefix_length, KeyValuePair,
+        LeafValue, Node, NodeKind,
+    },
+    node_view::NodeView,
+    options::Options,
     page::Page,
-    page_layout::{INTERNAL_HEADER_SIZE, LEAF_HEADER_SIZE, PTR_SIZE, VALUE_SIZE},
-    pager::{FreeQueue, Offset, Pager},
+    page_layout::{
+        INTERNAL_CHILD_SIZE, INTERNAL_HEADER_SIZE, KEY_MAX_SIZE, KEY_SPILL_SIZE, PTR_SIZE,
+        VALUE_INLINE_MAX_SIZE,
+    },
+    pager::{CommitPin, FreeQueue, Offset, Pager},
     PAGE_SIZE,
 };
 
 pub struct BTree {
     pager: Pager,
+    comparator: Arc<dyn Comparator>,
+    pub(crate) root: TreeRoot,
+    indexes: Vec<SecondaryIndex>,
+    /// Whether the file has a change log
+    change_log: bool,
+    /// Changes made by the ongoing write, logged when it is committed
+    pending_changes: Vec<PendingChange>,
+    watchers: Vec<Watcher>,
+}
+
+/// Sends the committed changes under a prefix of a tree
+struct Watcher {
+    /// Name of the named tree, if any
+    tree: Option<Vec<u8>>,
+    prefix: Vec<u8>,
+    sender: mpsc::Sender<Change>,
+}
+
+/// A change to the default tree or a named tree, before it is committed
+struct PendingChange {
+    sequence: u64,
+    /// Name of the named tree, if any
+    tree: Option<Vec<u8>>,
+    key: Vec<u8>,
+    /// The new value of the key, or None once deleted
+    value: Option<LeafValue>,
+    /// Whether the key held a value before
+    replaced: bool,
+}
+
+/// Where the root of the tree being worked on is kept
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TreeRoot {
+    /// The root page of the file config
+    Default,
+    /// The catalog of named trees, whose root is kept in the file config
+    Catalog,
+    /// A named tree, whose root is kept in the catalog
+    Named(Vec<u8>),
+    /// The tree of a secondary index, or the expiry tree of a tree, whose
+    /// root is kept in the catalog
+    Index(Vec<u8>),
+}
+
+/// A secondary index over the objects of a tree. Its entries are kept in a
+/// tree of their own, as the encoded index key followed by the primary key.
+#[derive(Clone)]
+struct SecondaryIndex {
+    /// Name of the tree of entries within the catalog
+    name: Vec<u8>,
+    /// The tree whose objects are indexed
+    table: TreeRoot,
+    extract: Arc<ExtractIndexKey>,
+}
+
+/// Returns the index key of an object, if it is to be indexed
+type ExtractIndexKey = dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// A value along with the sequence number of the write which set it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub sequence: u64,
 }
 
+/// Returns the value replacing the current pair of a key, if any
+type Swap<'a> =
+    dyn FnMut(&mut BTree, Option<&KeyValuePair>) -> Result<Option<Option<LeafValue>>, Error> + 'a;
+
+/// A key whose object changed, along with its old and new object
+type ObjectChange = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
 // Underflow at less than half of page size
 const UNDERFLOW_SPACE: usize = PAGE_SIZE / 3;
 
+// Internal trees of the catalog have names starting with a zero byte, and
+// are left out of the tree names
+const INTERNAL_TREE_PREFIX: u8 = 0x00;
+// The expiry tree of a tree holds the expiry time of every key expiring,
+// under 'k' followed by the key, and the same keys in expiry order, under
+// 'e' followed by the big endian time and the key
+const EXPIRY_TREE_NAME: &[u8] = b"\0expiry";
+const EXPIRY_BY_KEY: u8 = b'k';
+const KEY_BY_EXPIRY: u8 = b'e';
+// The change log holds the record of every change under its big endian
+// sequence number
+const CHANGE_LOG_NAME: &[u8] = b"\0changes";
+// Records too large for an object are split in chunks, kept under their
+// sequence number followed by the big endian chunk index, while the change
+// log holds their number of chunks
+const CHANGE_CHUNKS_NAME: &[u8] = b"\0changes/chunks";
+const RECORD_CHUNK_SIZE: usize = PAGE_SIZE - PTR_SIZE;
+// Secondary indexes are named after the encoded name of their tree, empty
+// for the default tree, followed by the name of the index
+const INDEX_TREE_NAME: &[u8] = b"\0index/";
+
 impl BTree {
     pub fn open(db_fp: &Path) -> Result<Self, Error> {
-        let mut pager = Pager::open(db_fp)?;
+        Self::open_with_options(db_fp, Options::default())
+    }
 
-        if pager.config.root_page == None {
-            let root = Node::new(
-                NodeKind::Leaf {
-                    next: None,
-                    previous: None,
-                    key_value_pairs: vec![],
-                    occupied_space: 0,
-                },
-                None,
-            );
-            let root_offset = pager.write_page(&Page::try_from(&root)?)?;
+    pub fn open_with_options(db_fp: &Path, options: Options) -> Result<Self, Error> {
+        let mut pager = Pager::open(db_fp, &options)?;
+
+        if pager.config.root_page.is_none() {
+            let root_offset = pager.write_page(&Page::try_from(&empty_leaf())?)?;
 
-            pager.set_root_page(root_offset)?;
+            pager.set_root_page(root_offset);
+            pager.commit(FreeQueue::new())?;
         }
 
-        Ok(Self { pager })
+        let mut btree = Self {
+            pager,
+            comparator: options.comparator,
+            root: TreeRoot::Default,
+            indexes: vec![],
+            change_log: false,
+            pending_changes: vec![],
+            watchers: vec![],
+        };
+        btree.change_log = btree.find_named_root(CHANGE_LOG_NAME)?.is_some();
+
+        Ok(btree)
     }
 
     pub fn get_file_size(&self) -> Result<u64, Error> {
         self.pager.get_file_size()
     }
 
+    /// Get a read-only view of the node at some offset. With a memory mapped
+    /// file the view reads straight from the map, without copying the page.
+    fn view_node(&self, offset: &Offset) -> Result<NodeView<'_>, Error> {
+        NodeView::new(self.pager.get_page(offset)?)
+    }
+
+    /// Read and fully parse the node at some offset, in order to modify it
+    fn read_node(&self, offset: &Offset) -> Result<Node, Error> {
+        self.view_node(offset)?.to_node(&self.pager)
+    }
+
     fn root_offset(&self) -> Result<Offset, Error> {
-        self.pager
-            .config
-            .root_page
-            .to_owned()
-            .ok_or(Error::InvalidRootOffset)
+        self.root_offset_of(&self.root)
     }
 
-    pub fn insert(&mut self, key: String, value: u64) -> Result<(), Error> {
-        let mut fq = FreeQueue::new();
+    fn root_offset_of(&self, root: &TreeRoot) -> Result<Offset, Error> {
+        match root {
+            TreeRoot::Default => self.pager.config.root_page.to_owned(),
+            TreeRoot::Catalog => self.pager.config.catalog_page.to_owned(),
+            TreeRoot::Named(name) | TreeRoot::Index(name) => return self.named_root(name),
+        }
+        .ok_or(Error::InvalidRootOffset)
+    }
 
-        let root_offset = self.root_offset()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let root = Node::try_from(root_page)?;
+    /// Switch to a new root for the tree being worked on. Named trees update
+    /// their entry in the catalog, freeing the catalog pages it replaces.
+    fn set_root(&mut self, fq: &mut FreeQueue, offset: Offset) -> Result<(), Error> {
+        match &self.root {
+            TreeRoot::Default => self.pager.set_root_page(offset),
+            TreeRoot::Catalog => self.pager.set_catalog_page(offset),
+            TreeRoot::Named(name) | TreeRoot::Index(name) => {
+                let name = name.to_owned();
+                let value = LeafValue::Number(offset.0 as u64);
+                self.in_catalog(|btree| btree.apply_changes(fq, vec![(name, Some(value))]))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take a sequence number for a write to the tree. Writes to the catalog,
+    /// indexes and expiry trees don't take any.
+    fn next_sequence(&mut self) -> u64 {
+        match self.root {
+            TreeRoot::Default | TreeRoot::Named(_) => self.pager.next_sequence(),
+            TreeRoot::Catalog | TreeRoot::Index(_) => 0,
+        }
+    }
+
+    /// Keys of the catalog and of indexes are ordered bytewise, whatever the
+    /// comparator of the trees
+    fn comparator(&self) -> &dyn Comparator {
+        match self.root {
+            TreeRoot::Catalog | TreeRoot::Index(_) => &Bytewise,
+            _ => self.comparator.as_ref(),
+        }
+    }
+
+    /// Insert a key, which can be any bytes. Strings work as well, and are
+    /// ordered by their UTF-8 bytes.
+    ///
+    /// Keys take from 1 to `KEY_MAX_SIZE` bytes (2 KiB, a quarter of a
+    /// page), longer ones are rejected with `Error::KeyOverflowError`. Keys
+    /// longer than 255 bytes are stored whole in an overflow object, with
+    /// only a prefix kept in the leaf. Internal nodes keep their separator
+    /// keys whole though, which is what caps the length of keys.
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: u64) -> Result<(), Error> {
+        self.insert_value(key.into(), LeafValue::Number(value))
+    }
+
+    /// Insert a key which expires at some time. Expired keys are treated as
+    /// missing by searches, ranges, neighbor lookups and counts, but keep
+    /// their pages until `purge_expired` deletes them. Inserting the key
+    /// again without an expiry time clears it.
+    pub fn insert_with_expiry(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: u64,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        let key = key.into();
+
+        self.write(|btree, fq| {
+            btree.insert_indexed(fq, key.clone(), LeafValue::Number(value))?;
+            btree.update_expiry(fq, vec![(key, Some(unix_millis(expires_at)))])
+        })
+    }
 
-        let status = self.insert_cow(&mut fq, root, &root_offset, KeyValuePair { key, value })?;
+    fn insert_value(&mut self, key: Vec<u8>, value: LeafValue) -> Result<(), Error> {
+        self.write(|btree, fq| {
+            btree.insert_indexed(fq, key.clone(), value)?;
+            btree.update_expiry(fq, vec![(key, None)])
+        })
+    }
+
+    /// Insert a key, moving it from its old object to the new one in the
+    /// indexes of the tree, and leaving the commit to the caller
+    fn insert_indexed(
+        &mut self,
+        fq: &mut FreeQueue,
+        key: Vec<u8>,
+        value: LeafValue,
+    ) -> Result<(), Error> {
+        if !self.has_indexes() {
+            self.insert_into_root(fq, key, value)?;
+            return Ok(());
+        }
+
+        let new_object = self.stored_object(&value)?;
+        let old_object = match self.insert_into_root(fq, key.clone(), value)? {
+            // Freed objects stay readable until the commit
+            Some(old_value) => self.stored_object(&old_value)?,
+            None => None,
+        };
+        self.update_indexes(fq, &[(key, old_object, new_object)])
+    }
+
+    /// Insert a key and switch to the new root, leaving the commit to the
+    /// caller. Returns the value the key held before, whose object is freed
+    /// but stays readable until the commit.
+    fn insert_into_root(
+        &mut self,
+        fq: &mut FreeQueue,
+        key: Vec<u8>,
+        value: LeafValue,
+    ) -> Result<Option<LeafValue>, Error> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > KEY_MAX_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+
+        let root_offset = self.root_offset()?;
+        let root = self.read_node(&root_offset)?;
+
+        let kv = KeyValuePair {
+            key,
+            key_overflow: None,
+            value,
+            sequence: self.next_sequence(),
+        };
+        // Whether the key is new is only known once inserted
+        let recorded = self
+            .recording()
+            .then(|| (kv.sequence, kv.key.clone(), kv.value.clone()));
+        let (replaced, status) = self.insert_cow(fq, root, &root_offset, kv)?;
+        if let Some((sequence, key, value)) = recorded {
+            self.record_change(sequence, &key, Some(&value), replaced.is_some())?;
+        }
         match status {
             InsertCOWStatus::NewOffset(o) => {
                 fq.add(root_offset);
-                self.pager.set_root_page(o)?;
+                self.set_root(fq, o)?;
             }
             InsertCOWStatus::DidSplit {
                 promoted_key,
                 first,
                 second,
+                first_count,
+                second_count,
             } => {
                 // The root node was split in two
                 let new_root_node = Node::new(
                     NodeKind::Internal {
                         keys: vec![promoted_key],
                         children: vec![first, second],
+                        counts: vec![first_count, second_count],
                         occupied_space: 0,
                     },
                     None,
@@ -82,21 +339,22 @@ impl BTree {
                 let new_root_offset = self.pager.write_page(&Page::try_from(&new_root_node)?)?;
                 fq.add(root_offset);
 
-                self.pager.set_root_page(new_root_offset)?;
+                self.set_root(fq, new_root_offset)?;
             }
         }
 
-        self.pager.free_pages(fq)?;
-        Ok(())
+        Ok(replaced)
     }
 
+    /// Insert a pair in the sub tree of a node, or replace the value of its
+    /// key, returning the value it replaced
     fn insert_cow(
         &mut self,
         fq: &mut FreeQueue,
         node: Node,
         node_offset: &Offset,
         kv: KeyValuePair,
-    ) -> Result<InsertCOWStatus, Error> {
+    ) -> Result<(Option<LeafValue>, InsertCOWStatus), Error> {
         // TODO: Need to update the new child's parent_node offset
         // TODO: Unless we find a way to never need the parent's offset...
 
@@ -104,25 +362,31 @@ impl BTree {
             NodeKind::Internal {
                 mut keys,
                 mut children,
+                mut counts,
                 occupied_space,
             } => {
                 // Find where to put this key
-                let idx = keys.binary_search(&kv.key).unwrap_or_else(|x| x);
+                let idx = keys
+                    .binary_search_by(|k| self.comparator().compare(k, &kv.key))
+                    .unwrap_or_else(|x| x);
                 let child_offset = children.get(idx).ok_or(Error::InternalNodeNoChild)?;
-                let child_page = self.pager.get_page(child_offset)?;
-                let child = Node::try_from(child_page)?;
+                let child = self.read_node(child_offset)?;
 
-                let status = self.insert_cow(fq, child, &child_offset, kv)?;
+                let (replaced, status) = self.insert_cow(fq, child, child_offset, kv)?;
 
                 match status {
                     InsertCOWStatus::NewOffset(new_child_offset) => {
                         // Update the child's offset
                         children[idx] = new_child_offset;
+                        if replaced.is_none() {
+                            counts[idx] += 1;
+                        }
                         // Write this node to disk
                         let o = self.pager.write_page(&Page::try_from(&Node::new(
                             NodeKind::Internal {
                                 keys,
                                 children,
+                                counts,
                                 occupied_space,
                             },
                             None,
@@ -130,26 +394,29 @@ impl BTree {
                         // Free the old version of this node
                         fq.add(node_offset.to_owned());
 
-                        Ok(InsertCOWStatus::NewOffset(o))
+                        Ok((replaced, InsertCOWStatus::NewOffset(o)))
                     }
                     InsertCOWStatus::DidSplit {
                         promoted_key,
                         first,
                         second,
+                        first_count,
+                        second_count,
                     } => {
                         let available_space = PAGE_SIZE - occupied_space;
-                        // A new key and a new child (reusing one child) + 1 for some reason?
-                        let required_space = PTR_SIZE + promoted_key.bytes().len() + 1 + 1;
+                        // A new key and a new child (reusing one child)
+                        let required_space = internal_key_size(&promoted_key);
 
                         if available_space < required_space {
                             // Add the new node and update child position
                             // THIS WILL OVERFLOW THIS NODE - make sure it is split later
                             children[idx] = first;
                             children.insert(idx + 1, second);
+                            counts[idx] = first_count;
+                            counts.insert(idx + 1, second_count);
                             keys.insert(idx, promoted_key);
 
-                            // TODO: Replace the following with find_median_key_idx:
-                            let median_idx = find_median_key_idx(&keys);
+                            let median_idx = find_split_key_idx(&keys);
                             if median_idx == 0 {
                                 return Err(Error::ImpossibleSplit);
                             }
@@ -160,12 +427,16 @@ impl BTree {
                             let new_promoted_key = sibling_keys.remove(0);
                             // Get the sibling's children
                             let sibling_children = children.split_off(median_idx + 1);
+                            let sibling_counts = counts.split_off(median_idx + 1);
+                            let first_count = counts.iter().sum();
+                            let second_count = sibling_counts.iter().sum();
 
                             let first_offset =
                                 self.pager.write_page(&Page::try_from(&Node::new(
                                     NodeKind::Internal {
                                         keys,
                                         children,
+                                        counts,
                                         occupied_space: 0,
                                     },
                                     None,
@@ -175,20 +446,28 @@ impl BTree {
                                     NodeKind::Internal {
                                         keys: sibling_keys,
                                         children: sibling_children,
+                                        counts: sibling_counts,
                                         occupied_space: 0,
                                     },
                                     None,
                                 ))?)?;
 
-                            Ok(InsertCOWStatus::DidSplit {
-                                promoted_key: new_promoted_key,
-                                first: first_offset,
-                                second: second_offset,
-                            })
+                            Ok((
+                                replaced,
+                                InsertCOWStatus::DidSplit {
+                                    promoted_key: new_promoted_key,
+                                    first: first_offset,
+                                    second: second_offset,
+                                    first_count,
+                                    second_count,
+                                },
+                            ))
                         } else {
                             // Add the new node and update child position
                             children[idx] = first;
                             children.insert(idx + 1, second);
+                            counts[idx] = first_count;
+                            counts.insert(idx + 1, second_count);
                             keys.insert(idx, promoted_key);
 
                             // Write this node to disk
@@ -196,6 +475,7 @@ impl BTree {
                                 NodeKind::Internal {
                                     keys,
                                     children,
+                                    counts,
                                     occupied_space,
                                 },
                                 None,
@@ -203,43 +483,53 @@ impl BTree {
                             // Free the old version of this node
                             fq.add(node_offset.to_owned());
 
-                            Ok(InsertCOWStatus::NewOffset(o))
+                            Ok((replaced, InsertCOWStatus::NewOffset(o)))
                         }
                     }
                 }
             }
             NodeKind::Leaf {
-                next,
-                previous,
                 mut key_value_pairs,
-                occupied_space,
+                occupied_space: _,
             } => {
-                let available_space = PAGE_SIZE - occupied_space;
-                let required_space = 1 + kv.key.bytes().len() + VALUE_SIZE;
+                let found = key_value_pairs
+                    .binary_search_by(|p| self.comparator().compare(&p.key, &kv.key));
+                let replaced = match found {
+                    // An existing key keeps its stored bytes, only taking the
+                    // new value, as in `change_leaf_pair`
+                    Ok(idx) => {
+                        let pair = &mut key_value_pairs[idx];
+                        pair.sequence = kv.sequence;
+                        let old_value = mem::replace(&mut pair.value, kv.value);
+                        if let LeafValue::Object(object) = &old_value {
+                            fq.add(object.to_owned());
+                        }
+                        Some(old_value)
+                    }
+                    // The overflow object of a long key is only written here,
+                    // so that nothing is left behind when the descent fails
+                    Err(idx) => {
+                        let mut kv = kv;
+                        if kv.key.len() > KEY_SPILL_SIZE {
+                            kv.key_overflow = Some(self.pager.write_object(&kv.key)?);
+                        }
+                        key_value_pairs.insert(idx, kv);
+                        None
+                    }
+                };
 
-                // Check if we have enough space to fit this key
-                if available_space < required_space {
+                // Check if the leaf still fits in a page. The new key may
+                // shorten the common prefix, growing every other key as well.
+                if leaf_size(&key_value_pairs) > PAGE_SIZE {
                     // Split the leaf node in two
-                    let (promoted_key, mut sibling_key_value_pairs) =
-                        split_key_value_pairs(&mut key_value_pairs)?;
+                    let (promoted_key, sibling_key_value_pairs) =
+                        split_key_value_pairs(&mut key_value_pairs, self.comparator())?;
 
-                    // We assume we have enough space now that the node is split
-                    // Insert into appropriate node
-                    if kv.key <= promoted_key {
-                        let idx = key_value_pairs.binary_search(&kv).unwrap_or_else(|x| x);
-                        key_value_pairs.insert(idx, kv);
-                    } else {
-                        let idx = sibling_key_value_pairs
-                            .binary_search(&kv)
-                            .unwrap_or_else(|x| x);
-                        sibling_key_value_pairs.insert(idx, kv);
-                    }
+                    let first_count = key_value_pairs.len() as u64;
+                    let second_count = sibling_key_value_pairs.len() as u64;
 
-                    // TODO: Next and previous
                     let sibling = Node::new(
                         NodeKind::Leaf {
-                            next: None,
-                            previous: None,
                             key_value_pairs: sibling_key_value_pairs,
                             occupied_space: 0, // Won't be used
                         },
@@ -247,83 +537,112 @@ impl BTree {
                     );
 
                     // Write this node and it's sibling to disk
-                    // let new_node_offset = self.pager.write_page(&Page::try_from(&node)?)?;
                     let new_node_offset = self.pager.write_page(&Page::try_from(&Node::new(
                         NodeKind::Leaf {
-                            next,
-                            previous,
                             key_value_pairs,
                             occupied_space: 0, // Won't be used
                         },
                         None,
                     ))?)?;
                     fq.add(node_offset.to_owned());
-                    // TODO: Update sibling's previous address before writing
                     let sibling_offset = self.pager.write_page(&Page::try_from(&sibling)?)?;
-                    // TODO: Update new_node's next address
 
-                    Ok(InsertCOWStatus::DidSplit {
-                        promoted_key,
-                        first: new_node_offset,
-                        second: sibling_offset,
-                    })
+                    Ok((
+                        replaced,
+                        InsertCOWStatus::DidSplit {
+                            promoted_key,
+                            first: new_node_offset,
+                            second: sibling_offset,
+                            first_count,
+                            second_count,
+                        },
+                    ))
                 } else {
-                    // Since we have enough space, we can simply insert the new kv
-                    let idx = key_value_pairs.binary_search(&kv).unwrap_or_else(|x| x);
-                    key_value_pairs.insert(idx, kv);
-
                     // Copy on write requires us to write the updated data to a new node
                     let new_addr = self.pager.write_page(&Page::try_from(&Node::new(
                         NodeKind::Leaf {
-                            next,
-                            previous,
                             key_value_pairs,
-                            occupied_space,
+                            occupied_space: 0, // Won't be used
                         },
                         None,
                     ))?)?;
                     fq.add(node_offset.to_owned());
 
                     // Return the new address to the parent node
-                    Ok(InsertCOWStatus::NewOffset(new_addr))
+                    Ok((replaced, InsertCOWStatus::NewOffset(new_addr)))
                 }
             }
         }
     }
 
-    pub fn delete(&mut self, key: &str) -> Result<Option<u64>, Error> {
-        let mut fq = FreeQueue::new();
+    /// Delete a key, returning its value. Keys holding an object are left
+    /// as they are with `Error::ValueKindMismatch`, see `delete_object`.
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<Option<u64>, Error> {
+        let key = key.as_ref();
+        let root_offset = self.root_offset()?;
+        if let Some(LeafValue::Object(_) | LeafValue::Inline(_)) =
+            self.search_node(&root_offset, key)?
+        {
+            return Err(Error::ValueKindMismatch);
+        }
+
+        let removed_value = self.write(|btree, fq| btree.delete_indexed(fq, key))?;
+        match removed_value {
+            Some(LeafValue::Number(value)) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Delete a key, removing its object from the indexes of the tree along
+    /// with its expiry time, and leaving the commit to the caller
+    fn delete_indexed(
+        &mut self,
+        fq: &mut FreeQueue,
+        key: &[u8],
+    ) -> Result<Option<LeafValue>, Error> {
+        let removed_value = self.delete_from_root(fq, key)?;
+
+        if let Some(value) = &removed_value {
+            self.record_delete(key)?;
+            if self.has_indexes() {
+                // Freed objects stay readable until the commit
+                let object = self.stored_object(value)?;
+                self.update_indexes(fq, &[(key.to_owned(), object, None)])?;
+            }
+            self.update_expiry(fq, vec![(key.to_owned(), None)])?;
+        }
+
+        Ok(removed_value)
+    }
 
+    /// Delete a key and switch to the new root, leaving the commit to the caller
+    fn delete_from_root(
+        &mut self,
+        fq: &mut FreeQueue,
+        key: &[u8],
+    ) -> Result<Option<LeafValue>, Error> {
         let root_offset = self.root_offset()?;
-        let root_page = self.pager.get_page(&root_offset)?;
-        let root = Node::try_from(root_page)?;
+        let root = self.read_node(&root_offset)?;
 
-        let (removed_value, status) = self.delete_cow(&mut fq, root, &root_offset, key)?;
+        let (removed_value, status) = self.delete_cow(fq, root, &root_offset, key)?;
 
         match status {
             DeleteCOWStatus::NewOffset(o) => {
                 fq.add(root_offset);
-                self.pager.set_root_page(o)?;
+                self.set_root(fq, o)?;
             }
             DeleteCOWStatus::DidUnderflow(node) => {
                 // It's totally fine for the root node to underflow, as long as it has enough keys.
                 // Save the new node as root and free old one
 
-                if let NodeKind::Internal {
-                    keys,
-                    children,
-                    occupied_space: _,
-                } = &node.node_kind
-                {
+                if let NodeKind::Internal { keys, children, .. } = &node.node_kind {
                     // If the node only has one child, aka keys.len() = 0, we promote
                     // that lonely child to be the new root. Otherwise we just write
                     // the underflowing but not lonely node to disk.
-                    if keys.len() == 0 {
+                    if keys.is_empty() {
                         // Promote this child
-                        self.pager.set_root_page(children[0].to_owned())?;
-
+                        self.set_root(fq, children[0].to_owned())?;
                         fq.add(root_offset);
-                        self.pager.free_pages(fq)?;
 
                         return Ok(removed_value);
                     }
@@ -331,11 +650,10 @@ impl BTree {
 
                 fq.add(root_offset);
                 let new_root_offset = self.pager.write_page(&Page::try_from(&node)?)?;
-                self.pager.set_root_page(new_root_offset)?;
+                self.set_root(fq, new_root_offset)?;
             }
         }
 
-        self.pager.free_pages(fq)?;
         Ok(removed_value)
     }
 
@@ -344,25 +662,29 @@ impl BTree {
         fq: &mut FreeQueue,
         node: Node,
         node_offset: &Offset,
-        key: &str,
-    ) -> Result<(Option<u64>, DeleteCOWStatus), Error> {
+        key: &[u8],
+    ) -> Result<(Option<LeafValue>, DeleteCOWStatus), Error> {
         match node.node_kind {
             NodeKind::Internal {
                 keys,
                 mut children,
+                mut counts,
                 occupied_space,
             } => {
-                let child_idx = keys.binary_search(&key.to_owned()).unwrap_or_else(|x| x);
+                let child_idx = keys
+                    .binary_search_by(|k| self.comparator().compare(k, key))
+                    .unwrap_or_else(|x| x);
 
                 // Get the child page
                 let child_offset = children.get(child_idx).ok_or(Error::InternalNodeNoChild)?;
-                let child_node = Node::try_from(self.pager.get_page(child_offset)?)?;
+                let child_node = self.read_node(child_offset)?;
 
                 let (removed_value, status) = self.delete_cow(fq, child_node, child_offset, key)?;
                 match status {
                     DeleteCOWStatus::NewOffset(o) => {
                         // Update the child position to the copy and free old
                         children[child_idx] = o;
+                        counts[child_idx] -= 1;
                         fq.add(node_offset.to_owned());
 
                         // Write this node and return success
@@ -370,6 +692,7 @@ impl BTree {
                             NodeKind::Internal {
                                 keys,
                                 children,
+                                counts,
                                 occupied_space,
                             },
                             None,
@@ -387,25 +710,31 @@ impl BTree {
                             unreachable!("DidUnderflow - only one child");
                         }
                         let sibling_offset = &children[sibling_idx];
-                        let sibling_node = Node::try_from(self.pager.get_page(&sibling_offset)?)?;
+                        let sibling_node = self.read_node(sibling_offset)?;
 
                         match node.node_kind {
                             NodeKind::Internal {
                                 keys: mut child_keys,
                                 children: mut child_children,
+                                counts: mut child_counts,
                                 occupied_space: child_occupied_space,
                             } => {
                                 if let NodeKind::Internal {
                                     keys: mut sibling_keys,
                                     children: mut sibling_children,
+                                    counts: mut sibling_counts,
                                     occupied_space: sibling_occupied_space,
                                 } = sibling_node.node_kind
                                 {
                                     // If it is possible to merge, we merge
                                     // Otherwise we borrow
+                                    // The merged node also gets the key between the two
+                                    // from this node, and the header of one of them
                                     if sibling_occupied_space + child_occupied_space
                                         - INTERNAL_HEADER_SIZE
-                                        < PAGE_SIZE
+                                        - INTERNAL_CHILD_SIZE
+                                        + internal_key_size(&keys[child_idx.min(sibling_idx)])
+                                        <= PAGE_SIZE
                                     {
                                         // println!("Merge - internal underflow + internal children");
 
@@ -420,8 +749,10 @@ impl BTree {
 
                                             sibling_keys.extend(child_keys);
                                             sibling_children.extend(child_children);
+                                            sibling_counts.extend(child_counts);
                                             child_keys = sibling_keys;
                                             child_children = sibling_children;
+                                            child_counts = sibling_counts;
                                         } else {
                                             // TODO: Could the added key possibly result in overflow?
                                             // Add the key in parent between the two children
@@ -430,9 +761,11 @@ impl BTree {
 
                                             child_keys.extend(sibling_keys);
                                             child_children.extend(sibling_children);
+                                            child_counts.extend(sibling_counts);
                                         }
 
                                         assert_eq!(child_children.len(), child_keys.len() + 1);
+                                        let child_count = child_counts.iter().sum();
 
                                         // Write the merged child to disk
                                         let new_child_offset =
@@ -440,6 +773,7 @@ impl BTree {
                                                 NodeKind::Internal {
                                                     keys: child_keys,
                                                     children: child_children,
+                                                    counts: child_counts,
                                                     occupied_space: 0,
                                                 },
                                                 None,
@@ -447,11 +781,14 @@ impl BTree {
 
                                         // TODO: Is it possible to avoid cloning?
                                         let mut new_children = children.clone();
+                                        let mut new_counts = counts.clone();
                                         let mut new_keys = keys.clone();
 
                                         // Update child and remove the merged sibling + key
                                         new_children[child_idx] = new_child_offset;
                                         new_children.remove(sibling_idx);
+                                        new_counts[child_idx] = child_count;
+                                        new_counts.remove(sibling_idx);
                                         let removed_key =
                                             new_keys.remove(child_idx.min(sibling_idx));
 
@@ -463,13 +800,14 @@ impl BTree {
                                         // Check whether or not the merge results in
                                         // this node underflowing
                                         // (occupied - removed key - removed sibling)
-                                        let new_occupied = occupied_space
-                                            - (removed_key.as_bytes().len() + 1 + PTR_SIZE);
+                                        let new_occupied =
+                                            occupied_space - internal_key_size(&removed_key);
 
                                         let new_node = Node::new(
                                             NodeKind::Internal {
                                                 keys: new_keys,
                                                 children: new_children,
+                                                counts: new_counts,
                                                 occupied_space: new_occupied,
                                             },
                                             None,
@@ -501,8 +839,9 @@ impl BTree {
                                                 .push(keys[child_idx.min(sibling_idx)].to_owned());
                                             child_keys.extend(sibling_keys);
                                             child_children.extend(sibling_children);
+                                            child_counts.extend(sibling_counts);
 
-                                            let median_idx = find_median_key_idx(&child_keys);
+                                            let median_idx = find_split_key_idx(&child_keys);
 
                                             if median_idx == 0 {
                                                 unreachable!("Impossible split");
@@ -515,13 +854,15 @@ impl BTree {
                                             // Get the sibling's children
                                             sibling_children =
                                                 child_children.split_off(median_idx + 1);
+                                            sibling_counts = child_counts.split_off(median_idx + 1);
                                         } else {
                                             sibling_keys
                                                 .push(keys[child_idx.min(sibling_idx)].to_owned());
                                             sibling_keys.extend(child_keys);
                                             sibling_children.extend(child_children);
+                                            sibling_counts.extend(child_counts);
 
-                                            let median_idx = find_median_key_idx(&sibling_keys);
+                                            let median_idx = find_split_key_idx(&sibling_keys);
                                             if median_idx == 0 {
                                                 unreachable!("Impossible split");
                                             }
@@ -532,11 +873,14 @@ impl BTree {
                                             median_key = child_keys.remove(0);
                                             // Get the child's children
                                             child_children =
-                                                sibling_children.split_off(median_idx + 1)
+                                                sibling_children.split_off(median_idx + 1);
+                                            child_counts = sibling_counts.split_off(median_idx + 1);
                                         }
 
                                         assert_eq!(child_children.len(), child_keys.len() + 1);
                                         assert_eq!(sibling_children.len(), sibling_keys.len() + 1);
+                                        let child_count = child_counts.iter().sum();
+                                        let sibling_count = sibling_counts.iter().sum();
 
                                         // Write the child and its sibling
                                         let new_child_offset =
@@ -544,6 +888,7 @@ impl BTree {
                                                 NodeKind::Internal {
                                                     keys: child_keys,
                                                     children: child_children,
+                                                    counts: child_counts,
                                                     occupied_space: 0,
                                                 },
                                                 None,
@@ -553,6 +898,7 @@ impl BTree {
                                                 NodeKind::Internal {
                                                     keys: sibling_keys,
                                                     children: sibling_children,
+                                                    counts: sibling_counts,
                                                     occupied_space: 0,
                                                 },
                                                 None,
@@ -560,11 +906,14 @@ impl BTree {
 
                                         // TODO: Is it possible to avoid cloning?
                                         let mut new_children = children.clone();
+                                        let mut new_counts = counts.clone();
                                         let mut new_keys = keys.clone();
 
                                         // Update child and remove the merged sibling + key
                                         new_children[sibling_idx] = new_sibling_offset;
                                         new_children[child_idx] = new_child_offset;
+                                        new_counts[sibling_idx] = sibling_count;
+                                        new_counts[child_idx] = child_count;
                                         // new_keys[if child_idx == 0 { 0 } else { child_idx - 1 }] =
                                         new_keys[child_idx.min(sibling_idx)] = median_key;
 
@@ -580,6 +929,7 @@ impl BTree {
                                                 NodeKind::Internal {
                                                     keys: new_keys,
                                                     children: new_children,
+                                                    counts: new_counts,
                                                     occupied_space: 0,
                                                 },
                                                 None,
@@ -593,40 +943,35 @@ impl BTree {
                                 }
                             }
                             NodeKind::Leaf {
-                                next: _,
-                                previous: _,
                                 key_value_pairs: mut child_kv_pairs,
-                                occupied_space: child_occupied_space,
+                                occupied_space: _,
                             } => {
                                 if let NodeKind::Leaf {
-                                    next: _,
-                                    previous: _,
                                     key_value_pairs: mut sibling_kv_pairs,
-                                    occupied_space: sibling_occupied_space,
+                                    occupied_space: _,
                                 } = sibling_node.node_kind
                                 {
+                                    // Gather all keys, in order
+                                    if sibling_idx < child_idx {
+                                        sibling_kv_pairs.extend(child_kv_pairs);
+                                        child_kv_pairs = sibling_kv_pairs;
+                                    } else {
+                                        child_kv_pairs.extend(sibling_kv_pairs);
+                                    }
+
                                     // If it is possible to merge, we merge
                                     // Otherwise we borrow
-                                    if sibling_occupied_space + child_occupied_space
-                                        - LEAF_HEADER_SIZE
-                                        < PAGE_SIZE
-                                    {
-                                        // Merge all keys
-                                        if sibling_idx < child_idx {
-                                            sibling_kv_pairs.extend(child_kv_pairs);
-                                            child_kv_pairs = sibling_kv_pairs;
-                                        } else {
-                                            child_kv_pairs.extend(sibling_kv_pairs);
-                                        }
-
+                                    // The merged keys may have a shorter common
+                                    // prefix than either node, so the size of the
+                                    // merged node has to be computed anew
+                                    if leaf_size(&child_kv_pairs) <= PAGE_SIZE {
                                         assert_ne!(child_kv_pairs.len(), 0);
+                                        let child_count = child_kv_pairs.len() as u64;
 
                                         // Write the child
                                         let new_child_offset =
                                             self.pager.write_page(&Page::try_from(&Node::new(
                                                 NodeKind::Leaf {
-                                                    next: None,
-                                                    previous: None,
                                                     key_value_pairs: child_kv_pairs,
                                                     occupied_space: 0,
                                                 },
@@ -635,11 +980,14 @@ impl BTree {
 
                                         // TODO: Is it possible to avoid cloning?
                                         let mut new_children = children.clone();
+                                        let mut new_counts = counts.clone();
                                         let mut new_keys = keys.clone();
 
                                         // Update child and remove the merged sibling + key
                                         new_children[child_idx] = new_child_offset;
                                         new_children.remove(sibling_idx);
+                                        new_counts[child_idx] = child_count;
+                                        new_counts.remove(sibling_idx);
                                         let removed_key =
                                             new_keys.remove(child_idx.min(sibling_idx));
 
@@ -651,13 +999,14 @@ impl BTree {
                                         // Check whether or not the merge results in
                                         // this node underflowing
                                         // (occupied - removed key - removed sibling)
-                                        let new_occupied = occupied_space
-                                            - (removed_key.as_bytes().len() + 1 + PTR_SIZE);
+                                        let new_occupied =
+                                            occupied_space - internal_key_size(&removed_key);
 
                                         let new_node = Node::new(
                                             NodeKind::Internal {
                                                 keys: new_keys,
                                                 children: new_children,
+                                                counts: new_counts,
                                                 occupied_space: new_occupied,
                                             },
                                             None,
@@ -680,18 +1029,20 @@ impl BTree {
                                         }
                                     } else {
                                         // println!("Split");
-                                        // Merge all keys and split in middle
-                                        let median_key;
-                                        // The split_key_value_pairs function assumes the keys are ordered
+                                        // Split the gathered keys in the middle
+                                        let (median_key, second_kv_pairs) = split_key_value_pairs(
+                                            &mut child_kv_pairs,
+                                            self.comparator(),
+                                        )?;
+                                        let sibling_kv_pairs;
                                         if child_idx < sibling_idx {
-                                            child_kv_pairs.extend(sibling_kv_pairs);
-                                            (median_key, sibling_kv_pairs) =
-                                                split_key_value_pairs(&mut child_kv_pairs)?;
+                                            sibling_kv_pairs = second_kv_pairs;
                                         } else {
-                                            sibling_kv_pairs.extend(child_kv_pairs);
-                                            (median_key, child_kv_pairs) =
-                                                split_key_value_pairs(&mut sibling_kv_pairs)?;
+                                            sibling_kv_pairs = child_kv_pairs;
+                                            child_kv_pairs = second_kv_pairs;
                                         }
+                                        let child_count = child_kv_pairs.len() as u64;
+                                        let sibling_count = sibling_kv_pairs.len() as u64;
 
                                         // println!(
                                         //     "Splitting - sibling first: {} - KVP ch: {} KVP sib: {}",
@@ -704,8 +1055,6 @@ impl BTree {
                                         let new_child_offset =
                                             self.pager.write_page(&Page::try_from(&Node::new(
                                                 NodeKind::Leaf {
-                                                    next: None,
-                                                    previous: None,
                                                     key_value_pairs: child_kv_pairs,
                                                     occupied_space: 0,
                                                 },
@@ -714,8 +1063,6 @@ impl BTree {
                                         let new_sibling_offset =
                                             self.pager.write_page(&Page::try_from(&Node::new(
                                                 NodeKind::Leaf {
-                                                    next: None,
-                                                    previous: None,
                                                     key_value_pairs: sibling_kv_pairs,
                                                     occupied_space: 0,
                                                 },
@@ -724,11 +1071,14 @@ impl BTree {
 
                                         // TODO: Is it possible to avoid cloning?
                                         let mut new_children = children.clone();
+                                        let mut new_counts = counts.clone();
                                         let mut new_keys = keys.clone();
 
                                         // Update child and remove the merged sibling + key
                                         new_children[sibling_idx] = new_sibling_offset;
                                         new_children[child_idx] = new_child_offset;
+                                        new_counts[sibling_idx] = sibling_count;
+                                        new_counts[child_idx] = child_count;
                                         // new_keys[if child_idx == 0 { 0 } else { child_idx - 1 }] =
                                         new_keys[child_idx.min(sibling_idx)] = median_key;
 
@@ -744,6 +1094,7 @@ impl BTree {
                                                 NodeKind::Internal {
                                                     keys: new_keys,
                                                     children: new_children,
+                                                    counts: new_counts,
                                                     occupied_space: 0,
                                                 },
                                                 None,
@@ -761,32 +1112,36 @@ impl BTree {
                 }
             }
             NodeKind::Leaf {
-                next,
-                previous,
                 mut key_value_pairs,
                 occupied_space,
             } => {
                 // Find the index of the value to remove
                 let idx = key_value_pairs
-                    .binary_search_by_key(&key, |kv| &*kv.key)
+                    .binary_search_by(|kv| self.comparator().compare(&kv.key, key))
                     .map_err(|_| Error::KeyNotFound(key.to_owned()))?;
 
-                // Remove and calculate the space difference
+                // Remove and calculate the new size, with a common prefix
+                // which may have grown
                 let removed = key_value_pairs.remove(idx);
-                let removed_space = removed.key.as_bytes().len() + 1 + VALUE_SIZE;
+                let new_occupied = leaf_size(&key_value_pairs);
+                if let Some(overflow) = &removed.key_overflow {
+                    fq.add(overflow.to_owned());
+                }
+                // The object of the key is freed in the same commit
+                if let LeafValue::Object(object) = &removed.value {
+                    fq.add(object.to_owned());
+                }
 
                 // This is fine on root:
                 // assert_ne!(key_value_pairs.len(), 0);
 
-                if occupied_space - removed_space < UNDERFLOW_SPACE {
+                if new_occupied < UNDERFLOW_SPACE {
                     Ok((
                         Some(removed.value),
                         DeleteCOWStatus::DidUnderflow(Node::new(
                             NodeKind::Leaf {
-                                next,
-                                previous,
                                 key_value_pairs,
-                                occupied_space: occupied_space - removed_space,
+                                occupied_space: new_occupied,
                             },
                             None,
                         )),
@@ -794,8 +1149,6 @@ impl BTree {
                 } else {
                     let offset = self.pager.write_page(&Page::try_from(&Node::new(
                         NodeKind::Leaf {
-                            next,
-                            previous,
                             key_value_pairs,
                             occupied_space,
                         },
@@ -809,39 +1162,49 @@ impl BTree {
         }
     }
 
-    pub fn search(&mut self, key: &str) -> Result<Option<u64>, Error> {
-        let root_page = self.pager.get_page(&self.root_offset()?)?;
-        let root_node = Node::try_from(root_page)?;
-
-        self.search_node(&root_node, &key)
+    /// Search for the value of a key, which must not hold an object
+    pub fn search(&mut self, key: impl AsRef<[u8]>) -> Result<Option<u64>, Error> {
+        Ok(self.search_with_meta(key)?.map(|versioned| versioned.value))
     }
 
-    fn search_node(&mut self, node: &Node, key: &str) -> Result<Option<u64>, Error> {
-        match &node.node_kind {
-            NodeKind::Internal {
-                keys,
-                children,
-                occupied_space: _,
-            } => {
-                let idx = keys.binary_search(&key.to_string()).unwrap_or_else(|x| x);
-                let child_offset = children.get(idx).ok_or(Error::InternalNodeNoChild)?;
-                let child_page = self.pager.get_page(child_offset)?;
-                let child_node = Node::try_from(child_page)?;
+    /// Search for the value of a key, along with the sequence number of the
+    /// write which set it
+    pub fn search_with_meta(
+        &mut self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Versioned<u64>>, Error> {
+        match self.search_live(key.as_ref())? {
+            Some((LeafValue::Number(value), sequence)) => Ok(Some(Versioned { value, sequence })),
+            Some(_) => Err(Error::ValueKindMismatch),
+            None => Ok(None),
+        }
+    }
 
-                self.search_node(&child_node, key)
-            }
-            NodeKind::Leaf {
-                next: _,
-                previous: _,
-                key_value_pairs,
-                occupied_space: _,
-            } => Ok(key_value_pairs
-                .binary_search_by_key(&key, |a| &a.key)
-                .ok()
-                .map(|x| key_value_pairs[x].value)),
+    /// Search for the value of a key and its sequence number, treating
+    /// expired keys as missing
+    fn search_live(&self, key: &[u8]) -> Result<Option<(LeafValue, u64)>, Error> {
+        let offset = self.root_offset()?;
+        match search_tree_entry(&self.pager, self.comparator(), &offset, key)? {
+            Some(_) if self.is_expired(key)? => Ok(None),
+            entry => Ok(entry),
         }
     }
 
+    /// Sequence number of the last write to any tree of the file
+    pub fn sequence(&self) -> u64 {
+        self.pager.sequence()
+    }
+
+    /// Generation of the last commit, which every commit bumps
+    pub fn generation(&self) -> u64 {
+        self.pager.generation()
+    }
+
+    /// Search the sub tree at some offset, without deserializing any nodes
+    fn search_node(&self, offset: &Offset, key: &[u8]) -> Result<Option<LeafValue>, Error> {
+        search_tree(&self.pager, self.comparator(), offset, key)
+    }
+
     pub fn print(&mut self) -> Result<(), Error> {
         println!();
 
@@ -854,18 +1217,24 @@ impl BTree {
     fn print_sub_tree(&mut self, prefix: String, offset: &Offset) -> Result<(), Error> {
         println!("{}Node at offset: {}", prefix, offset.0);
         let cur_prefix = format!("{}|->", prefix);
-        let page = self.pager.get_page(&offset)?;
-        let node = Node::try_from(page)?;
+        let node = self.read_node(offset)?;
 
         match node.node_kind {
             NodeKind::Internal {
                 keys,
                 children,
                 occupied_space,
+                ..
             } => {
                 println!("{}Internal child count: {:?}", cur_prefix, children.len());
                 println!("{}Occupied space: {:?}", cur_prefix, occupied_space);
-                println!("{}Keys: {:?}", cur_prefix, keys);
+                println!(
+                    "{}Keys: {:?}",
+                    cur_prefix,
+                    keys.iter()
+                        .map(|k| String::from_utf8_lossy(k))
+                        .collect::<Vec<_>>()
+                );
 
                 let child_prefix = format!("{}   |  ", prefix);
                 for child_offset in children {
@@ -875,8 +1244,6 @@ impl BTree {
                 Ok(())
             }
             NodeKind::Leaf {
-                next: _,
-                previous: _,
                 key_value_pairs,
                 occupied_space,
             } => {
@@ -886,7 +1253,14 @@ impl BTree {
                     key_value_pairs.len()
                 );
                 println!("{}Occupied space: {:?}", cur_prefix, occupied_space);
-                println!("{}Leaf kv-pairs: {:?}", cur_prefix, key_value_pairs);
+                println!(
+                    "{}Leaf kv-pairs: {:?}",
+                    cur_prefix,
+                    key_value_pairs
+                        .iter()
+                        .map(|kv| (String::from_utf8_lossy(&kv.key), &kv.value))
+                        .collect::<Vec<_>>()
+                );
                 Ok(())
             }
         }
@@ -894,29 +1268,17 @@ impl BTree {
 
     pub fn count_nodes(&mut self) -> Result<usize, Error> {
         fn sub(s: &mut BTree, offset: &Offset) -> Result<usize, Error> {
-            let page = s.pager.get_page(&offset)?;
-            let node = Node::try_from(page)?;
-
-            match node.node_kind {
-                NodeKind::Internal {
-                    keys: _,
-                    children,
-                    occupied_space: _,
-                } => {
-                    let mut sum = 0;
-                    for child_offset in children {
-                        let c = sub(s, &child_offset)?;
-                        sum += c;
-                    }
-                    Ok(sum + 1)
-                }
-                NodeKind::Leaf {
-                    next: _,
-                    previous: _,
-                    key_value_pairs: _,
-                    occupied_space: _,
-                } => Ok(1),
+            let node = s.view_node(offset)?;
+            if node.is_leaf() {
+                return Ok(1);
+            }
+
+            let mut sum = 0;
+            for child_offset in node.children()? {
+                let c = sub(s, &child_offset)?;
+                sum += c;
             }
+            Ok(sum + 1)
         }
 
         let offset = self.root_offset()?;
@@ -926,135 +1288,3934 @@ impl BTree {
     /// Get the depth of the current b-tree, including root.
     pub fn get_depth(&mut self) -> Result<usize, Error> {
         fn sub(s: &mut BTree, offset: &Offset) -> Result<usize, Error> {
-            let page = s.pager.get_page(&offset)?;
-            let node = Node::try_from(page)?;
-
-            match node.node_kind {
-                NodeKind::Internal {
-                    keys: _,
-                    children,
-                    occupied_space: _,
-                } => {
-                    let mut sum = 0;
-                    for child_offset in children {
-                        let c = sub(s, &child_offset)?;
-                        sum = sum.max(c);
-                    }
-                    Ok(sum + 1)
-                }
-                NodeKind::Leaf {
-                    next: _,
-                    previous: _,
-                    key_value_pairs: _,
-                    occupied_space: _,
-                } => Ok(1),
+            let node = s.view_node(offset)?;
+            if node.is_leaf() {
+                return Ok(1);
+            }
+
+            let mut sum = 0;
+            for child_offset in node.children()? {
+                let c = sub(s, &child_offset)?;
+                sum = sum.max(c);
             }
+            Ok(sum + 1)
         }
 
         let offset = self.root_offset()?;
         sub(self, &offset)
     }
 
-    pub fn insert_object(&mut self, key: String, object: Vec<u8>) -> Result<(), Error> {
-        let o = self.pager.write_object(&object)?;
+    /// Insert an object for a key. Objects up to `VALUE_INLINE_MAX_SIZE`
+    /// bytes are kept inline in the leaf, larger ones in the object store,
+    /// up to `OBJECT_MAX_SIZE` bytes.
+    ///
+    /// Keys take up to `KEY_MAX_SIZE` bytes like in `insert`, longer ones
+    /// are rejected with `Error::KeyOverflowError`.
+    pub fn insert_object(&mut self, key: impl Into<Vec<u8>>, object: Vec<u8>) -> Result<(), Error> {
+        self.insert_object_until(key.into(), object, None)
+    }
 
-        assert_eq!(object, self.pager.get_object(&o)?);
+    /// Insert an object for a key which expires at some time, see
+    /// `insert_with_expiry`
+    pub fn insert_object_with_expiry(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        object: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        self.insert_object_until(key.into(), object, Some(expires_at))
+    }
 
-        self.insert(key, o.0 as u64)?;
+    fn insert_object_until(
+        &mut self,
+        key: Vec<u8>,
+        object: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.write(|btree, fq| {
+            let value = object_value(&mut btree.pager, object)?;
+            btree.insert_indexed(fq, key.clone(), value)?;
+            btree.update_expiry(fq, vec![(key, expires_at.map(unix_millis))])
+        })
+    }
 
-        Ok(())
+    pub fn search_object(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .search_object_with_meta(key)?
+            .map(|versioned| versioned.value))
     }
 
-    pub fn search_object(&mut self, key: &str) -> Result<Option<Vec<u8>>, Error> {
-        let o = self.search(key)?;
-        if let Some(v) = o {
-            let v = Offset(v as usize);
-            let obj = self.pager.get_object(&v)?;
-            Ok(Some(obj))
-        } else {
-            Ok(None)
+    /// Search for the object of a key, along with the sequence number of the
+    /// write which set it
+    pub fn search_object_with_meta(
+        &mut self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Versioned<Vec<u8>>>, Error> {
+        let (value, sequence) = match self.search_live(key.as_ref())? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        match self.stored_object(&value)? {
+            Some(value) => Ok(Some(Versioned { value, sequence })),
+            None => Err(Error::ValueKindMismatch),
         }
     }
 
-    pub fn delete_object(&mut self, key: &str) -> Result<(), Error> {
-        let obj_offset = self.delete(key)?;
-
-        if let Some(obj_offset) = obj_offset {
-            let offset = Offset(obj_offset as usize);
-            self.pager.free_object(offset)?;
+    /// Delete a key along with its object, in one commit. Keys holding a
+    /// number are left as they are with `Error::ValueKindMismatch`, see `delete`.
+    pub fn delete_object(&mut self, key: impl AsRef<[u8]>) -> Result<(), Error> {
+        let key = key.as_ref();
+        let root_offset = self.root_offset()?;
+        if let Some(LeafValue::Number(_)) = self.search_node(&root_offset, key)? {
+            return Err(Error::ValueKindMismatch);
         }
 
+        self.write(|btree, fq| btree.delete_indexed(fq, key))?;
+
         Ok(())
     }
-}
 
-enum InsertCOWStatus {
-    NewOffset(Offset),
-    /// Returned when a node is split in two
-    DidSplit {
-        promoted_key: String,
-        first: Offset,
-        second: Offset,
-    },
-}
+    /// Set or delete the value of a key, only if its current value is
+    /// `expected`, with None standing for a missing key. Otherwise nothing is
+    /// written and the current value is returned.
+    ///
+    /// The check and the write happen in a single copy-on-write pass down
+    /// the path to the key, and a single commit.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<u64>,
+        new: Option<u64>,
+    ) -> Result<Result<(), Option<u64>>, Error> {
+        let mut current = None;
+        let swapped = self.swap_value(key.into(), &mut |_, kv| {
+            current = match kv.map(|kv| &kv.value) {
+                Some(LeafValue::Number(value)) => Some(*value),
+                Some(_) => return Err(Error::ValueKindMismatch),
+                None => None,
+            };
+
+            Ok((current == expected).then(|| new.map(LeafValue::Number)))
+        })?;
+
+        Ok(if swapped { Ok(()) } else { Err(current) })
+    }
 
-enum DeleteCOWStatus {
-    NewOffset(Offset),
-    /// Returned when a node underflows
-    DidUnderflow(Node),
-}
+    /// Set or delete the object of a key, only if its current object holds
+    /// the `expected` bytes, like `compare_and_swap`
+    pub fn compare_and_swap_object(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, Error> {
+        let mut current = None;
+        let mut new = Some(new);
+        let swapped = self.swap_value(key.into(), &mut |btree, kv| {
+            current = match kv.map(|kv| &kv.value) {
+                Some(LeafValue::Number(_)) => return Err(Error::ValueKindMismatch),
+                Some(value) => btree.stored_object(value)?,
+                None => None,
+            };
+            if current.as_deref() != expected {
+                return Ok(None);
+            }
 
-fn split_key_value_pairs(
-    key_value_pairs: &mut Vec<KeyValuePair>,
-) -> Result<(String, Vec<KeyValuePair>), Error> {
-    // Get the total length of all keys, in order to find the middle key
-    let total_key_size: usize = key_value_pairs.iter().map(|x| x.key.as_bytes().len()).sum();
+            let new = new
+                .take()
+                .flatten()
+                .map(|object| object_value(&mut btree.pager, object))
+                .transpose()?;
+            Ok(Some(new))
+        })?;
 
-    // Find the median key (total_key_size/2)
-    let mut key_sum = 0;
-    let mut median_idx = 0;
-    for (idx, kvp) in key_value_pairs.iter().enumerate() {
-        key_sum += kvp.key.as_bytes().len();
+        Ok(if swapped { Ok(()) } else { Err(current) })
+    }
 
-        if key_sum > total_key_size / 2 + 1 {
-            median_idx = idx;
-            break;
-        }
+    /// Set or delete the object of a key, only if it was last written with
+    /// the `expected` sequence number, with None standing for a missing key.
+    /// Otherwise nothing is written and the current sequence number is
+    /// returned, like `compare_and_swap`.
+    pub fn compare_and_swap_sequence(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<u64>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Result<(), Option<u64>>, Error> {
+        let mut current = None;
+        let mut new = Some(new);
+        let swapped = self.swap_value(key.into(), &mut |btree, kv| {
+            current = kv.map(|kv| kv.sequence);
+            if current != expected {
+                return Ok(None);
+            }
+
+            let new = new
+                .take()
+                .flatten()
+                .map(|object| object_value(&mut btree.pager, object))
+                .transpose()?;
+            Ok(Some(new))
+        })?;
+
+        Ok(if swapped { Ok(()) } else { Err(current) })
     }
 
-    if median_idx == 0 {
-        return Err(Error::ImpossibleSplit);
+    /// Fill an empty tree with pairs sorted by key, building it bottom-up
+    /// instead of inserting the pairs one by one. Nodes are filled up to
+    /// `fill_factor` of a page, leaving room for later inserts, and the whole
+    /// tree is written in a single commit.
+    ///
+    /// Keys must be strictly increasing, otherwise `UnsortedInput` is
+    /// returned and the tree is left empty.
+    pub fn bulk_load<K: Into<Vec<u8>>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, u64)>,
+        fill_factor: f64,
+    ) -> Result<(), Error> {
+        self.write(|btree, fq| {
+            btree.bulk_load_values(fq, pairs, fill_factor, |_, value| {
+                Ok(LeafValue::Number(value))
+            })
+        })
     }
 
-    // Get siblings pairs
-    let sibling_pairs = key_value_pairs.split_off(median_idx);
+    /// Fill an empty tree with objects sorted by key, like `bulk_load`
+    pub fn bulk_load_objects<K: Into<Vec<u8>>>(
+        &mut self,
+        objects: impl IntoIterator<Item = (K, Vec<u8>)>,
+        fill_factor: f64,
+    ) -> Result<(), Error> {
+        // The tree is checked to be empty, so no key has an old object to
+        // take out of the indexes
+        let indexed = self.has_indexes();
+        self.write(|btree, fq| {
+            let mut changes = vec![];
+            let objects = objects.into_iter().map(|(key, object)| {
+                let key = key.into();
+                if indexed {
+                    changes.push((key.clone(), None, Some(object.clone())));
+                }
+                (key, object)
+            });
 
-    // Get the median key
-    let median_key = key_value_pairs
-        .get(median_idx - 1)
-        .ok_or(Error::ImpossibleSplit)?
-        .key
-        .to_owned();
+            btree.bulk_load_values(fq, objects, fill_factor, object_value)?;
+            btree.update_indexes(fq, &changes)
+        })
+    }
 
-    Ok((median_key, sibling_pairs))
-}
+    /// Build the tree out of sorted pairs and switch to its root, leaving the
+    /// commit to the caller
+    fn bulk_load_values<K: Into<Vec<u8>>, V>(
+        &mut self,
+        fq: &mut FreeQueue,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+        to_value: impl FnMut(&mut Pager, V) -> Result<LeafValue, Error>,
+    ) -> Result<(), Error> {
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(Error::InvalidFillFactor(fill_factor));
+        }
+        let target_size = (PAGE_SIZE as f64 * fill_factor) as usize;
 
-fn find_median_key_idx(keys: &Vec<String>) -> usize {
-    // Get the total length of all keys, in order to find the middle key
-    // TODO: Use occupied_space instead?
-    let total_key_size: usize = keys.iter().map(|x| x.as_bytes().len()).sum();
+        let root_offset = self.root_offset()?;
+        let root = self.view_node(&root_offset)?;
+        if !root.is_leaf() || root.key_count() > 0 {
+            return Err(Error::TreeNotEmpty);
+        }
 
-    // Find the median key (total_key_size/2)
-    let mut key_sum = 0;
-    let mut median_idx = 0;
-    for (idx, key) in keys.iter().enumerate() {
-        key_sum += key.as_bytes().len();
+        // The input is only checked as it is written. The pages of a failed
+        // load are freed by the rollback of the write.
+        let new_root_offset = self.build_bulk_tree(pairs, target_size, to_value)?;
+        fq.add(root_offset);
+        self.set_root(fq, new_root_offset)
+    }
 
-        if key_sum > total_key_size / 2 + 1 {
-            median_idx = idx;
-            break;
+    /// Write the nodes of a tree out of sorted pairs, returning its root
+    fn build_bulk_tree<K: Into<Vec<u8>>, V>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        target_size: usize,
+        mut to_value: impl FnMut(&mut Pager, V) -> Result<LeafValue, Error>,
+    ) -> Result<Offset, Error> {
+        let mut level = BulkLevel::default();
+
+        // Full leaves are written one behind, so that the last two can be
+        // rebalanced once the input runs out
+        let mut pending: Option<Vec<KeyValuePair>> = None;
+        let mut leaf: Vec<KeyValuePair> = vec![];
+        let mut prefix_length = 0;
+        let mut pairs_size = 0;
+
+        for (key, value) in pairs {
+            let key = key.into();
+            if key.is_empty() {
+                return Err(Error::EmptyKey);
+            }
+            if key.len() > KEY_MAX_SIZE {
+                return Err(Error::KeyOverflowError);
+            }
+
+            if let Some(previous) = leaf.last() {
+                if self.comparator().compare(&previous.key, &key) != Ordering::Less {
+                    return Err(Error::UnsortedInput {
+                        previous: previous.key.clone(),
+                        key,
+                    });
+                }
+            }
+
+            let key_overflow = if key.len() > KEY_SPILL_SIZE {
+                Some(self.pager.write_object(&key)?)
+            } else {
+                None
+            };
+            let kv = KeyValuePair {
+                key,
+                key_overflow,
+                value: to_value(&mut self.pager, value)?,
+                sequence: self.next_sequence(),
+            };
+            self.record_change(kv.sequence, &kv.key, Some(&kv.value), false)?;
+
+            // Keep track of the leaf size as pairs are added, rather than
+            // computing it again for every pair
+            let new_prefix_length = match leaf.first() {
+                Some(first) => {
+                    prefix_length.min(shared_prefix_length(first.inline_key(), kv.inline_key()))
+                }
+                None => kv.inline_key().len(),
+            };
+            let new_size =
+                prefixed_leaf_size(new_prefix_length, pairs_size + kv.size(), leaf.len() + 1);
+
+            if !leaf.is_empty() && new_size > target_size {
+                if let Some(full) = pending.replace(mem::take(&mut leaf)) {
+                    self.write_bulk_leaf(&mut level, full)?;
+                }
+
+                prefix_length = kv.inline_key().len();
+                pairs_size = kv.size();
+            } else {
+                prefix_length = new_prefix_length;
+                pairs_size += kv.size();
+            }
+
+            leaf.push(kv);
+        }
+
+        // The last leaf must not underflow, so it is merged with the one
+        // before, and split again evenly if they don't fit in a page
+        match pending {
+            Some(mut full) if leaf_size(&leaf) < UNDERFLOW_SPACE => {
+                full.append(&mut leaf);
+
+                if leaf_size(&full) > PAGE_SIZE {
+                    let (_, second) = split_key_value_pairs(&mut full, self.comparator())?;
+                    self.write_bulk_leaf(&mut level, full)?;
+                    self.write_bulk_leaf(&mut level, second)?;
+                } else {
+                    self.write_bulk_leaf(&mut level, full)?;
+                }
+            }
+            Some(full) => {
+                self.write_bulk_leaf(&mut level, full)?;
+                self.write_bulk_leaf(&mut level, leaf)?;
+            }
+            None => self.write_bulk_leaf(&mut level, leaf)?,
+        }
+
+        // Build the internal levels bottom-up, until a single root is left
+        let BulkLevel {
+            mut children,
+            mut separators,
+            ..
+        } = level;
+        while children.len() > 1 {
+            let mut groups = vec![];
+            let mut promoted = vec![];
+
+            let mut level_children = children.into_iter();
+            let mut group_children: Vec<(Offset, u64)> =
+                level_children.next().into_iter().collect();
+            let mut group_keys = vec![];
+            for (child, separator) in level_children.zip(separators) {
+                let size = internal_size(&group_keys) + internal_key_size(&separator);
+                if group_children.len() > 1 && size > target_size {
+                    groups.push((mem::take(&mut group_children), mem::take(&mut group_keys)));
+                    promoted.push(separator);
+                } else {
+                    group_keys.push(separator);
+                }
+
+                group_children.push(child);
+            }
+
+            // The last node must not underflow nor be left with one child
+            if group_children.len() < 2 || internal_size(&group_keys) < UNDERFLOW_SPACE {
+                if let (Some((mut merged_children, mut merged_keys)), Some(separator)) =
+                    (groups.pop(), promoted.pop())
+                {
+                    merged_keys.push(separator);
+                    merged_keys.append(&mut group_keys);
+                    merged_children.append(&mut group_children);
+
+                    if internal_size(&merged_keys) > PAGE_SIZE {
+                        let split_idx = find_split_key_idx(&merged_keys);
+                        group_keys = merged_keys.split_off(split_idx + 1);
+                        group_children = merged_children.split_off(split_idx + 1);
+                        promoted.push(merged_keys.pop().ok_or(Error::ImpossibleSplit)?);
+                        groups.push((merged_children, merged_keys));
+                    } else {
+                        group_keys = merged_keys;
+                        group_children = merged_children;
+                    }
+                }
+            }
+            groups.push((group_children, group_keys));
+
+            let mut offsets = vec![];
+            for (children, keys) in groups {
+                let node = internal_node(keys, children);
+                let count = node.entry_count();
+                let offset = self.pager.write_page(&Page::try_from(&node)?)?;
+                offsets.push((offset, count));
+            }
+
+            children = offsets;
+            separators = promoted;
         }
+
+        let (new_root_offset, _) = children.pop().ok_or(Error::InvalidRootOffset)?;
+
+        Ok(new_root_offset)
     }
 
-    median_idx
+    /// Write a leaf at the end of the bottom level of a tree being bulk loaded
+    fn write_bulk_leaf(
+        &mut self,
+        level: &mut BulkLevel,
+        key_value_pairs: Vec<KeyValuePair>,
+    ) -> Result<(), Error> {
+        if let (Some(last), Some(first)) = (&level.last_key, key_value_pairs.first()) {
+            level
+                .separators
+                .push(self.comparator().separator(last, &first.key));
+        }
+        level.last_key = key_value_pairs.last().map(|kv| kv.key.clone());
+
+        let count = key_value_pairs.len() as u64;
+        let node = Node::new(
+            NodeKind::Leaf {
+                key_value_pairs,
+                occupied_space: 0,
+            },
+            None,
+        );
+        let offset = self.pager.write_page(&Page::try_from(&node)?)?;
+        level.children.push((offset, count));
+
+        Ok(())
+    }
+
+    /// Apply a batch of puts and deletes in a single copy-on-write pass and
+    /// commit. Every node touched by the batch is rewritten once, and the
+    /// root is switched once at the end.
+    ///
+    /// Operations on the same key are applied in the order they were added
+    /// to the batch.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        let mut ops = batch.into_ops();
+        if ops.iter().any(|(key, _)| key.is_empty()) {
+            return Err(Error::EmptyKey);
+        }
+        if ops.iter().any(|(key, _)| key.len() > KEY_MAX_SIZE) {
+            return Err(Error::KeyOverflowError);
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        // The sort is stable, keeping operations on the same key in order
+        ops.sort_by(|(a, _), (b, _)| self.comparator().compare(a, b));
+
+        let object_changes = if self.has_indexes() {
+            self.batch_object_changes(&ops)?
+        } else {
+            vec![]
+        };
+        // Puts and deletes both clear the expiry time of a key
+        let mut expiry_changes = vec![];
+        if self.expiry_filter(&self.root)?.is_some() {
+            expiry_changes = ops.iter().map(|(key, _)| (key.clone(), None)).collect();
+            expiry_changes.dedup();
+        }
+
+        self.write(|btree, fq| {
+            // Values to set, or None to delete the key. Objects are written
+            // within the write, so that a failed batch frees them again.
+            let mut changes = Vec::with_capacity(ops.len());
+            for (key, op) in ops {
+                let value = match op {
+                    BatchOp::Put(value) => Some(LeafValue::Number(value)),
+                    BatchOp::PutObject(object) => Some(object_value(&mut btree.pager, object)?),
+                    BatchOp::Delete => None,
+                };
+                changes.push((key, value));
+            }
+
+            btree.apply_changes(fq, changes)?;
+            btree.update_indexes(fq, &object_changes)?;
+            btree.update_expiry(fq, expiry_changes)
+        })
+    }
+
+    /// Apply sorted changes to the tree in a single copy-on-write pass and
+    /// switch to the new root, leaving the commit to the caller
+    fn apply_changes(
+        &mut self,
+        fq: &mut FreeQueue,
+        changes: Vec<(Vec<u8>, Option<LeafValue>)>,
+    ) -> Result<(), Error> {
+        let root_offset = self.root_offset()?;
+        let root = self.read_node(&root_offset)?;
+        fq.add(root_offset);
+
+        let (nodes, separators) = self.apply_batch_cow(fq, root, changes)?;
+        self.switch_root(fq, nodes, separators)
+    }
+
+    /// The objects changed by sorted batch operations, with one change for
+    /// every key
+    fn batch_object_changes(&self, ops: &[(Vec<u8>, BatchOp)]) -> Result<Vec<ObjectChange>, Error> {
+        let root_offset = self.root_offset()?;
+
+        let mut changes: Vec<ObjectChange> = vec![];
+        for (key, op) in ops {
+            let new = match op {
+                BatchOp::PutObject(object) => Some(object.clone()),
+                BatchOp::Put(_) | BatchOp::Delete => None,
+            };
+
+            match changes.last_mut() {
+                Some(change) if self.comparator().compare(&change.0, key) == Ordering::Equal => {
+                    change.0 = key.clone();
+                    change.2 = new;
+                }
+                _ => {
+                    let old = match self.search_node(&root_offset, key)? {
+                        Some(value) => self.stored_object(&value)?,
+                        None => None,
+                    };
+                    changes.push((key.clone(), old, new));
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Write the nodes replacing the root, adding levels on top of them until
+    /// a single root is left, and switch to the new root
+    fn switch_root(
+        &mut self,
+        fq: &mut FreeQueue,
+        mut nodes: Vec<Node>,
+        mut separators: Vec<Vec<u8>>,
+    ) -> Result<(), Error> {
+        while nodes.len() > 1 {
+            let mut children = vec![];
+            for node in nodes {
+                let count = node.entry_count();
+                children.push((self.pager.write_page(&Page::try_from(&node)?)?, count));
+            }
+
+            (nodes, separators) = split_internal(separators, children)?;
+        }
+
+        let root = nodes.pop().ok_or(Error::InvalidRootOffset)?;
+        let mut root_offset = self.pager.write_page(&Page::try_from(&root)?)?;
+
+        // A root left with a single child is replaced by that child
+        loop {
+            let root = self.view_node(&root_offset)?;
+            if root.is_leaf() || root.child_count() > 1 {
+                break;
+            }
+
+            let child_offset = root.child(0)?;
+            fq.add(root_offset);
+            root_offset = child_offset;
+        }
+        self.set_root(fq, root_offset)
+    }
+
+    /// Apply sorted changes to a subtree, returning the nodes replacing it
+    /// along with the separators between them. The nodes are left for the
+    /// parent to write, since it may have to merge those which underflow.
+    fn apply_batch_cow(
+        &mut self,
+        fq: &mut FreeQueue,
+        node: Node,
+        changes: Vec<(Vec<u8>, Option<LeafValue>)>,
+    ) -> Result<NewNodes, Error> {
+        match node.node_kind {
+            NodeKind::Internal {
+                keys,
+                children,
+                counts,
+                ..
+            } => {
+                // The children once changed, along with the keys between them
+                let mut new_children = vec![];
+                let mut new_keys = vec![];
+
+                let mut changes = changes.into_iter().peekable();
+                for (idx, (child_offset, count)) in children.into_iter().zip(counts).enumerate() {
+                    if idx > 0 {
+                        new_keys.push(keys[idx - 1].to_owned());
+                    }
+
+                    // Keys up to the separator belong to the child on its left
+                    let mut child_changes = vec![];
+                    while let Some(change) = changes.next_if(|(key, _)| {
+                        idx == keys.len()
+                            || self.comparator().compare(key, &keys[idx]) != Ordering::Greater
+                    }) {
+                        child_changes.push(change);
+                    }
+
+                    if child_changes.is_empty() {
+                        new_children.push(BatchChild::Written(child_offset, count));
+                        continue;
+                    }
+
+                    let child = self.read_node(&child_offset)?;
+                    fq.add(child_offset);
+
+                    let (nodes, separators) = self.apply_batch_cow(fq, child, child_changes)?;
+                    push_batch_nodes(&mut new_children, &mut new_keys, nodes, separators);
+                }
+
+                self.write_batch_children(fq, new_children, new_keys)
+            }
+            NodeKind::Leaf {
+                mut key_value_pairs,
+                ..
+            } => {
+                for (key, value) in changes {
+                    let found = key_value_pairs
+                        .binary_search_by(|kv| self.comparator().compare(&kv.key, &key));
+                    self.change_leaf_pair(fq, &mut key_value_pairs, found, key, value)?;
+                }
+
+                split_leaf(key_value_pairs, self.comparator())
+            }
+        }
+    }
+
+    /// Set or delete the pair of a key within the pairs of a leaf, at the
+    /// index found by a binary search for the key
+    fn change_leaf_pair(
+        &mut self,
+        fq: &mut FreeQueue,
+        key_value_pairs: &mut Vec<KeyValuePair>,
+        found: Result<usize, usize>,
+        key: Vec<u8>,
+        value: Option<LeafValue>,
+    ) -> Result<(), Error> {
+        match (found, value) {
+            (Ok(idx), Some(value)) => {
+                let sequence = self.next_sequence();
+                self.record_change(sequence, &key, Some(&value), true)?;
+
+                let kv = &mut key_value_pairs[idx];
+                kv.sequence = sequence;
+                if let LeafValue::Object(object) = mem::replace(&mut kv.value, value) {
+                    fq.add(object);
+                }
+            }
+            (Ok(idx), None) => {
+                self.record_delete(&key)?;
+                free_pair(fq, key_value_pairs.remove(idx));
+            }
+            (Err(idx), Some(value)) => {
+                let sequence = self.next_sequence();
+                self.record_change(sequence, &key, Some(&value), false)?;
+
+                let key_overflow = if key.len() > KEY_SPILL_SIZE {
+                    Some(self.pager.write_object(&key)?)
+                } else {
+                    None
+                };
+                key_value_pairs.insert(
+                    idx,
+                    KeyValuePair {
+                        key,
+                        key_overflow,
+                        value,
+                        sequence,
+                    },
+                );
+            }
+            (Err(_), None) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Replace the value of a key in a single copy-on-write pass and commit,
+    /// as long as `swap` returns the new value for the current one, or
+    /// `Some(None)` to delete the key. Nothing is written when it returns
+    /// None. Expired keys are passed as missing.
+    ///
+    /// Returns whether the key was written.
+    fn swap_value(&mut self, key: Vec<u8>, swap: &mut Swap) -> Result<bool, Error> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > KEY_MAX_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+
+        let expired = self.is_expired(&key)?;
+        let indexed = self.has_indexes();
+        let mut old_object = None;
+        let mut new_value = None;
+        let mut checked_swap = |btree: &mut Self, current: Option<&KeyValuePair>| {
+            let current = current.filter(|_| !expired);
+            if indexed {
+                old_object = current
+                    .map(|kv| btree.stored_object(&kv.value))
+                    .transpose()?;
+            }
+
+            let new = swap(btree, current)?;
+            new_value.clone_from(&new);
+            Ok(new)
+        };
+
+        // Nothing is committed when the swap writes nothing, so the write
+        // only starts once the tree was swapped
+        let mut fq = FreeQueue::new();
+        let swapped = self.swap_in_tree(&mut fq, &key, &mut checked_swap);
+        let (nodes, separators) = match swapped {
+            Ok(Some(new_nodes)) => new_nodes,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                self.rollback()?;
+                return Err(e);
+            }
+        };
+
+        self.write_from(fq, |btree, fq| {
+            btree.switch_root(fq, nodes, separators)?;
+            if indexed {
+                let new_object = match &new_value {
+                    Some(Some(value)) => btree.stored_object(value)?,
+                    _ => None,
+                };
+                let old_object = old_object.flatten();
+                btree.update_indexes(fq, &[(key.clone(), old_object, new_object)])?;
+            }
+            btree.update_expiry(fq, vec![(key, None)])
+        })?;
+
+        Ok(true)
+    }
+
+    /// Swap the value of a key from the root, freeing the old root when the
+    /// swap wrote anything
+    fn swap_in_tree(
+        &mut self,
+        fq: &mut FreeQueue,
+        key: &[u8],
+        swap: &mut Swap,
+    ) -> Result<Option<NewNodes>, Error> {
+        let root_offset = self.root_offset()?;
+        let root = self.read_node(&root_offset)?;
+        let new_nodes = self.swap_cow(fq, root, key, swap)?;
+        if new_nodes.is_some() {
+            fq.add(root_offset);
+        }
+
+        Ok(new_nodes)
+    }
+
+    /// Look up a key in a subtree and change its value with `swap`,
+    /// returning the nodes replacing the subtree. Nodes are only copied
+    /// once `swap` returned a new value, otherwise None is returned.
+    fn swap_cow(
+        &mut self,
+        fq: &mut FreeQueue,
+        node: Node,
+        key: &[u8],
+        swap: &mut Swap,
+    ) -> Result<Option<NewNodes>, Error> {
+        match node.node_kind {
+            NodeKind::Internal {
+                keys,
+                children,
+                counts,
+                ..
+            } => {
+                let child_idx = keys
+                    .binary_search_by(|k| self.comparator().compare(k, key))
+                    .unwrap_or_else(|x| x);
+
+                let child = self.read_node(&children[child_idx])?;
+                let mut new_nodes = match self.swap_cow(fq, child, key, swap)? {
+                    Some(new_nodes) => new_nodes,
+                    None => return Ok(None),
+                };
+                fq.add(children[child_idx].to_owned());
+
+                let mut new_children = vec![];
+                let mut new_keys = vec![];
+                for (idx, (child_offset, count)) in children.into_iter().zip(counts).enumerate() {
+                    if idx > 0 {
+                        new_keys.push(keys[idx - 1].to_owned());
+                    }
+
+                    if idx == child_idx {
+                        let (nodes, separators) = mem::take(&mut new_nodes);
+                        push_batch_nodes(&mut new_children, &mut new_keys, nodes, separators);
+                    } else {
+                        new_children.push(BatchChild::Written(child_offset, count));
+                    }
+                }
+
+                self.write_batch_children(fq, new_children, new_keys)
+                    .map(Some)
+            }
+            NodeKind::Leaf {
+                mut key_value_pairs,
+                ..
+            } => {
+                let found =
+                    key_value_pairs.binary_search_by(|kv| self.comparator().compare(&kv.key, key));
+                let current = found.ok().map(|idx| &key_value_pairs[idx]);
+
+                let value = match swap(self, current)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                self.change_leaf_pair(fq, &mut key_value_pairs, found, key.to_owned(), value)?;
+
+                split_leaf(key_value_pairs, self.comparator()).map(Some)
+            }
+        }
+    }
+
+    /// Merge the changed children of a node which underflow with a sibling,
+    /// then write them, splitting the node if it outgrew a page
+    fn write_batch_children(
+        &mut self,
+        fq: &mut FreeQueue,
+        mut new_children: Vec<BatchChild>,
+        mut new_keys: Vec<Vec<u8>>,
+    ) -> Result<NewNodes, Error> {
+        let mut idx = 0;
+        while idx < new_children.len() {
+            if new_children.len() < 2 || !new_children[idx].underflows() {
+                idx += 1;
+                continue;
+            }
+
+            // Merge with the next sibling, or the previous one for the
+            // last child
+            let left_idx = idx.min(new_children.len() - 2);
+            let right = new_children.remove(left_idx + 1);
+            let left = new_children.remove(left_idx);
+            let separator = new_keys.remove(left_idx);
+
+            let left = self.batch_child_node(fq, left)?;
+            let right = self.batch_child_node(fq, right)?;
+            let (nodes, separators) = merge_nodes(left, separator, right, self.comparator())?;
+
+            // A merged node may still underflow and is checked again,
+            // while a split one is half full at least
+            idx = if nodes.len() == 1 {
+                left_idx
+            } else {
+                left_idx + nodes.len()
+            };
+            new_keys.splice(left_idx..left_idx, separators);
+            new_children.splice(left_idx..left_idx, nodes.into_iter().map(BatchChild::New));
+        }
+
+        let mut children = vec![];
+        for child in new_children {
+            children.push(match child {
+                BatchChild::Written(offset, count) => (offset, count),
+                BatchChild::New(node) => {
+                    let count = node.entry_count();
+                    (self.pager.write_page(&Page::try_from(&node)?)?, count)
+                }
+            });
+        }
+
+        split_internal(new_keys, children)
+    }
+
+    /// Take the node of a child, reading and freeing it if it was written
+    fn batch_child_node(&self, fq: &mut FreeQueue, child: BatchChild) -> Result<Node, Error> {
+        match child {
+            BatchChild::Written(offset, _) => {
+                let node = self.read_node(&offset)?;
+                fq.add(offset);
+                Ok(node)
+            }
+            BatchChild::New(node) => Ok(node),
+        }
+    }
+
+    /// Delete every key within a range along with their objects, in a
+    /// single commit, returning the number of deleted keys. Subtrees entirely
+    /// within the range are freed as a whole rather than rewritten, and only
+    /// the nodes along the paths to both ends of the range are rewritten and
+    /// rebalanced.
+    pub fn delete_range<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64, Error> {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        let range = (
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        );
+
+        let indexed = self.has_indexes();
+        let expiring = self.expiry_filter(&self.root)?.is_some();
+        let recording = self.recording();
+
+        let mut object_changes = vec![];
+        let mut deleted_keys = vec![];
+        if indexed || expiring || recording {
+            for entry in self.cursor((start.clone(), end.clone()))? {
+                let (key, value) = entry?;
+                if let (true, Some(object)) = (indexed, self.stored_object(&value)?) {
+                    object_changes.push((key.clone(), Some(object), None));
+                }
+                deleted_keys.push(key);
+            }
+        }
+
+        self.write(|btree, fq| {
+            let root_offset = btree.root_offset()?;
+            let root = btree.read_node(&root_offset)?;
+            fq.add(root_offset);
+
+            let ((nodes, separators), deleted) =
+                btree.delete_range_cow(fq, root, &range, None, None)?;
+            btree.switch_root(fq, nodes, separators)?;
+            btree.update_indexes(fq, &object_changes)?;
+            for key in &deleted_keys {
+                btree.record_delete(key)?;
+            }
+            if expiring {
+                let expiry_changes = deleted_keys.into_iter().map(|key| (key, None)).collect();
+                btree.update_expiry(fq, expiry_changes)?;
+            }
+
+            Ok(deleted)
+        })
+    }
+
+    /// Delete the keys within a range from a subtree holding keys greater
+    /// than `lower` and up to `upper`, None standing for the ends of the tree.
+    /// Returns the nodes replacing the subtree, like `apply_batch_cow`, and
+    /// the number of deleted keys.
+    fn delete_range_cow(
+        &mut self,
+        fq: &mut FreeQueue,
+        node: Node,
+        range: &(Bound<&[u8]>, Bound<&[u8]>),
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<(NewNodes, u64), Error> {
+        let mut deleted = 0;
+
+        match node.node_kind {
+            NodeKind::Internal {
+                keys,
+                children,
+                counts,
+                ..
+            } => {
+                let mut new_children = vec![];
+                let mut new_keys = vec![];
+
+                for (idx, (child_offset, count)) in children.into_iter().zip(counts).enumerate() {
+                    let child_lower = if idx == 0 {
+                        lower
+                    } else {
+                        Some(keys[idx - 1].as_slice())
+                    };
+                    let child_upper = keys.get(idx).map(Vec::as_slice).or(upper);
+
+                    if self.covers(range, child_lower, child_upper) {
+                        self.free_subtree(fq, child_offset)?;
+                        deleted += count;
+                        continue;
+                    }
+
+                    // The key right before a child still works as separator
+                    // when the children before it are dropped
+                    if !new_children.is_empty() {
+                        new_keys.push(keys[idx - 1].to_owned());
+                    }
+
+                    if self.misses(range, child_lower, child_upper) {
+                        new_children.push(BatchChild::Written(child_offset, count));
+                        continue;
+                    }
+
+                    let child = self.read_node(&child_offset)?;
+                    fq.add(child_offset);
+
+                    let ((nodes, separators), child_deleted) =
+                        self.delete_range_cow(fq, child, range, child_lower, child_upper)?;
+                    push_batch_nodes(&mut new_children, &mut new_keys, nodes, separators);
+                    deleted += child_deleted;
+                }
+
+                // Only the root can lose every child, leaving an empty tree
+                if new_children.is_empty() {
+                    return Ok((split_leaf(vec![], self.comparator())?, deleted));
+                }
+
+                let nodes = self.write_batch_children(fq, new_children, new_keys)?;
+                Ok((nodes, deleted))
+            }
+            NodeKind::Leaf {
+                key_value_pairs, ..
+            } => {
+                let mut kept = vec![];
+                for kv in key_value_pairs {
+                    if self.range_contains(range, &kv.key) {
+                        free_pair(fq, kv);
+                        deleted += 1;
+                    } else {
+                        kept.push(kv);
+                    }
+                }
+
+                Ok((split_leaf(kept, self.comparator())?, deleted))
+            }
+        }
+    }
+
+    /// Free every page of a subtree, along with the long keys and objects of
+    /// its leaves
+    fn free_subtree(&self, fq: &mut FreeQueue, offset: Offset) -> Result<(), Error> {
+        let node = self.view_node(&offset)?;
+        if node.is_leaf() {
+            for idx in 0..node.key_count() {
+                if let Some(overflow) = node.key_overflow(idx)? {
+                    fq.add(overflow);
+                }
+                if let LeafValue::Object(object) = node.value(idx)? {
+                    fq.add(object);
+                }
+            }
+        } else {
+            for child in node.children()? {
+                self.free_subtree(fq, child)?;
+            }
+        }
+
+        fq.add(offset);
+        Ok(())
+    }
+
+    fn range_contains(&self, range: &(Bound<&[u8]>, Bound<&[u8]>), key: &[u8]) -> bool {
+        let after_start = match range.0 {
+            Bound::Included(start) => self.comparator().compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.comparator().compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.1 {
+            Bound::Included(end) => self.comparator().compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.comparator().compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        };
+
+        after_start && before_end
+    }
+
+    /// Whether a range holds every key greater than `lower` and up to `upper`
+    fn covers(
+        &self,
+        range: &(Bound<&[u8]>, Bound<&[u8]>),
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> bool {
+        let after_start = match (range.0, lower) {
+            (Bound::Unbounded, _) => true,
+            (_, None) => false,
+            (Bound::Included(start) | Bound::Excluded(start), Some(lower)) => {
+                self.comparator().compare(start, lower) != Ordering::Greater
+            }
+        };
+        let before_end = match (range.1, upper) {
+            (Bound::Unbounded, _) => true,
+            (_, None) => false,
+            (Bound::Included(end), Some(upper)) => {
+                self.comparator().compare(upper, end) != Ordering::Greater
+            }
+            (Bound::Excluded(end), Some(upper)) => {
+                self.comparator().compare(upper, end) == Ordering::Less
+            }
+        };
+
+        after_start && before_end
+    }
+
+    /// Whether a range holds none of the keys greater than `lower` and up to
+    /// `upper`
+    fn misses(
+        &self,
+        range: &(Bound<&[u8]>, Bound<&[u8]>),
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> bool {
+        let before_start = match (range.0, upper) {
+            (Bound::Unbounded, _) | (_, None) => false,
+            (Bound::Included(start), Some(upper)) => {
+                self.comparator().compare(upper, start) == Ordering::Less
+            }
+            (Bound::Excluded(start), Some(upper)) => {
+                self.comparator().compare(upper, start) != Ordering::Greater
+            }
+        };
+        let after_end = match (range.1, lower) {
+            (Bound::Unbounded, _) | (_, None) => false,
+            (Bound::Included(end) | Bound::Excluded(end), Some(lower)) => {
+                self.comparator().compare(lower, end) != Ordering::Less
+            }
+        };
+
+        before_start || after_end
+    }
+
+    /// Iterate over the keys within a range and their values, in key order.
+    /// The keys must not hold objects.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<Range<'_>, Error> {
+        Ok(Range {
+            cursor: self.cursor(range)?,
+            expiry: self.expiry_filter(&self.root)?,
+        })
+    }
+
+    /// Iterate over the keys within a range and their objects, in key order
+    pub fn range_objects<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<ObjectRange<'_>, Error> {
+        Ok(ObjectRange {
+            pager: &self.pager,
+            cursor: self.cursor(range)?,
+            expiry: self.expiry_filter(&self.root)?,
+        })
+    }
+
+    /// Number of keys in the tree, read from the subtree counts of the root.
+    /// Expired keys are purged first, so that they are left out.
+    pub fn len(&mut self) -> Result<u64, Error> {
+        self.purge_expired()?;
+        self.entry_count()
+    }
+
+    /// Number of keys in the tree, expired or not
+    fn entry_count(&self) -> Result<u64, Error> {
+        self.view_node(&self.root_offset()?)?.entry_count()
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Number of keys within a range, without walking the keys. Expired keys
+    /// are purged first, like in `len`.
+    pub fn count_range<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64, Error> {
+        self.purge_expired()?;
+
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.count_before(key.as_ref(), false)?,
+            Bound::Excluded(key) => self.count_before(key.as_ref(), true)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.count_before(key.as_ref(), true)?,
+            Bound::Excluded(key) => self.count_before(key.as_ref(), false)?,
+            Bound::Unbounded => self.entry_count()?,
+        };
+
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Number of keys smaller than a key, which is the position of the key in
+    /// key order if it exists
+    pub fn rank(&mut self, key: impl AsRef<[u8]>) -> Result<u64, Error> {
+        self.count_range(..key.as_ref())
+    }
+
+    /// The key at some position in key order, starting from 0. Expired keys
+    /// are purged first, like in `len`.
+    pub fn nth(&mut self, n: u64) -> Result<Option<Vec<u8>>, Error> {
+        self.purge_expired()?;
+
+        Ok(self.nth_entry(n)?.map(|(key, _)| key))
+    }
+
+    /// The first key and its value
+    pub fn first(&self) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::First)?
+            .map(number_entry)
+            .transpose()
+    }
+
+    /// The last key and its value
+    pub fn last(&self) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::Last)?.map(number_entry).transpose()
+    }
+
+    /// The greatest key smaller than or equal to a key, and its value
+    pub fn floor(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::Floor(key.as_ref()))?
+            .map(number_entry)
+            .transpose()
+    }
+
+    /// The smallest key greater than or equal to a key, and its value
+    pub fn ceiling(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::Ceiling(key.as_ref()))?
+            .map(number_entry)
+            .transpose()
+    }
+
+    /// The greatest key strictly smaller than a key, and its value
+    pub fn lower(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::Lower(key.as_ref()))?
+            .map(number_entry)
+            .transpose()
+    }
+
+    /// The smallest key strictly greater than a key, and its value
+    pub fn higher(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.neighbor(Neighbor::Higher(key.as_ref()))?
+            .map(number_entry)
+            .transpose()
+    }
+
+    /// Like `neighbor`, with the object of the key
+    pub(crate) fn neighbor_object(&self, neighbor: Neighbor) -> Result<Option<ObjectEntry>, Error> {
+        self.neighbor(neighbor)?
+            .map(|entry| object_entry(&self.pager, entry))
+            .transpose()
+    }
+
+    /// Find a key relative to another one, by counting the keys before it
+    fn neighbor(&self, neighbor: Neighbor) -> Result<Option<(Vec<u8>, LeafValue)>, Error> {
+        let forward = matches!(
+            neighbor,
+            Neighbor::First | Neighbor::Ceiling(_) | Neighbor::Higher(_)
+        );
+        let mut entry = self.neighbor_entry(neighbor)?;
+
+        // Expired keys are skipped, moving on in the same direction
+        if let Some(expiry) = self.expiry_filter(&self.root)? {
+            while let Some((key, _)) = &entry {
+                if !expiry.is_expired(key)? {
+                    break;
+                }
+
+                let key = key.clone();
+                entry = self.neighbor_entry(if forward {
+                    Neighbor::Higher(&key)
+                } else {
+                    Neighbor::Lower(&key)
+                })?;
+            }
+        }
+
+        Ok(entry)
+    }
+
+    fn neighbor_entry(&self, neighbor: Neighbor) -> Result<Option<(Vec<u8>, LeafValue)>, Error> {
+        let n = match neighbor {
+            Neighbor::First => Some(0),
+            Neighbor::Last => self.entry_count()?.checked_sub(1),
+            Neighbor::Floor(key) => self.count_before(key, true)?.checked_sub(1),
+            Neighbor::Ceiling(key) => Some(self.count_before(key, false)?),
+            Neighbor::Lower(key) => self.count_before(key, false)?.checked_sub(1),
+            Neighbor::Higher(key) => Some(self.count_before(key, true)?),
+        };
+
+        match n {
+            Some(n) => self.nth_entry(n),
+            None => Ok(None),
+        }
+    }
+
+    fn nth_entry(&self, mut n: u64) -> Result<Option<(Vec<u8>, LeafValue)>, Error> {
+        let mut node = self.view_node(&self.root_offset()?)?;
+        while !node.is_leaf() {
+            // Skip the subtrees holding the keys before the nth
+            let mut idx = 0;
+            loop {
+                let count = node.subtree_count(idx)?;
+                if n < count {
+                    break;
+                }
+
+                n -= count;
+                idx += 1;
+                if idx == node.child_count() {
+                    return Ok(None);
+                }
+            }
+
+            node = self.view_node(&node.child(idx)?)?;
+        }
+
+        if n >= node.key_count() as u64 {
+            return Ok(None);
+        }
+
+        let idx = n as usize;
+        Ok(Some((
+            node.key(idx, &self.pager)?.into_owned(),
+            node.value(idx)?,
+        )))
+    }
+
+    /// Number of keys smaller than a key, or equal to it as well when
+    /// inclusive. Only the subtrees left of the path down to the key are
+    /// counted, using the counts of their parents.
+    fn count_before(&self, key: &[u8], inclusive: bool) -> Result<u64, Error> {
+        let mut count = 0;
+
+        let mut node = self.view_node(&self.root_offset()?)?;
+        while !node.is_leaf() {
+            let idx = node
+                .search(key, self.comparator(), &self.pager)?
+                .unwrap_or_else(|x| x);
+            for child_idx in 0..idx {
+                count += node.subtree_count(child_idx)?;
+            }
+
+            node = self.view_node(&node.child(idx)?)?;
+        }
+
+        let idx = match node.search(key, self.comparator(), &self.pager)? {
+            Ok(idx) if inclusive => idx + 1,
+            Ok(idx) | Err(idx) => idx,
+        };
+
+        Ok(count + idx as u64)
+    }
+
+    /// Create the catalog of named trees, unless the file already has one
+    pub(crate) fn create_catalog(&mut self) -> Result<(), Error> {
+        if self.pager.config.catalog_page.is_some() {
+            return Ok(());
+        }
+
+        self.add_catalog()?;
+        self.pager.commit(FreeQueue::new())
+    }
+
+    /// Write an empty catalog, leaving the commit to the caller
+    fn add_catalog(&mut self) -> Result<(), Error> {
+        let catalog_offset = self.pager.write_page(&Page::try_from(&empty_leaf())?)?;
+        self.pager.set_catalog_page(catalog_offset);
+
+        Ok(())
+    }
+
+    /// Look up the root of a named tree in the catalog
+    fn named_root(&self, name: &[u8]) -> Result<Offset, Error> {
+        let catalog_offset = self
+            .pager
+            .config
+            .catalog_page
+            .to_owned()
+            .ok_or(Error::InvalidRootOffset)?;
+
+        match search_tree(&self.pager, &Bytewise, &catalog_offset, name)? {
+            Some(LeafValue::Number(offset)) => Ok(Offset(offset as usize)),
+            Some(_) => Err(Error::ValueKindMismatch),
+            None => Err(Error::TreeNotFound(
+                String::from_utf8_lossy(name).into_owned(),
+            )),
+        }
+    }
+
+    /// Look up the root of a named tree, if the file has a catalog holding it
+    fn find_named_root(&self, name: &[u8]) -> Result<Option<Offset>, Error> {
+        if self.pager.config.catalog_page.is_none() {
+            return Ok(None);
+        }
+
+        match self.named_root(name) {
+            Ok(offset) => Ok(Some(offset)),
+            Err(Error::TreeNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run some operation on the catalog instead of the current tree
+    fn in_catalog<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.with_root(TreeRoot::Catalog, operation)
+    }
+
+    /// Run some operation on another tree than the current one
+    fn with_root<T>(
+        &mut self,
+        root: TreeRoot,
+        operation: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let root = mem::replace(&mut self.root, root);
+        let result = operation(self);
+        self.root = root;
+
+        result
+    }
+
+    /// Create an empty named tree, unless one with the name already exists
+    pub(crate) fn create_tree(&mut self, name: &[u8]) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if name.len() > KEY_MAX_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+
+        match self.named_root(name) {
+            Ok(_) => return Ok(()),
+            Err(Error::TreeNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut fq = FreeQueue::new();
+        self.create_empty_tree(&mut fq, name)?;
+
+        self.commit(fq)
+    }
+
+    /// Add an empty tree to the catalog, leaving the commit to the caller
+    fn create_empty_tree(&mut self, fq: &mut FreeQueue, name: &[u8]) -> Result<(), Error> {
+        let root_offset = self.pager.write_page(&Page::try_from(&empty_leaf())?)?;
+        let root = LeafValue::Number(root_offset.0 as u64);
+
+        self.in_catalog(|btree| btree.apply_changes(fq, vec![(name.to_vec(), Some(root))]))
+    }
+
+    /// Remove a named tree from the catalog, freeing all of its pages along
+    /// with its expiry tree and its indexes
+    pub(crate) fn drop_tree(&mut self, name: &[u8]) -> Result<(), Error> {
+        let root_offset = self.named_root(name)?;
+
+        let mut fq = FreeQueue::new();
+        self.free_subtree(&mut fq, root_offset)?;
+        let mut names = vec![name.to_vec()];
+
+        let root = TreeRoot::Named(name.to_vec());
+        let expiry_name = expiry_tree_name(&root);
+        if let Some(expiry_root) = self.find_named_root(&expiry_name)? {
+            self.free_subtree(&mut fq, expiry_root)?;
+            names.push(expiry_name);
+        }
+
+        let index_prefix = index_tree_name(&root, &[]);
+        let (start, end) = prefix_range(&index_prefix);
+        let catalog_offset = self.root_offset_of(&TreeRoot::Catalog)?;
+        let indexes = Cursor::new(&self.pager, &Bytewise, &catalog_offset, start, end)?
+            .map(|entry| entry.map(|(name, _)| name))
+            .collect::<Result<Vec<_>, _>>()?;
+        for index_name in indexes {
+            let index_root = self.named_root(&index_name)?;
+            self.free_subtree(&mut fq, index_root)?;
+            names.push(index_name);
+        }
+        self.indexes.retain(|index| index.table != root);
+
+        names.sort();
+        let changes = names.into_iter().map(|name| (name, None)).collect();
+        self.in_catalog(|btree| btree.apply_changes(&mut fq, changes))?;
+
+        self.commit(fq)
+    }
+
+    /// Names of every tree in the catalog, in bytewise order
+    pub(crate) fn tree_names(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let catalog_offset = self
+            .pager
+            .config
+            .catalog_page
+            .to_owned()
+            .ok_or(Error::InvalidRootOffset)?;
+
+        Cursor::new(
+            &self.pager,
+            &Bytewise,
+            &catalog_offset,
+            Bound::Unbounded,
+            Bound::Unbounded,
+        )?
+        .map(|entry| entry.map(|(name, _)| name))
+        .filter(|name| !matches!(name, Ok(name) if is_internal_tree(name)))
+        .collect()
+    }
+
+    /// Declare a secondary index over the objects of the tree, keyed by
+    /// whatever `extract` returns for an object. Objects for which it returns
+    /// None are left out of the index.
+    ///
+    /// The entries of the index are kept in an internal tree of the
+    /// catalog, named after the tree and the index, and are updated along
+    /// with the objects in the same commit. They are dropped along with the
+    /// tree. The first time an index is declared, the objects already in
+    /// the tree are indexed. Functions can't be stored, so indexes have to
+    /// be declared again every time the file is opened, before any writes.
+    pub fn create_index(
+        &mut self,
+        name: &str,
+        extract: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        let name = index_tree_name(&self.root, name.as_bytes());
+        if name.len() > KEY_MAX_SIZE {
+            return Err(Error::KeyOverflowError);
+        }
+
+        self.create_catalog()?;
+        let exists = match self.named_root(&name) {
+            Ok(_) => true,
+            Err(Error::TreeNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+
+        self.indexes.retain(|index| index.name != name);
+        self.indexes.push(SecondaryIndex {
+            name: name.clone(),
+            table: self.root.clone(),
+            extract: Arc::new(extract),
+        });
+
+        if !exists {
+            let mut changes = vec![];
+            for entry in self.cursor::<&[u8]>(..)? {
+                let (key, value) = entry?;
+                if let Some(object) = self.stored_object(&value)? {
+                    changes.push((key, None, Some(object)));
+                }
+            }
+
+            let mut fq = FreeQueue::new();
+            self.create_empty_tree(&mut fq, &name)?;
+            self.update_indexes(&mut fq, &changes)?;
+            self.commit(fq)?;
+        }
+
+        Ok(())
+    }
+
+    /// Look up the objects of a secondary index with some index key, along
+    /// with their keys, in the order of their keys
+    pub fn search_index(
+        &self,
+        name: &str,
+        index_key: impl AsRef<[u8]>,
+    ) -> Result<Vec<ObjectEntry>, Error> {
+        let tree_name = index_tree_name(&self.root, name.as_bytes());
+        let index = self
+            .indexes
+            .iter()
+            .find(|index| index.name == tree_name)
+            .ok_or_else(|| Error::IndexNotFound(name.to_owned()))?;
+
+        let table_offset = self.root_offset_of(&index.table)?;
+        let index_offset = self.named_root(&index.name)?;
+        let expiry = self.expiry_filter(&index.table)?;
+
+        let prefix = index_key.as_ref().to_vec().to_key_bytes();
+        let (start, end) = prefix_range(&prefix);
+
+        let mut entries = vec![];
+        for entry in Cursor::new(&self.pager, &Bytewise, &index_offset, start, end)? {
+            let (entry_key, _) = entry?;
+            let key = entry_key[prefix.len()..].to_vec();
+            if let Some(expiry) = &expiry {
+                if expiry.is_expired(&key)? {
+                    continue;
+                }
+            }
+
+            if let Some(value) = self.search_node(&table_offset, &key)? {
+                if let Some(object) = self.stored_object(&value)? {
+                    entries.push((key, object));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn has_indexes(&self) -> bool {
+        self.indexes.iter().any(|index| index.table == self.root)
+    }
+
+    /// Update the indexes of the tree for some changed objects, leaving the
+    /// commit to the caller
+    fn update_indexes(
+        &mut self,
+        fq: &mut FreeQueue,
+        changes: &[ObjectChange],
+    ) -> Result<(), Error> {
+        let indexes: Vec<SecondaryIndex> = self
+            .indexes
+            .iter()
+            .filter(|index| index.table == self.root)
+            .cloned()
+            .collect();
+
+        for index in indexes {
+            let mut entries = vec![];
+            for (key, old, new) in changes {
+                let old = old.as_deref().and_then(|object| (index.extract)(object));
+                let new = new.as_deref().and_then(|object| (index.extract)(object));
+                if old == new {
+                    continue;
+                }
+
+                if let Some(index_key) = old {
+                    entries.push((index_entry_key(index_key, key), None));
+                }
+                if let Some(index_key) = new {
+                    entries.push((index_entry_key(index_key, key), Some(LeafValue::Number(0))));
+                }
+            }
+            if entries.is_empty() {
+                continue;
+            }
+            if entries.iter().any(|(key, _)| key.len() > KEY_MAX_SIZE) {
+                return Err(Error::KeyOverflowError);
+            }
+
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            self.with_root(TreeRoot::Index(index.name), |btree| {
+                btree.apply_changes(fq, entries)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The object of a leaf value, read from the object store if needed
+    fn stored_object(&self, value: &LeafValue) -> Result<Option<Vec<u8>>, Error> {
+        match value {
+            LeafValue::Inline(object) => Ok(Some(object.clone())),
+            LeafValue::Object(o) => Ok(Some(self.pager.get_object(o)?)),
+            LeafValue::Number(_) => Ok(None),
+        }
+    }
+
+    /// Delete every expired key along with its object, in a single commit,
+    /// returning the number of deleted keys. The keys are found in expiry
+    /// order, without scanning the tree.
+    pub fn purge_expired(&mut self) -> Result<u64, Error> {
+        let expired = self.expired_keys()?;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut batch = WriteBatch::new();
+        for key in &expired {
+            batch.delete(key.as_slice());
+        }
+        self.apply_batch(batch)?;
+
+        Ok(expired.len() as u64)
+    }
+
+    /// Keys of the tree which have expired, in key order. They are found in
+    /// expiry order, without scanning the tree.
+    fn expired_keys(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut expired = match self.expiry_filter(&self.root)? {
+            Some(expiry) => Cursor::new(
+                &self.pager,
+                &Bytewise,
+                &expiry.root,
+                Bound::Included(vec![KEY_BY_EXPIRY]),
+                Bound::Excluded(expiry_order_key(expiry.now + 1, &[])),
+            )?
+            .map(|entry| entry.map(|(key, _)| key[1 + mem::size_of::<u64>()..].to_vec()))
+            .collect::<Result<Vec<_>, Error>>()?,
+            None => return Ok(vec![]),
+        };
+        expired.sort_by(|a, b| self.comparator().compare(a, b));
+
+        Ok(expired)
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool, Error> {
+        match self.expiry_filter(&self.root)? {
+            Some(expiry) => expiry.is_expired(key),
+            None => Ok(false),
+        }
+    }
+
+    /// A filter for the expired keys of a tree, unless none of its keys
+    /// ever had an expiry time
+    fn expiry_filter(&self, root: &TreeRoot) -> Result<Option<ExpiryFilter<'_>>, Error> {
+        if !matches!(root, TreeRoot::Default | TreeRoot::Named(_)) {
+            return Ok(None);
+        }
+
+        Ok(self
+            .find_named_root(&expiry_tree_name(root))?
+            .map(|root| ExpiryFilter {
+                pager: &self.pager,
+                root,
+                now: unix_millis(SystemTime::now()),
+            }))
+    }
+
+    /// Set or clear the expiry times of some keys of the tree, leaving the
+    /// commit to the caller. The expiry tree is created on first use.
+    fn update_expiry(
+        &mut self,
+        fq: &mut FreeQueue,
+        changes: Vec<(Vec<u8>, Option<u64>)>,
+    ) -> Result<(), Error> {
+        if !matches!(self.root, TreeRoot::Default | TreeRoot::Named(_)) {
+            return Ok(());
+        }
+
+        let name = expiry_tree_name(&self.root);
+        let root = match self.find_named_root(&name)? {
+            Some(root) => root,
+            None if changes.iter().all(|(_, expires_at)| expires_at.is_none()) => return Ok(()),
+            None => {
+                if self.pager.config.catalog_page.is_none() {
+                    self.add_catalog()?;
+                }
+                self.create_empty_tree(fq, &name)?;
+                self.named_root(&name)?
+            }
+        };
+
+        let mut entries = vec![];
+        for (key, expires_at) in changes {
+            let expiry_key = [&[EXPIRY_BY_KEY], key.as_slice()].concat();
+            if let Some(LeafValue::Number(old)) =
+                search_tree(&self.pager, &Bytewise, &root, &expiry_key)?
+            {
+                entries.push((expiry_order_key(old, &key), None));
+            }
+
+            match expires_at {
+                Some(expires_at) => {
+                    entries.push((
+                        expiry_order_key(expires_at, &key),
+                        Some(LeafValue::Number(0)),
+                    ));
+                    entries.push((expiry_key, Some(LeafValue::Number(expires_at))));
+                }
+                None => entries.push((expiry_key, None)),
+            }
+        }
+        if entries.iter().any(|(key, _)| key.len() > KEY_MAX_SIZE) {
+            return Err(Error::KeyOverflowError);
+        }
+
+        // The sort is stable, so that an expiry time set again is kept
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.with_root(TreeRoot::Index(name), |btree| {
+            btree.apply_changes(fq, entries)
+        })
+    }
+
+    /// Start recording every change to the default tree and the named trees
+    /// in a change log, kept in the file. Each insert, update and delete is
+    /// logged with its sequence number, in the commit making it.
+    pub fn enable_change_log(&mut self) -> Result<(), Error> {
+        if self.change_log {
+            return Ok(());
+        }
+
+        self.create_catalog()?;
+        self.create_tree(CHANGE_LOG_NAME)?;
+        self.change_log = true;
+
+        Ok(())
+    }
+
+    /// Iterate over the logged changes with a sequence number greater than
+    /// `sequence`, in sequence order. Consumers can resume from the sequence
+    /// number of the last change they handled.
+    pub fn changes_since(&self, sequence: u64) -> Result<Changes<'_>, Error> {
+        let root = self
+            .find_named_root(CHANGE_LOG_NAME)?
+            .ok_or(Error::ChangeLogDisabled)?;
+
+        Ok(Changes {
+            pager: &self.pager,
+            chunks: self.find_named_root(CHANGE_CHUNKS_NAME)?,
+            cursor: Cursor::new(
+                &self.pager,
+                &Bytewise,
+                &root,
+                Bound::Excluded(sequence.to_be_bytes().to_vec()),
+                Bound::Unbounded,
+            )?,
+        })
+    }
+
+    /// Delete the logged changes with a sequence number lower than `before`,
+    /// returning the number of deleted changes
+    pub fn truncate_changes(&mut self, before: u64) -> Result<u64, Error> {
+        if !self.change_log {
+            return Err(Error::ChangeLogDisabled);
+        }
+
+        let end = before.to_be_bytes().to_vec();
+        if self.find_named_root(CHANGE_CHUNKS_NAME)?.is_some() {
+            self.with_root(TreeRoot::Index(CHANGE_CHUNKS_NAME.to_vec()), |btree| {
+                btree.delete_range(..end.as_slice())
+            })?;
+        }
+        self.with_root(TreeRoot::Index(CHANGE_LOG_NAME.to_vec()), |btree| {
+            btree.delete_range(..end)
+        })
+    }
+
+    /// Delete all but the last `count` logged changes, returning the number
+    /// of deleted changes
+    pub fn retain_changes(&mut self, count: u64) -> Result<u64, Error> {
+        if !self.change_log {
+            return Err(Error::ChangeLogDisabled);
+        }
+
+        let first_kept =
+            self.with_root(
+                TreeRoot::Index(CHANGE_LOG_NAME.to_vec()),
+                |btree| match btree.len()?.checked_sub(count) {
+                    Some(first_kept) if first_kept > 0 => btree.nth(first_kept),
+                    _ => Ok(None),
+                },
+            )?;
+
+        match first_kept {
+            Some(key) => self.truncate_changes(change_sequence(&key)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Receive every change committed under a prefix of the tree, from
+    /// then on. Watchers are dropped along with their receiver.
+    pub fn watch_prefix(
+        &mut self,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<mpsc::Receiver<Change>, Error> {
+        let tree = match &self.root {
+            TreeRoot::Default => None,
+            TreeRoot::Named(name) => Some(name.to_owned()),
+            _ => {
+                return Err(Error::UnexpectedError(
+                    "Only the default tree and named trees can be watched".to_string(),
+                ))
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push(Watcher {
+            tree,
+            prefix: prefix.as_ref().to_vec(),
+            sender,
+        });
+
+        Ok(receiver)
+    }
+
+    fn recording(&self) -> bool {
+        (self.change_log || !self.watchers.is_empty())
+            && matches!(self.root, TreeRoot::Default | TreeRoot::Named(_))
+    }
+
+    /// Keep a change to the tree, to be logged once committed. `replaced`
+    /// tells whether the key held a value before the change.
+    fn record_change(
+        &mut self,
+        sequence: u64,
+        key: &[u8],
+        value: Option<&LeafValue>,
+        replaced: bool,
+    ) -> Result<(), Error> {
+        if !self.recording() {
+            return Ok(());
+        }
+
+        // A key that expired but wasn't purged yet was already gone for
+        // readers, so setting it again is an insert. The expiry tree is only
+        // updated after the keys, and still holds the old expiry times here.
+        let replaced = replaced && value.is_some() && !self.is_expired(key)?;
+
+        let tree = match &self.root {
+            TreeRoot::Named(name) => Some(name.to_owned()),
+            _ => None,
+        };
+        self.pending_changes.push(PendingChange {
+            sequence,
+            tree,
+            key: key.to_owned(),
+            value: value.cloned(),
+            replaced,
+        });
+
+        Ok(())
+    }
+
+    /// Keep the deletion of a key, which takes a sequence number of its own
+    fn record_delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self.recording() {
+            let sequence = self.pager.next_sequence();
+            self.record_change(sequence, key, None, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run a write and commit it. A failed write is rolled back: the roots
+    /// and sequence number are those of the last commit again, the pages it
+    /// wrote are freed, and the changes it recorded are dropped.
+    fn write<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Self, &mut FreeQueue) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.write_from(FreeQueue::new(), operation)
+    }
+
+    /// Like `write`, going on from a write that already freed pages in `fq`
+    fn write_from<T>(
+        &mut self,
+        mut fq: FreeQueue,
+        operation: impl FnOnce(&mut Self, &mut FreeQueue) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match operation(self, &mut fq) {
+            Ok(result) => {
+                self.commit(fq)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        self.pending_changes.clear();
+        self.pager.rollback()
+    }
+
+    /// Commit the ongoing write, appending its changes to the change log and
+    /// sending them to the watchers
+    fn commit(&mut self, mut fq: FreeQueue) -> Result<(), Error> {
+        let pending = mem::take(&mut self.pending_changes);
+        if pending.is_empty() {
+            return self.pager.commit(fq);
+        }
+
+        // Objects are read before the commit, as replaced ones are freed by it
+        let changes = pending
+            .into_iter()
+            .map(|change| self.committed_change(change))
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.change_log {
+            self.log_changes(&mut fq, &changes)?;
+        }
+        self.pager.commit(fq)?;
+
+        self.notify_watchers(&changes);
+        Ok(())
+    }
+
+    fn committed_change(&self, change: PendingChange) -> Result<Change, Error> {
+        let kind = match (&change.value, change.replaced) {
+            (Some(LeafValue::Number(value)), false) => ChangeKind::Insert(*value),
+            (Some(LeafValue::Number(value)), true) => ChangeKind::Update(*value),
+            (Some(value), replaced) => {
+                let object = self.stored_object(value)?.ok_or(Error::ValueKindMismatch)?;
+                if replaced {
+                    ChangeKind::UpdateObject(object)
+                } else {
+                    ChangeKind::InsertObject(object)
+                }
+            }
+            (None, _) => ChangeKind::Delete,
+        };
+        let tree = change
+            .tree
+            .map(|name| String::from_utf8(name).map_err(|_| Error::KeyParseError))
+            .transpose()?;
+
+        Ok(Change {
+            sequence: change.sequence,
+            tree,
+            key: change.key,
+            kind,
+        })
+    }
+
+    /// Send the committed changes to the watchers of their prefix, dropping
+    /// the watchers whose receiver is gone
+    fn notify_watchers(&mut self, changes: &[Change]) {
+        self.watchers.retain(|watcher| {
+            changes
+                .iter()
+                .filter(|change| {
+                    change.tree.as_ref().map(String::as_bytes) == watcher.tree.as_deref()
+                        && change.key.starts_with(&watcher.prefix)
+                })
+                .all(|change| watcher.sender.send(change.clone()).is_ok())
+        });
+    }
+
+    /// Append changes to the change log, leaving the commit to the caller
+    fn log_changes(&mut self, fq: &mut FreeQueue, changes: &[Change]) -> Result<(), Error> {
+        let mut entries = Vec::with_capacity(changes.len());
+        let mut chunks = vec![];
+        for change in changes {
+            let tree = change.tree.as_deref().map(str::as_bytes);
+            let record = encode_change(tree, &change.key, &change.kind);
+            let key = change.sequence.to_be_bytes().to_vec();
+            if record.len() <= RECORD_CHUNK_SIZE {
+                entries.push((key, Some(object_value(&mut self.pager, record)?)));
+                continue;
+            }
+
+            let mut count = 0;
+            for (idx, chunk) in record.chunks(RECORD_CHUNK_SIZE).enumerate() {
+                let chunk_key = [&key[..], &(idx as u32).to_be_bytes()].concat();
+                let chunk = object_value(&mut self.pager, chunk.to_vec())?;
+                chunks.push((chunk_key, Some(chunk)));
+                count += 1;
+            }
+            entries.push((key, Some(LeafValue::Number(count))));
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        chunks.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if !chunks.is_empty() {
+            if self.find_named_root(CHANGE_CHUNKS_NAME)?.is_none() {
+                self.create_empty_tree(fq, CHANGE_CHUNKS_NAME)?;
+            }
+            self.with_root(TreeRoot::Index(CHANGE_CHUNKS_NAME.to_vec()), |btree| {
+                btree.apply_changes(fq, chunks)
+            })?;
+        }
+        self.with_root(TreeRoot::Index(CHANGE_LOG_NAME.to_vec()), |btree| {
+            btree.apply_changes(fq, entries)
+        })
+    }
+
+    fn cursor<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<Cursor<'_>, Error> {
+        Cursor::new(
+            &self.pager,
+            self.comparator(),
+            &self.root_offset()?,
+            owned_bound(range.start_bound()),
+            owned_bound(range.end_bound()),
+        )
+    }
+
+    /// Write a consistent copy of the database to a new file, holding every
+    /// tree of the last commit. The copy is compacted, with only the pages
+    /// still in use, and is a database file of its own.
+    pub fn backup_to(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut backup = self.start_backup(path)?;
+        self.continue_backup(&mut backup, usize::MAX)?;
+
+        Ok(())
+    }
+
+    /// Start copying the last commit to a new file, like `backup_to`, a few
+    /// pages at a time with `continue_backup`. Writes can go on in between,
+    /// as the pages of the commit being copied are not reused until the
+    /// backup is complete, aborted or dropped.
+    pub fn start_backup(&mut self, path: impl AsRef<Path>) -> Result<Backup, Error> {
+        self.pin_backup(path.as_ref(), None)
+    }
+
+    /// Write a page backup of the last commit, holding the pages written
+    /// since its base backup, or every page without a base. Unlike the
+    /// compacted copy of `backup_to`, page backups keep the offsets of the
+    /// pages in the file, and are restored with `restore_backups`.
+    ///
+    /// Nodes are stamped with the generation of the commit writing them, and
+    /// are never changed in place. The subtrees of nodes which are not newer
+    /// than the base backup are already in the chain, and are left out.
+    pub fn backup_pages_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        base: Option<&Path>,
+    ) -> Result<(), Error> {
+        let mut backup = self.start_page_backup(path, base)?;
+        self.continue_backup(&mut backup, usize::MAX)?;
+
+        Ok(())
+    }
+
+    /// Start a page backup like `backup_pages_to`, to be continued with
+    /// `continue_backup` like the backups of `start_backup`
+    pub fn start_page_backup(
+        &mut self,
+        path: impl AsRef<Path>,
+        base: Option<&Path>,
+    ) -> Result<Backup, Error> {
+        let since = match base {
+            Some(base) => backup::backup_generation(base)?,
+            None => 0,
+        };
+        if since > self.pager.generation() {
+            return Err(Error::InvalidBackup(
+                "The base backup is newer than the database".to_string(),
+            ));
+        }
+
+        self.pin_backup(path.as_ref(), Some(since))
+    }
+
+    fn pin_backup(&mut self, path: &Path, since: Option<u64>) -> Result<Backup, Error> {
+        let (config, pin) = self.pager.pin();
+        Backup::create(path, &config, pin, since)
+    }
+
+    /// Restore a database to a new file from a chain of page backups: a full
+    /// backup, followed by any number of incremental ones, each based on the
+    /// backup before it.
+    pub fn restore_backups(chain: &[&Path], path: &Path) -> Result<(), Error> {
+        Self::restore_backups_with_options(chain, path, Options::default())
+    }
+
+    /// Restore a database like `restore_backups`, with the options it is
+    /// opened with, such as its comparator
+    pub fn restore_backups_with_options(
+        chain: &[&Path],
+        path: &Path,
+        options: Options,
+    ) -> Result<(), Error> {
+        backup::restore(chain, path, &options)
+    }
+
+    /// Copy up to `pages` more pages of a backup, returning whether it is
+    /// complete. The backup must have been started from this database.
+    pub fn continue_backup(&mut self, backup: &mut Backup, pages: usize) -> Result<bool, Error> {
+        match &backup.pin {
+            None => return Ok(true),
+            Some(pin) => self.check_backup_pin(pin)?,
+        }
+
+        let done = backup.step(&self.pager, pages)?;
+        if done {
+            if let Some(pin) = backup.pin.take() {
+                self.pager.unpin(pin)?;
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Give up on a backup, letting the pages of its commit be reused. The
+    /// partial copy is left without a config, and cannot be opened. The
+    /// backup must have been started from this database.
+    pub fn abort_backup(&mut self, mut backup: Backup) -> Result<(), Error> {
+        if let Some(pin) = backup.pin.take() {
+            self.check_backup_pin(&pin)?;
+            self.pager.unpin(pin)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_backup_pin(&self, pin: &CommitPin) -> Result<(), Error> {
+        if !self.pager.holds(pin) {
+            return Err(Error::InvalidBackup(
+                "The backup was started from another database".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sync every commit to disk, regardless of the sync mode.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.pager.sync()
+    }
+
+    /// Sync the commits which the sync mode has not synced yet, such as
+    /// the tail of a group commit. Does nothing if everything is synced.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.pager.flush()
+    }
+
+    /// Close the database, syncing the commits which are not synced yet.
+    /// Dropping the database syncs them as well, but ignores any error.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+/// Iterator over the keys in a range and their values
+pub struct Range<'a> {
+    cursor: Cursor<'a>,
+    expiry: Option<ExpiryFilter<'a>>,
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<(Vec<u8>, u64), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(next_live(&mut self.cursor, &self.expiry)?.and_then(number_entry))
+    }
+}
+
+/// Iterator over the keys in a range and their objects
+pub struct ObjectRange<'a> {
+    pager: &'a Pager,
+    cursor: Cursor<'a>,
+    expiry: Option<ExpiryFilter<'a>>,
+}
+
+impl Iterator for ObjectRange<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(
+            next_live(&mut self.cursor, &self.expiry)?
+                .and_then(|entry| object_entry(self.pager, entry)),
+        )
+    }
+}
+
+/// Iterator over the changes of the change log
+pub struct Changes<'a> {
+    pager: &'a Pager,
+    /// Root of the chunks of the records split in several objects
+    chunks: Option<Offset>,
+    cursor: Cursor<'a>,
+}
+
+impl Changes<'_> {
+    /// Read a record split in `count` chunks back in one piece
+    fn chunked_record(&self, key: &[u8], count: u64) -> Result<Vec<u8>, Error> {
+        let root = self.chunks.as_ref().ok_or(Error::ValueParseError)?;
+        let (start, end) = prefix_range(key);
+
+        let mut record = vec![];
+        let mut found = 0;
+        for entry in Cursor::new(self.pager, &Bytewise, root, start, end)? {
+            record.extend(object_entry(self.pager, entry?)?.1);
+            found += 1;
+        }
+        if found != count {
+            return Err(Error::ValueParseError);
+        }
+
+        Ok(record)
+    }
+}
+
+impl Iterator for Changes<'_> {
+    type Item = Result<Change, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.cursor.next()?.and_then(|(key, value)| {
+            let record = match value {
+                LeafValue::Number(count) => self.chunked_record(&key, count)?,
+                value => object_entry(self.pager, (key.clone(), value))?.1,
+            };
+            decode_change(change_sequence(&key)?, &record)
+        }))
+    }
+}
+
+/// Sequence number of a change from its key in the change log
+fn change_sequence(key: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        key.try_into().map_err(|_| Error::KeyParseError)?,
+    ))
+}
+
+/// Tells the keys of a tree which expired, as of when it was created
+struct ExpiryFilter<'a> {
+    pager: &'a Pager,
+    /// Root of the expiry tree
+    root: Offset,
+    now: u64,
+}
+
+impl ExpiryFilter<'_> {
+    fn is_expired(&self, key: &[u8]) -> Result<bool, Error> {
+        let expiry_key = [&[EXPIRY_BY_KEY], key].concat();
+        match search_tree(self.pager, &Bytewise, &self.root, &expiry_key)? {
+            Some(LeafValue::Number(expires_at)) => Ok(expires_at <= self.now),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The next entry of a cursor whose key hasn't expired
+fn next_live(
+    cursor: &mut Cursor,
+    expiry: &Option<ExpiryFilter>,
+) -> Option<Result<(Vec<u8>, LeafValue), Error>> {
+    loop {
+        let entry = cursor.next()?;
+        if let (Some(expiry), Ok((key, _))) = (expiry, &entry) {
+            match expiry.is_expired(key) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        return Some(entry);
+    }
+}
+
+/// The key of a key in expiry order, within the expiry tree
+fn expiry_order_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+    [&[KEY_BY_EXPIRY], &expires_at.to_be_bytes()[..], key].concat()
+}
+
+/// Milliseconds since the Unix epoch, or zero for earlier times
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Name of the expiry tree of a tree, within the catalog
+fn expiry_tree_name(root: &TreeRoot) -> Vec<u8> {
+    match root {
+        TreeRoot::Named(name) => [EXPIRY_TREE_NAME, b"/", name].concat(),
+        _ => EXPIRY_TREE_NAME.to_vec(),
+    }
+}
+
+/// Name of a secondary index of a tree, within the catalog
+fn index_tree_name(root: &TreeRoot, name: &[u8]) -> Vec<u8> {
+    let tree = match root {
+        TreeRoot::Named(tree) => tree.to_owned(),
+        _ => vec![],
+    };
+    [INDEX_TREE_NAME, &tree.to_key_bytes(), name].concat()
+}
+
+pub(crate) fn is_internal_tree(name: &[u8]) -> bool {
+    name.first() == Some(&INTERNAL_TREE_PREFIX)
+}
+
+/// The key of an index entry, made of the encoded index key followed by the
+/// key of the object, so that entries with the same index key are ordered by
+/// the keys of their objects
+fn index_entry_key(index_key: Vec<u8>, key: &[u8]) -> Vec<u8> {
+    let mut entry_key = index_key.to_key_bytes();
+    entry_key.extend_from_slice(key);
+    entry_key
+}
+
+/// Search a subtree for the value of a key, without deserializing any nodes
+fn search_tree(
+    pager: &Pager,
+    comparator: &dyn Comparator,
+    offset: &Offset,
+    key: &[u8],
+) -> Result<Option<LeafValue>, Error> {
+    Ok(search_tree_entry(pager, comparator, offset, key)?.map(|(value, _)| value))
+}
+
+/// Search a subtree for the value of a key and its sequence number
+fn search_tree_entry(
+    pager: &Pager,
+    comparator: &dyn Comparator,
+    offset: &Offset,
+    key: &[u8],
+) -> Result<Option<(LeafValue, u64)>, Error> {
+    let mut node = NodeView::new(pager.get_page(offset)?)?;
+    while !node.is_leaf() {
+        let idx = node.search(key, comparator, pager)?.unwrap_or_else(|x| x);
+        node = NodeView::new(pager.get_page(&node.child(idx)?)?)?;
+    }
+
+    match node.search(key, comparator, pager)? {
+        Ok(idx) => Ok(Some((node.value(idx)?, node.sequence(idx)?))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn empty_leaf() -> Node {
+    Node::new(
+        NodeKind::Leaf {
+            key_value_pairs: vec![],
+            occupied_space: 0,
+        },
+        None,
+    )
+}
+
+fn number_entry((key, value): (Vec<u8>, LeafValue)) -> Result<(Vec<u8>, u64), Error> {
+    match value {
+        LeafValue::Number(value) => Ok((key, value)),
+        _ => Err(Error::ValueKindMismatch),
+    }
+}
+
+/// A key along with its object
+pub(crate) type ObjectEntry = (Vec<u8>, Vec<u8>);
+
+fn object_entry(pager: &Pager, (key, value): (Vec<u8>, LeafValue)) -> Result<ObjectEntry, Error> {
+    match value {
+        LeafValue::Inline(object) => Ok((key, object)),
+        LeafValue::Object(o) => Ok((key, pager.get_object(&o)?)),
+        LeafValue::Number(_) => Err(Error::ValueKindMismatch),
+    }
+}
+
+/// A key found relative to another one, or to the ends of the tree
+pub(crate) enum Neighbor<'a> {
+    First,
+    Last,
+    /// The greatest key smaller than or equal to this one
+    Floor(&'a [u8]),
+    /// The smallest key greater than or equal to this one
+    Ceiling(&'a [u8]),
+    /// The greatest key strictly smaller than this one
+    Lower(&'a [u8]),
+    /// The smallest key strictly greater than this one
+    Higher(&'a [u8]),
+}
+
+/// The range of keys starting with some prefix, for use with `BTree::range`.
+/// Only holds for the default bytewise comparator.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // The first key after the prefixed keys is the prefix with its last byte
+    // incremented, once trailing 0xff bytes are dropped
+    let mut end = prefix.to_owned();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+
+    match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            (Bound::Included(prefix.to_owned()), Bound::Excluded(end))
+        }
+        None => (Bound::Included(prefix.to_owned()), Bound::Unbounded),
+    }
+}
+
+fn owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+enum InsertCOWStatus {
+    NewOffset(Offset),
+    /// Returned when a node is split in two
+    DidSplit {
+        promoted_key: Vec<u8>,
+        first: Offset,
+        second: Offset,
+        /// Number of key-value pairs under each half
+        first_count: u64,
+        second_count: u64,
+    },
+}
+
+enum DeleteCOWStatus {
+    NewOffset(Offset),
+    /// Returned when a node underflows
+    DidUnderflow(Node),
+}
+
+/// The nodes of a level of a tree being bulk loaded, in key order, along
+/// with the separators between them
+#[derive(Default)]
+struct BulkLevel {
+    /// Offsets of the nodes, with the number of pairs under each
+    children: Vec<(Offset, u64)>,
+    separators: Vec<Vec<u8>>,
+    /// Last key of the last node, to find the next separator
+    last_key: Option<Vec<u8>>,
+}
+
+/// Space used by an internal node holding some keys, and one more child
+fn internal_size(keys: &[Vec<u8>]) -> usize {
+    INTERNAL_HEADER_SIZE
+        + INTERNAL_CHILD_SIZE
+        + keys.iter().map(|k| internal_key_size(k)).sum::<usize>()
+}
+
+/// Build an internal node from its keys and children, along with the number
+/// of pairs under every child
+fn internal_node(keys: Vec<Vec<u8>>, children: Vec<(Offset, u64)>) -> Node {
+    let (children, counts) = children.into_iter().unzip();
+
+    Node::new(
+        NodeKind::Internal {
+            keys,
+            children,
+            counts,
+            occupied_space: 0,
+        },
+        None,
+    )
+}
+
+/// Nodes replacing a subtree which isn't written yet, with the separators
+/// between them
+type NewNodes = (Vec<Node>, Vec<Vec<u8>>);
+
+/// A child of an internal node changed by a batch: either left as it was,
+/// with the number of pairs under it, or replaced by a node which isn't
+/// written yet
+enum BatchChild {
+    Written(Offset, u64),
+    New(Node),
+}
+
+impl BatchChild {
+    fn underflows(&self) -> bool {
+        match self {
+            BatchChild::Written(..) => false,
+            BatchChild::New(node) => match &node.node_kind {
+                NodeKind::Internal { keys, children, .. } => {
+                    children.len() < 2 || internal_size(keys) < UNDERFLOW_SPACE
+                }
+                NodeKind::Leaf {
+                    key_value_pairs, ..
+                } => leaf_size(key_value_pairs) < UNDERFLOW_SPACE,
+            },
+        }
+    }
+}
+
+/// Add the nodes replacing a child to the children of its parent
+fn push_batch_nodes(
+    children: &mut Vec<BatchChild>,
+    keys: &mut Vec<Vec<u8>>,
+    nodes: Vec<Node>,
+    separators: Vec<Vec<u8>>,
+) {
+    let mut nodes = nodes.into_iter();
+    children.extend(nodes.next().map(BatchChild::New));
+    for (separator, node) in separators.into_iter().zip(nodes) {
+        keys.push(separator);
+        children.push(BatchChild::New(node));
+    }
+}
+
+/// Free the long key and the object of a deleted pair
+fn free_pair(fq: &mut FreeQueue, kv: KeyValuePair) {
+    if let Some(overflow) = kv.key_overflow {
+        fq.add(overflow);
+    }
+    if let LeafValue::Object(object) = kv.value {
+        fq.add(object);
+    }
+}
+
+/// Split pairs into as many leaves as needed for each to fit in a page,
+/// returning the separators between them
+fn split_leaf(
+    mut key_value_pairs: Vec<KeyValuePair>,
+    comparator: &dyn Comparator,
+) -> Result<NewNodes, Error> {
+    if leaf_size(&key_value_pairs) <= PAGE_SIZE {
+        let node = Node::new(
+            NodeKind::Leaf {
+                key_value_pairs,
+                occupied_space: 0,
+            },
+            None,
+        );
+        return Ok((vec![node], vec![]));
+    }
+
+    let (separator, second_pairs) = split_key_value_pairs(&mut key_value_pairs, comparator)?;
+    let (mut nodes, mut separators) = split_leaf(key_value_pairs, comparator)?;
+    let (second_nodes, second_separators) = split_leaf(second_pairs, comparator)?;
+
+    nodes.extend(second_nodes);
+    separators.push(separator);
+    separators.extend(second_separators);
+
+    Ok((nodes, separators))
+}
+
+/// Split keys and children into as many internal nodes as needed for each
+/// to fit in a page, returning the keys promoted between them
+fn split_internal(
+    mut keys: Vec<Vec<u8>>,
+    mut children: Vec<(Offset, u64)>,
+) -> Result<NewNodes, Error> {
+    if internal_size(&keys) <= PAGE_SIZE {
+        return Ok((vec![internal_node(keys, children)], vec![]));
+    }
+
+    let split_idx = find_split_key_idx(&keys);
+    if split_idx == 0 {
+        return Err(Error::ImpossibleSplit);
+    }
+
+    let second_keys = keys.split_off(split_idx + 1);
+    let second_children = children.split_off(split_idx + 1);
+    let promoted_key = keys.pop().ok_or(Error::ImpossibleSplit)?;
+
+    let (mut nodes, mut separators) = split_internal(keys, children)?;
+    let (second_nodes, second_separators) = split_internal(second_keys, second_children)?;
+
+    nodes.extend(second_nodes);
+    separators.push(promoted_key);
+    separators.extend(second_separators);
+
+    Ok((nodes, separators))
+}
+
+/// Merge two sibling nodes, splitting them again if they don't fit in a
+/// page
+fn merge_nodes(
+    left: Node,
+    separator: Vec<u8>,
+    right: Node,
+    comparator: &dyn Comparator,
+) -> Result<NewNodes, Error> {
+    match (left.node_kind, right.node_kind) {
+        (
+            NodeKind::Leaf {
+                key_value_pairs: mut pairs,
+                ..
+            },
+            NodeKind::Leaf {
+                key_value_pairs: mut right_pairs,
+                ..
+            },
+        ) => {
+            pairs.append(&mut right_pairs);
+            split_leaf(pairs, comparator)
+        }
+        (
+            NodeKind::Internal {
+                mut keys,
+                children,
+                counts,
+                ..
+            },
+            NodeKind::Internal {
+                keys: mut right_keys,
+                children: right_children,
+                counts: right_counts,
+                ..
+            },
+        ) => {
+            keys.push(separator);
+            keys.append(&mut right_keys);
+            let children = children
+                .into_iter()
+                .zip(counts)
+                .chain(right_children.into_iter().zip(right_counts))
+                .collect();
+            split_internal(keys, children)
+        }
+        _ => Err(Error::InvalidNodeKind),
+    }
+}
+
+/// Keep small objects inline in the leaf, and write larger ones to the
+/// object store
+fn object_value(pager: &mut Pager, object: Vec<u8>) -> Result<LeafValue, Error> {
+    if object.len() <= VALUE_INLINE_MAX_SIZE {
+        return Ok(LeafValue::Inline(object));
+    }
+
+    let o = pager.write_object(&object)?;
+
+    assert_eq!(object, pager.get_object(&o)?);
+
+    Ok(LeafValue::Object(o))
+}
+
+/// Split the pairs of a leaf in the middle, returning the second half along
+/// with the shortest separator of the two halves
+fn split_key_value_pairs(
+    key_value_pairs: &mut Vec<KeyValuePair>,
+    comparator: &dyn Comparator,
+) -> Result<(Vec<u8>, Vec<KeyValuePair>), Error> {
+    // Get the total size of all pairs, in order to find the middle key
+    let total_key_size: usize = key_value_pairs.iter().map(|x| x.size()).sum();
+
+    // Find the median key (total_key_size/2)
+    let mut key_sum = 0;
+    let mut median_idx = 0;
+    for (idx, kvp) in key_value_pairs.iter().enumerate() {
+        key_sum += kvp.size();
+
+        if key_sum > total_key_size / 2 + 1 {
+            median_idx = idx;
+            break;
+        }
+    }
+
+    if median_idx == 0 {
+        return Err(Error::ImpossibleSplit);
+    }
+
+    // Get siblings pairs
+    let sibling_pairs = key_value_pairs.split_off(median_idx);
+
+    // Get a key between the two halves
+    let last = key_value_pairs.last().ok_or(Error::ImpossibleSplit)?;
+    let first = sibling_pairs.first().ok_or(Error::ImpossibleSplit)?;
+    let separator = comparator.separator(&last.key, &first.key);
+
+    Ok((separator, sibling_pairs))
+}
+
+/// Find the key to promote when splitting an internal node. The shortest
+/// of the keys around the middle is promoted, to keep the keys higher up in
+/// the tree short as well.
+fn find_split_key_idx(keys: &[Vec<u8>]) -> usize {
+    let total_key_size: usize = keys.iter().map(|k| internal_key_size(k)).sum();
+
+    // Any key with between a third and two thirds of the keys before it
+    let mut key_sum = 0;
+    let mut split_idx = 0;
+    for (idx, key) in keys.iter().enumerate() {
+        if idx > 0
+            && key_sum >= total_key_size / 3
+            && (split_idx == 0 || key.len() < keys[split_idx].len())
+        {
+            split_idx = idx;
+        }
+
+        key_sum += internal_key_size(key);
+        if key_sum > total_key_size * 2 / 3 {
+            break;
+        }
+    }
+
+    split_idx
+}
+
+mod test {
+    #[cfg(test)]
+    fn temp_db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("inefficax_test_{}.db", name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        use super::BTree;
+
+        let path = temp_db_path("binary_keys");
+        let mut db = BTree::open(&path).unwrap();
+
+        for n in 0..2000_u32 {
+            db.insert(n.to_be_bytes(), n as u64).unwrap();
+        }
+        db.insert(vec![0xff, 0x00, 0xfe], 42).unwrap();
+
+        for n in 0..2000_u32 {
+            assert_eq!(db.search(n.to_be_bytes()).unwrap(), Some(n as u64));
+        }
+        assert_eq!(db.search([0xff, 0x00, 0xfe]).unwrap(), Some(42));
+        assert_eq!(db.delete([0xff, 0x00, 0xfe]).unwrap(), Some(42));
+        assert_eq!(db.search([0xff, 0x00, 0xfe]).unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_long_keys() {
+        use super::BTree;
+        use crate::{error::Error, page_layout::KEY_MAX_SIZE};
+
+        let path = temp_db_path("long_keys");
+        let mut db = BTree::open(&path).unwrap();
+
+        // Long keys sharing their inline prefix
+        let key = |n: usize| format!("https://example.com/{}/{}", "a".repeat(300 + n), n);
+        for n in 0..500 {
+            db.insert(key(n), n as u64).unwrap();
+        }
+        for n in (0..500).step_by(2) {
+            assert_eq!(db.delete(key(n)).unwrap(), Some(n as u64));
+        }
+        drop(db);
+
+        let mut db = BTree::open(&path).unwrap();
+        for n in 0..500 {
+            let expected = if n % 2 == 0 { None } else { Some(n as u64) };
+            assert_eq!(db.search(key(n)).unwrap(), expected);
+        }
+
+        assert!(matches!(
+            db.insert(vec![0; KEY_MAX_SIZE + 1], 0),
+            Err(Error::KeyOverflowError)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_keys() {
+        use super::BTree;
+        use crate::{batch::WriteBatch, error::Error};
+
+        let path = temp_db_path("empty_keys");
+        let mut db = BTree::open(&path).unwrap();
+        db.insert("a", 1).unwrap();
+
+        assert!(matches!(db.insert(Vec::new(), 0), Err(Error::EmptyKey)));
+        assert!(matches!(
+            db.insert_object(Vec::new(), b"object".to_vec()),
+            Err(Error::EmptyKey)
+        ));
+        assert!(matches!(
+            db.compare_and_swap(Vec::new(), None, Some(0)),
+            Err(Error::EmptyKey)
+        ));
+        let mut batch = WriteBatch::new();
+        batch.put("b", 2).put(Vec::new(), 0);
+        assert!(matches!(db.apply_batch(batch), Err(Error::EmptyKey)));
+
+        // The tree is left readable
+        assert_eq!(db.search("a").unwrap(), Some(1));
+        assert_eq!(db.search("b").unwrap(), None);
+        drop(db);
+
+        let path2 = temp_db_path("empty_keys_bulk");
+        let mut db2 = BTree::open(&path2).unwrap();
+        assert!(matches!(
+            db2.bulk_load(vec![(Vec::new(), 0)], 1.0),
+            Err(Error::EmptyKey)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+    }
+
+    #[test]
+    fn test_insert_replaces() {
+        use super::BTree;
+
+        let path = temp_db_path("insert_replaces");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:05}{}", n, "x".repeat((n % 2 * 300) as usize));
+        for n in 0..3000 {
+            db.insert(key(n), n).unwrap();
+        }
+        for n in 0..3000 {
+            db.insert(key(n), n + 1).unwrap();
+        }
+        let sequence = db.sequence();
+        db.insert(key(7), 70).unwrap();
+
+        assert_eq!(db.len().unwrap(), 3000);
+        assert_eq!(db.range::<&[u8]>(..).unwrap().count(), 3000);
+        assert_eq!(db.search(key(6)).unwrap(), Some(7));
+        let versioned = db.search_with_meta(key(7)).unwrap().unwrap();
+        assert_eq!(versioned.value, 70);
+        assert!(versioned.sequence > sequence);
+
+        // Replaced objects are freed, so rewriting one doesn't grow the file
+        for _ in 0..10 {
+            db.insert_object("object", vec![1; 4000]).unwrap();
+        }
+        let file_length = std::fs::metadata(&path).unwrap().len();
+        for _ in 0..100 {
+            db.insert_object("object", vec![2; 4000]).unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_length);
+        assert_eq!(db.search_object("object").unwrap(), Some(vec![2; 4000]));
+        assert_eq!(db.len().unwrap(), 3001);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_inline_and_stored_objects() {
+        use super::BTree;
+        use crate::{
+            error::Error,
+            page_layout::{OBJECT_MAX_SIZE, VALUE_INLINE_MAX_SIZE},
+            WriteBatch,
+        };
+
+        let path = temp_db_path("inline_objects");
+        let mut db = BTree::open(&path).unwrap();
+
+        let object = |n: usize| vec![n as u8; n % (2 * VALUE_INLINE_MAX_SIZE)];
+        for n in 0..1000 {
+            db.insert_object(format!("o{}", n), object(n)).unwrap();
+        }
+        db.insert("n", 42).unwrap();
+        for n in (0..1000).step_by(3) {
+            db.delete_object(format!("o{}", n)).unwrap();
+        }
+        drop(db);
+
+        let mut db = BTree::open(&path).unwrap();
+        for n in 0..1000 {
+            let expected = if n % 3 == 0 { None } else { Some(object(n)) };
+            assert_eq!(db.search_object(format!("o{}", n)).unwrap(), expected);
+        }
+
+        assert!(matches!(db.search("o1"), Err(Error::ValueKindMismatch)));
+        assert!(matches!(
+            db.search_object("n"),
+            Err(Error::ValueKindMismatch)
+        ));
+        assert_eq!(db.search("n").unwrap(), Some(42));
+
+        // Deleting an object as a number, or a number as an object, leaves
+        // it in place
+        assert!(matches!(db.delete("o1"), Err(Error::ValueKindMismatch)));
+        assert!(matches!(db.delete("o1000"), Err(Error::KeyNotFound(_))));
+        assert_eq!(db.search_object("o1").unwrap(), Some(object(1)));
+        assert!(matches!(
+            db.delete_object("n"),
+            Err(Error::ValueKindMismatch)
+        ));
+        assert_eq!(db.search("n").unwrap(), Some(42));
+
+        // Objects larger than a page are refused, leaving the key as it was
+        assert!(matches!(
+            db.insert_object("o1", vec![1; 20000]),
+            Err(Error::ValueOverflowError)
+        ));
+        let mut batch = WriteBatch::new();
+        batch.put_object("big", vec![1; OBJECT_MAX_SIZE + 1]);
+        assert!(matches!(
+            db.apply_batch(batch),
+            Err(Error::ValueOverflowError)
+        ));
+        assert_eq!(db.search_object("o1").unwrap(), Some(object(1)));
+        db.insert_object("big", vec![1; OBJECT_MAX_SIZE]).unwrap();
+        assert_eq!(
+            db.search_object("big").unwrap(),
+            Some(vec![1; OBJECT_MAX_SIZE])
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_range() {
+        use super::{prefix_range, BTree};
+        use std::ops::Bound;
+
+        let path = temp_db_path("range");
+        let mut db = BTree::open(&path).unwrap();
+
+        for n in (0..3000_u32).rev() {
+            db.insert(n.to_be_bytes(), n as u64).unwrap();
+        }
+
+        let values = |range: (Bound<[u8; 4]>, Bound<[u8; 4]>)| -> Vec<u64> {
+            db.range(range).unwrap().map(|kv| kv.unwrap().1).collect()
+        };
+        assert_eq!(
+            values((
+                Bound::Included(10_u32.to_be_bytes()),
+                Bound::Excluded(2500_u32.to_be_bytes())
+            )),
+            (10..2500).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            values((
+                Bound::Excluded(10_u32.to_be_bytes()),
+                Bound::Included(2500_u32.to_be_bytes())
+            )),
+            (11..=2500).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            values((Bound::Unbounded, Bound::Unbounded)),
+            (0..3000).collect::<Vec<_>>()
+        );
+
+        // Every key starting with 0x00 0x00 0x01
+        assert_eq!(
+            db.range(prefix_range(&[0, 0, 1]))
+                .unwrap()
+                .map(|kv| kv.unwrap().1)
+                .collect::<Vec<_>>(),
+            (256..512).collect::<Vec<_>>()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_compression() {
+        use super::BTree;
+
+        let path = temp_db_path("prefix_compression");
+        let mut db = BTree::open(&path).unwrap();
+
+        // Without their common prefix, these keys all fit in a single leaf
+        let key = |n: u64| format!("user/profile/settings/{:04}", n);
+        for n in 0..400 {
+            db.insert(key(n), n).unwrap();
+        }
+        assert_eq!(db.count_nodes().unwrap(), 1);
+
+        // A key without the prefix splits the leaf
+        db.insert("a", 400).unwrap();
+        assert!(db.count_nodes().unwrap() > 1);
+        for n in 0..400 {
+            assert_eq!(db.search(key(n)).unwrap(), Some(n));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_short_separators() {
+        use super::BTree;
+
+        let path = temp_db_path("short_separators");
+        let mut db = BTree::open(&path).unwrap();
+
+        // Whole keys would only fit a few to an internal node, while the
+        // separators between them only need their first bytes
+        let key = |n: u64| format!("{:04}{}", n, "x".repeat(1500));
+        for n in 0..2000 {
+            db.insert(key(n), n).unwrap();
+        }
+        assert_eq!(db.get_depth().unwrap(), 2);
+        for n in 0..2000 {
+            assert_eq!(db.search(key(n)).unwrap(), Some(n));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        use super::BTree;
+        use crate::error::Error;
+
+        let path = temp_db_path("bulk_load");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:06}{}", n, "x".repeat((n % 7 * 60) as usize));
+        assert!(matches!(
+            db.bulk_load([(key(2), 2), (key(1), 1)], 0.9),
+            Err(Error::UnsortedInput { .. })
+        ));
+        assert!(matches!(
+            db.bulk_load([(key(1), 1)], 1.5),
+            Err(Error::InvalidFillFactor(_))
+        ));
+
+        db.bulk_load((0..20000).map(|n| (key(n), n)), 0.9).unwrap();
+        assert!(matches!(
+            db.bulk_load([(key(1), 1)], 0.9),
+            Err(Error::TreeNotEmpty)
+        ));
+
+        for n in 0..20000 {
+            assert_eq!(db.search(key(n)).unwrap(), Some(n));
+        }
+        assert_eq!(db.range::<&[u8]>(..).unwrap().count(), 20000);
+
+        // The loaded tree takes inserts and deletes like any other
+        for n in (0..20000).step_by(2) {
+            assert_eq!(db.delete(key(n)).unwrap(), Some(n));
+        }
+        db.insert(key(20000), 20000).unwrap();
+        for n in 0..=20000 {
+            let expected = if n % 2 == 0 && n < 20000 {
+                None
+            } else {
+                Some(n)
+            };
+            assert_eq!(db.search(key(n)).unwrap(), expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_bulk_load() {
+        use super::BTree;
+        use crate::error::Error;
+
+        let path = temp_db_path("failed_bulk_load");
+        let fresh_path = temp_db_path("failed_bulk_load_fresh");
+        let mut db = BTree::open(&path).unwrap();
+        db.enable_change_log().unwrap();
+
+        // Long keys and stored objects, so that every kind of page is written
+        let key = |n: u64| format!("key{:06}{}", n, "x".repeat(300));
+        let object = |n: u64| vec![n as u8; 1000];
+        let unsorted = (0..2000).chain([0]).map(|n| (key(n), object(n)));
+        let sequence = db.sequence();
+        let generation = db.generation();
+        assert!(matches!(
+            db.bulk_load_objects(unsorted, 0.9),
+            Err(Error::UnsortedInput { .. })
+        ));
+        assert_eq!(db.len().unwrap(), 0);
+        // Nothing of the failed load is committed
+        assert_eq!(db.sequence(), sequence);
+        assert_eq!(db.generation(), generation);
+
+        // The pages of the failed load are reused, and its changes dropped
+        db.bulk_load_objects((0..2000).map(|n| (key(n), object(n))), 0.9)
+            .unwrap();
+        let mut fresh = BTree::open(&fresh_path).unwrap();
+        fresh.enable_change_log().unwrap();
+        fresh
+            .bulk_load_objects((0..2000).map(|n| (key(n), object(n))), 0.9)
+            .unwrap();
+        assert!(
+            std::fs::metadata(&path).unwrap().len()
+                <= std::fs::metadata(&fresh_path).unwrap().len()
+        );
+
+        let changes = db
+            .changes_since(0)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(changes.len(), 2000);
+        assert!(changes.iter().all(|change| change.sequence > sequence));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&fresh_path).unwrap();
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        use super::BTree;
+        use crate::batch::WriteBatch;
+
+        let path = temp_db_path("apply_batch");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:06}", n);
+        let mut batch = WriteBatch::new();
+        for n in 0..10000 {
+            batch.put(key(n), n);
+        }
+        db.apply_batch(batch).unwrap();
+
+        // Operations on the same key are applied in order
+        let mut batch = WriteBatch::new();
+        for n in (0..10000).step_by(2) {
+            batch.delete(key(n));
+        }
+        batch
+            .put(key(1), 100)
+            .put_object(key(3), vec![3; 5000])
+            .delete(key(5))
+            .put(key(5), 500)
+            .delete(key(20000));
+        db.apply_batch(batch).unwrap();
+
+        for n in 0..10000 {
+            let expected = match n {
+                1 => Some(100),
+                5 => Some(500),
+                _ if n % 2 == 0 => None,
+                _ => Some(n),
+            };
+            if n != 3 {
+                assert_eq!(db.search(key(n)).unwrap(), expected);
+            }
+        }
+        assert_eq!(db.search_object(key(3)).unwrap(), Some(vec![3; 5000]));
+
+        // Deleting every key leaves a single empty leaf
+        let mut batch = WriteBatch::new();
+        for n in 0..10000 {
+            batch.delete(key(n));
+        }
+        db.apply_batch(batch).unwrap();
+        assert_eq!(db.count_nodes().unwrap(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_order_statistics() {
+        use super::BTree;
+        use rand::seq::SliceRandom;
+
+        let path = temp_db_path("order_statistics");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:05}", n);
+        let mut numbers: Vec<u64> = (0..5000).collect();
+        numbers.shuffle(&mut rand::thread_rng());
+        for n in &numbers {
+            db.insert(key(*n), *n).unwrap();
+        }
+        for n in numbers.iter().filter(|n| *n % 3 == 0) {
+            db.delete(key(*n)).unwrap();
+        }
+
+        // Only the keys not divisible by 3 are left
+        let rank = |n: u64| n - n.div_ceil(3);
+        assert_eq!(db.len().unwrap(), 3333);
+        for n in (0..5000).filter(|n| n % 3 != 0) {
+            assert_eq!(db.rank(key(n)).unwrap(), rank(n));
+            assert_eq!(db.nth(rank(n)).unwrap(), Some(key(n).into_bytes()));
+        }
+        assert_eq!(db.nth(3333).unwrap(), None);
+
+        assert_eq!(db.rank(key(3)).unwrap(), 2);
+        assert_eq!(db.count_range(key(3)..key(9)).unwrap(), 4);
+        assert_eq!(db.count_range(key(4)..=key(8)).unwrap(), 4);
+        assert_eq!(db.count_range(key(4000)..).unwrap(), 667);
+        assert_eq!(db.count_range(key(9)..key(3)).unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_neighbors() {
+        use super::BTree;
+
+        let path = temp_db_path("neighbors");
+        let mut db = BTree::open(&path).unwrap();
+        assert_eq!(db.first().unwrap(), None);
+        assert_eq!(db.floor("key").unwrap(), None);
+
+        // Only even numbers are keys
+        let key = |n: u64| format!("key{:05}", n);
+        let pair = |n: u64| Some((key(n).into_bytes(), n));
+        for n in (0..4000).step_by(2) {
+            db.insert(key(n), n).unwrap();
+        }
+
+        assert_eq!(db.first().unwrap(), pair(0));
+        assert_eq!(db.last().unwrap(), pair(3998));
+
+        assert_eq!(db.floor(key(1000)).unwrap(), pair(1000));
+        assert_eq!(db.floor(key(1001)).unwrap(), pair(1000));
+        assert_eq!(db.ceiling(key(1000)).unwrap(), pair(1000));
+        assert_eq!(db.ceiling(key(1001)).unwrap(), pair(1002));
+        assert_eq!(db.lower(key(1000)).unwrap(), pair(998));
+        assert_eq!(db.lower(key(1001)).unwrap(), pair(1000));
+        assert_eq!(db.higher(key(1000)).unwrap(), pair(1002));
+        assert_eq!(db.higher(key(999)).unwrap(), pair(1000));
+
+        assert_eq!(db.floor("a").unwrap(), None);
+        assert_eq!(db.lower(key(0)).unwrap(), None);
+        assert_eq!(db.ceiling("z").unwrap(), None);
+        assert_eq!(db.higher(key(3998)).unwrap(), None);
+        assert_eq!(db.floor("z").unwrap(), pair(3998));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        use super::BTree;
+
+        let path = temp_db_path("delete_range");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:05}", n);
+        for n in 0..10000 {
+            db.insert(key(n), n).unwrap();
+        }
+
+        assert_eq!(db.delete_range(key(1000)..key(9000)).unwrap(), 8000);
+        assert_eq!(db.delete_range(key(500)..=key(1000)).unwrap(), 500);
+        assert_eq!(db.delete_range(key(600)..key(700)).unwrap(), 0);
+        assert_eq!(db.len().unwrap(), 1500);
+
+        let keys: Vec<u64> = db
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|kv| kv.unwrap().1)
+            .collect();
+        let expected: Vec<u64> = (0..500).chain(9000..10000).collect();
+        assert_eq!(keys, expected);
+        for n in 0..10000 {
+            let expected = if (500..9000).contains(&n) {
+                None
+            } else {
+                Some(n)
+            };
+            assert_eq!(db.search(key(n)).unwrap(), expected);
+        }
+
+        // The objects of the deleted keys are freed, so that their pages are
+        // reused by the next ones
+        for n in 0..200 {
+            db.insert_object(key(n + 20000), vec![1; 3000]).unwrap();
+        }
+        db.delete_range(key(20000)..).unwrap();
+        let file_size = db.get_file_size().unwrap();
+        for n in 0..200 {
+            db.insert_object(key(n + 20000), vec![2; 3000]).unwrap();
+        }
+        assert!(db.get_file_size().unwrap() < file_size + 100 * crate::PAGE_SIZE as u64);
+
+        assert_eq!(db.delete_range::<&[u8]>(..).unwrap(), 1700);
+        assert_eq!(db.count_nodes().unwrap(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_rollback() {
+        use super::BTree;
+        use crate::WriteBatch;
+
+        let path = temp_db_path("failed_write_rollback");
+        let mut db = BTree::open(&path).unwrap();
+
+        // Objects starting with '!' get an index key too long to be written,
+        // after the object itself was
+        db.create_index("too_long", |object| {
+            (object[0] == b'!').then(|| vec![b'i'; 5000])
+        })
+        .unwrap();
+        db.insert_object("a", vec![1; 100]).unwrap();
+        let sequence = db.sequence();
+
+        let fail = |db: &mut BTree| {
+            assert!(db.insert_object("k", vec![b'!'; 100]).is_err());
+            // The objects of a failed batch are freed as well
+            let mut batch = WriteBatch::new();
+            batch.put("b", 2).put_object("k", vec![b'!'; 100]);
+            for n in 0..50 {
+                batch.put_object(format!("o{}", n), vec![2; 1000]);
+            }
+            assert!(db.apply_batch(batch).is_err());
+        };
+        fail(&mut db);
+        let size = db.get_file_size().unwrap();
+        for _ in 0..10 {
+            fail(&mut db);
+        }
+        assert_eq!(db.search_object("k").unwrap(), None);
+        assert_eq!(db.search("b").unwrap(), None);
+        assert_eq!(db.search_object("o0").unwrap(), None);
+        assert_eq!(db.sequence(), sequence);
+        // The pages written by the failed writes are reused
+        assert_eq!(db.get_file_size().unwrap(), size);
+
+        db.insert("c", 3).unwrap();
+        drop(db);
+        let mut db = BTree::open(&path).unwrap();
+        assert_eq!(db.search_object("k").unwrap(), None);
+        assert_eq!(db.search("b").unwrap(), None);
+        assert_eq!(db.search_object("a").unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.search("c").unwrap(), Some(3));
+        assert_eq!(db.len().unwrap(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_secondary_index() {
+        use super::BTree;
+        use crate::WriteBatch;
+
+        let path = temp_db_path("secondary_index");
+        let mut db = BTree::open(&path).unwrap();
+
+        // Objects are "<city>:<padding>", indexed by city
+        let user = |city: &str, size: usize| format!("{}:{}", city, "x".repeat(size)).into_bytes();
+        let city = |object: &[u8]| {
+            let end = object.iter().position(|&b| b == b':')?;
+            Some(object[..end].to_vec())
+        };
+        let keys = |db: &BTree, city: &str| -> Vec<String> {
+            db.search_index("by_city", city)
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect()
+        };
+
+        // Objects inserted before the index is declared are indexed too
+        db.insert_object("user1", user("paris", 10)).unwrap();
+        db.insert_object("user2", user("oslo", 1000)).unwrap();
+        db.insert_object("user3", b"no city".to_vec()).unwrap();
+        db.create_index("by_city", city).unwrap();
+        assert_eq!(keys(&db, "paris"), vec!["user1"]);
+
+        for n in 4..1000 {
+            let name = if n % 2 == 0 { "paris" } else { "rome" };
+            db.insert_object(format!("user{:04}", n), user(name, n))
+                .unwrap();
+        }
+        assert_eq!(keys(&db, "rome").len(), 498);
+        assert_eq!(keys(&db, "oslo"), vec!["user2"]);
+        assert_eq!(keys(&db, "os"), Vec::<String>::new());
+        assert_eq!(
+            db.search_index("by_city", "oslo").unwrap(),
+            vec![(b"user2".to_vec(), user("oslo", 1000))]
+        );
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put_object("user2", user("rome", 5))
+            .delete("user0004")
+            .put_object("user0005", user("rome", 6))
+            .put("user0006", 6);
+        db.apply_batch(batch).unwrap();
+        assert_eq!(keys(&db, "oslo"), Vec::<String>::new());
+        assert_eq!(keys(&db, "rome")[..3], ["user0005", "user0007", "user0009"]);
+        assert_eq!(keys(&db, "rome").last().unwrap(), "user2");
+        assert_eq!(keys(&db, "paris")[..2], ["user0008", "user0010"]);
+
+        db.delete_object("user2").unwrap();
+        db.delete_range("user0100".."user0900").unwrap();
+        assert_eq!(keys(&db, "rome").len(), 98);
+        assert_eq!(keys(&db, "paris").len(), 97);
+        assert!(db.search_index("by_name", "paris").is_err());
+
+        // Inserting over an object moves its key out of the old index key
+        db.insert_object("user0005", user("oslo", 7)).unwrap();
+        db.insert_object("user0005", user("oslo", 7)).unwrap();
+        assert_eq!(keys(&db, "oslo"), vec!["user0005"]);
+        assert_eq!(keys(&db, "rome").len(), 97);
+        db.insert_object("user0005", user("rome", 8)).unwrap();
+        db.delete_object("user0005").unwrap();
+        db.insert_object("user0007", b"no city".to_vec()).unwrap();
+        db.insert("user0009", 9).unwrap();
+        assert_eq!(keys(&db, "oslo"), Vec::<String>::new());
+        assert_eq!(keys(&db, "rome").len(), 95);
+
+        // Indexes are declared again after opening the file
+        drop(db);
+        let mut db = BTree::open(&path).unwrap();
+        db.create_index("by_city", city).unwrap();
+        assert_eq!(keys(&db, "paris")[..2], ["user0008", "user0010"]);
+        assert_eq!(keys(&db, "paris").len(), 97);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        use super::BTree;
+        use crate::error::Error;
+
+        let path = temp_db_path("compare_and_swap");
+        let mut db = BTree::open(&path).unwrap();
+
+        let key = |n: u64| format!("key{:05}", n);
+        for n in 0..5000 {
+            assert_eq!(db.compare_and_swap(key(n), None, Some(n)).unwrap(), Ok(()));
+        }
+        assert_eq!(
+            db.compare_and_swap(key(7), None, Some(0)).unwrap(),
+            Err(Some(7))
+        );
+        assert_eq!(
+            db.compare_and_swap(key(7), Some(8), Some(0)).unwrap(),
+            Err(Some(7))
+        );
+        assert_eq!(
+            db.compare_and_swap(key(7), Some(7), Some(70)).unwrap(),
+            Ok(())
+        );
+        assert_eq!(db.search(key(7)).unwrap(), Some(70));
+        assert_eq!(
+            db.compare_and_swap("missing", Some(1), None).unwrap(),
+            Err(None)
+        );
+
+        // Deletes shrink the tree like any other delete
+        for n in (0..5000).filter(|n| n % 10 != 0) {
+            let value = if n == 7 { 70 } else { n };
+            assert_eq!(
+                db.compare_and_swap(key(n), Some(value), None).unwrap(),
+                Ok(())
+            );
+        }
+        assert_eq!(db.len().unwrap(), 500);
+        let keys: Vec<u64> = db
+            .range::<&[u8]>(..)
+            .unwrap()
+            .map(|kv| kv.unwrap().1)
+            .collect();
+        assert_eq!(keys, (0..500).map(|n| n * 10).collect::<Vec<_>>());
+
+        let object = vec![1; 1000];
+        assert_eq!(
+            db.compare_and_swap_object("object", None, Some(object.clone()))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            db.compare_and_swap_object("object", Some(&[2; 1000]), None)
+                .unwrap(),
+            Err(Some(object.clone()))
+        );
+        assert_eq!(
+            db.compare_and_swap_object("object", Some(&object), Some(vec![3]))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(db.search_object("object").unwrap(), Some(vec![3]));
+        assert!(matches!(
+            db.compare_and_swap("object", None, None),
+            Err(Error::ValueKindMismatch)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        use super::{BTree, Versioned};
+        use crate::WriteBatch;
+
+        let path = temp_db_path("sequence_numbers");
+        let mut db = BTree::open(&path).unwrap();
+        assert_eq!(db.sequence(), 0);
+
+        let key = |n: u64| format!("key{:05}", n);
+        for n in 0..2000 {
+            db.insert(key(n), n).unwrap();
+        }
+        assert_eq!(db.sequence(), 2000);
+        assert_eq!(
+            db.search_with_meta(key(41)).unwrap(),
+            Some(Versioned {
+                value: 41,
+                sequence: 42
+            })
+        );
+
+        let mut batch = WriteBatch::new();
+        batch.put(key(3), 30).put(key(1), 10).delete(key(2));
+        db.apply_batch(batch).unwrap();
+        assert_eq!(db.search_with_meta(key(1)).unwrap().unwrap().sequence, 2001);
+        assert_eq!(db.search_with_meta(key(3)).unwrap().unwrap().sequence, 2002);
+        assert_eq!(db.search_with_meta(key(4)).unwrap().unwrap().sequence, 5);
+
+        // The sequence is kept in the file
+        drop(db);
+        let mut db = BTree::open(&path).unwrap();
+        assert_eq!(db.sequence(), 2002);
+
+        db.insert_object("object", vec![1; 500]).unwrap();
+        let sequence = db
+            .search_object_with_meta("object")
+            .unwrap()
+            .unwrap()
+            .sequence;
+        assert_eq!(sequence, 2003);
+        assert_eq!(
+            db.compare_and_swap_sequence("object", Some(2000), None)
+                .unwrap(),
+            Err(Some(2003))
+        );
+        assert_eq!(
+            db.compare_and_swap_sequence("object", Some(2003), Some(vec![2]))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            db.search_object_with_meta("object").unwrap(),
+            Some(Versioned {
+                value: vec![2],
+                sequence: 2004
+            })
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_change_log() {
+        use crate::{Change, ChangeKind, Database, WriteBatch};
+
+        let path = temp_db_path("change_log");
+        let mut db = Database::open(&path).unwrap();
+        let key = |n: u64| format!("key{:05}", n).into_bytes();
+        assert!(db.default_tree().changes_since(0).is_err());
+
+        // Writes before the log is enabled are not logged
+        db.default_tree().insert(key(0), 0).unwrap();
+        db.default_tree().enable_change_log().unwrap();
+        for n in 1..1000 {
+            db.default_tree().insert(key(n), n).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(key(1), 10).delete(key(2)).delete(key(5000));
+        db.default_tree().apply_batch(batch).unwrap();
+        db.default_tree().delete(key(3)).unwrap();
+        db.default_tree().delete_range(key(10)..key(20)).unwrap();
+        db.open_tree("objects")
+            .unwrap()
+            .insert_object("object", vec![7; 1000])
+            .unwrap();
+
+        let changes = db
+            .default_tree()
+            .changes_since(0)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(changes.len(), 999 + 2 + 1 + 10 + 1);
+        assert!(changes.windows(2).all(|w| w[0].sequence < w[1].sequence));
+        assert_eq!(
+            changes[999],
+            Change {
+                sequence: 1001,
+                tree: None,
+                key: key(1),
+                kind: ChangeKind::Update(10),
+            }
+        );
+        assert_eq!(changes[0].kind, ChangeKind::Insert(1));
+        assert_eq!(changes[1000].kind, ChangeKind::Delete);
+        assert_eq!(changes[1001].key, key(3));
+        assert_eq!(
+            changes.last().unwrap(),
+            &Change {
+                sequence: db.default_tree().sequence(),
+                tree: Some("objects".to_string()),
+                key: b"object".to_vec(),
+                kind: ChangeKind::InsertObject(vec![7; 1000]),
+            }
+        );
+
+        // A consumer resumes after the last change it handled
+        drop(db);
+        let mut db = Database::open(&path).unwrap();
+        let last = changes[1001].sequence;
+        db.default_tree().insert(key(0), 1).unwrap();
+        let resumed = db
+            .default_tree()
+            .changes_since(last)
+            .unwrap()
+            .map(|change| change.unwrap().sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(resumed.len(), 10 + 1 + 1);
+        assert!(resumed.iter().all(|&sequence| sequence > last));
+
+        // Truncation and retention
+        assert_eq!(db.default_tree().truncate_changes(501).unwrap(), 499);
+        assert_eq!(
+            db.default_tree()
+                .changes_since(0)
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .sequence,
+            501
+        );
+        assert_eq!(db.default_tree().retain_changes(5).unwrap(), 515 - 5);
+        assert_eq!(db.default_tree().changes_since(0).unwrap().count(), 5);
+        assert_eq!(db.default_tree().retain_changes(10).unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_change_log_large_records() {
+        use super::BTree;
+        use crate::ChangeKind;
+
+        let path = temp_db_path("change_log_large_records");
+        let mut db = BTree::open(&path).unwrap();
+        db.enable_change_log().unwrap();
+
+        // The record of the change is larger than a page
+        let key = vec![b'k'; 500];
+        let object: Vec<u8> = (0..8000).map(|n| n as u8).collect();
+        db.insert_object(key.clone(), object.clone()).unwrap();
+        db.insert("small", 1).unwrap();
+        let changes = |db: &BTree| {
+            db.changes_since(0)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let logged = changes(&db);
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[0].key, key);
+        assert_eq!(logged[0].kind, ChangeKind::InsertObject(object));
+        assert_eq!(logged[1].kind, ChangeKind::Insert(1));
+
+        // Truncating the log drops the chunks along with the records
+        assert_eq!(db.truncate_changes(logged[1].sequence).unwrap(), 1);
+        assert_eq!(changes(&db).len(), 1);
+        drop(db);
+        let db = BTree::open(&path).unwrap();
+        assert_eq!(db.changes_since(0).unwrap().count(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watch_prefix() {
+        use crate::{Change, ChangeKind, Database, WriteBatch};
+        use std::time::{Duration, SystemTime};
+
+        let path = temp_db_path("watch_prefix");
+        let mut db = Database::open(&path).unwrap();
+        let users = db.default_tree().watch_prefix("user/").unwrap();
+        let orders = db.default_tree().watch_prefix("order/").unwrap();
+
+        db.default_tree().insert("user/1", 1).unwrap();
+        db.default_tree().insert("order/1", 10).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("user/1", 2);
+        db.default_tree().apply_batch(batch).unwrap();
+        db.open_tree("other").unwrap().insert("user/2", 3).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("user/3", 3).delete("user/1").delete("user/4");
+        db.default_tree().apply_batch(batch).unwrap();
+        db.default_tree()
+            .insert_object("user/5", vec![5; 1000])
+            .unwrap();
+        db.default_tree().delete_range("user/".."user0").unwrap();
+
+        let changes = users
+            .try_iter()
+            .map(|change| (change.key, change.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (b"user/1".to_vec(), ChangeKind::Insert(1)),
+                (b"user/1".to_vec(), ChangeKind::Update(2)),
+                (b"user/1".to_vec(), ChangeKind::Delete),
+                (b"user/3".to_vec(), ChangeKind::Insert(3)),
+                (b"user/5".to_vec(), ChangeKind::InsertObject(vec![5; 1000])),
+                (b"user/3".to_vec(), ChangeKind::Delete),
+                (b"user/5".to_vec(), ChangeKind::Delete),
+            ]
+        );
+        assert_eq!(
+            orders.try_iter().collect::<Vec<_>>(),
+            vec![Change {
+                sequence: 2,
+                tree: None,
+                key: b"order/1".to_vec(),
+                kind: ChangeKind::Insert(10),
+            }]
+        );
+
+        // Named trees are watched apart from the default tree
+        let other = db.open_tree("other").unwrap().watch_prefix("").unwrap();
+        db.open_tree("other").unwrap().delete("user/2").unwrap();
+        db.default_tree().insert("user/6", 6).unwrap();
+        let change = other.try_recv().unwrap();
+        assert_eq!(change.tree.as_deref(), Some("other"));
+        assert_eq!(change.kind, ChangeKind::Delete);
+        assert!(other.try_recv().is_err());
+
+        // Setting a key again updates it, unless it expired in between
+        let past = SystemTime::now() - Duration::from_secs(1);
+        db.default_tree().insert("user/6", 60).unwrap();
+        db.default_tree()
+            .insert_with_expiry("user/7", 7, past)
+            .unwrap();
+        db.default_tree().insert("user/7", 70).unwrap();
+        assert_eq!(
+            users
+                .try_iter()
+                .map(|change| change.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                ChangeKind::Insert(6),
+                ChangeKind::Update(60),
+                ChangeKind::Insert(7),
+                ChangeKind::Insert(70),
+            ]
+        );
+
+        // Watchers are dropped along with their receiver
+        drop(users);
+        db.default_tree().insert("user/7", 7).unwrap();
+        assert_eq!(db.default_tree().watchers.len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watch_failed_write() {
+        use super::BTree;
+        use crate::{ChangeKind, Error};
+
+        let path = temp_db_path("watch_failed_write");
+        let mut db = BTree::open(&path).unwrap();
+        let changes = db.watch_prefix("").unwrap();
+
+        // The first keys are recorded before the input turns out unsorted
+        let unsorted = [("b", 1), ("c", 2), ("a", 3)];
+        assert!(matches!(
+            db.bulk_load(unsorted, 1.0),
+            Err(Error::UnsortedInput { .. })
+        ));
+        assert!(changes.try_recv().is_err());
+
+        db.insert("d", 4).unwrap();
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.key, b"d");
+        assert_eq!(change.kind, ChangeKind::Insert(4));
+        assert!(changes.try_recv().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        use super::BTree;
+        use crate::WriteBatch;
+        use std::time::{Duration, SystemTime};
+
+        let path = temp_db_path("expiry");
+        let mut db = BTree::open(&path).unwrap();
+
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let key = |n: u64| format!("key{:05}", n);
+        for n in 0..3000 {
+            match n % 3 {
+                0 => db.insert(key(n), n).unwrap(),
+                1 => db.insert_with_expiry(key(n), n, past).unwrap(),
+                _ => db.insert_with_expiry(key(n), n, future).unwrap(),
+            }
+        }
+        db.insert_object_with_expiry("object", vec![7; 1000], past)
+            .unwrap();
+
+        assert_eq!(db.search(key(3)).unwrap(), Some(3));
+        assert_eq!(db.search(key(4)).unwrap(), None);
+        assert_eq!(db.search(key(5)).unwrap(), Some(5));
+        assert_eq!(db.search_object("object").unwrap(), None);
+        assert_eq!(db.range(key(0)..key(7)).unwrap().count(), 5);
+        assert_eq!(db.floor(key(4)).unwrap().unwrap().1, 3);
+        assert_eq!(db.ceiling(key(4)).unwrap().unwrap().1, 5);
+        assert_eq!(db.last().unwrap().unwrap().1, 2999);
+
+        // Writing a key again clears its expiry time
+        let mut batch = WriteBatch::new();
+        batch.put(key(1), 1).delete(key(2));
+        db.apply_batch(batch).unwrap();
+        db.delete(key(7)).unwrap();
+        db.insert(key(7), 7).unwrap();
+        db.insert(key(4), 4).unwrap();
+        assert_eq!(db.search(key(1)).unwrap(), Some(1));
+        assert_eq!(db.search(key(4)).unwrap(), Some(4));
+        assert_eq!(db.search(key(7)).unwrap(), Some(7));
+
+        assert_eq!(db.purge_expired().unwrap(), 998);
+        assert_eq!(db.purge_expired().unwrap(), 0);
+        assert_eq!(db.range::<&[u8]>(..).unwrap().count(), 2002);
+
+        // Counting purges the keys expired since, leaving them out
+        for n in (0..7).step_by(2) {
+            db.insert_with_expiry(key(n), n, past).unwrap();
+        }
+        assert_eq!(db.count_range(key(0)..key(7)).unwrap(), 3);
+        assert_eq!(db.purge_expired().unwrap(), 0);
+        assert_eq!(db.count_range(key(0)..=key(0)).unwrap(), 0);
+        assert_eq!(db.rank(key(5)).unwrap(), 2);
+        assert_eq!(db.nth(2).unwrap(), Some(key(5).into_bytes()));
+        assert_eq!(db.len().unwrap(), 1999);
+        assert_eq!(db.nth(1998).unwrap(), Some(key(2999).into_bytes()));
+        assert_eq!(db.nth(1999).unwrap(), None);
+
+        // Keys expiring later are kept, and purged once they expire
+        db.insert_with_expiry(key(5000), 5000, past).unwrap();
+        drop(db);
+        let mut db = BTree::open(&path).unwrap();
+        assert_eq!(db.search(key(8)).unwrap(), Some(8));
+        assert_eq!(db.purge_expired().unwrap(), 1);
+        assert_eq!(db.len().unwrap(), 1999);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_comparator_mismatch() {
+        use super::BTree;
+        use crate::{comparator::Reverse, error::Error, options::Options};
+        use std::sync::Arc;
+
+        let path = temp_db_path("comparator_mismatch");
+        let options = Options {
+            comparator: Arc::new(Reverse),
+            ..Default::default()
+        };
+
+        let mut db = BTree::open_with_options(&path, options.clone()).unwrap();
+        db.insert("a", 1).unwrap();
+        drop(db);
+
+        assert!(matches!(
+            BTree::open(&path),
+            Err(Error::ComparatorMismatch { .. })
+        ));
+        let mut db = BTree::open_with_options(&path, options).unwrap();
+        assert_eq!(db.search("a").unwrap(), Some(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_close_syncs_group_tail() {
+        use super::BTree;
+        use crate::options::{Options, SyncMode};
+        use std::time::Duration;
+
+        let path = temp_db_path("close");
+        let options = Options {
+            sync_mode: SyncMode::Group {
+                interval: Duration::from_secs(3600),
+                ops: 1000,
+            },
+            ..Default::default()
+        };
+
+        let mut db = BTree::open_with_options(&path, options.clone()).unwrap();
+        db.insert("a", 1).unwrap();
+        assert!(db.pager.unsynced_commits > 0);
+        db.close().unwrap();
+
+        let mut db = BTree::open_with_options(&path, options).unwrap();
+        assert_eq!(db.search("a").unwrap(), Some(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,110 @@
+use crate::{encoding::Key, error::Error};
+
+/// A change to a key of the default tree or of a named tree, as recorded in
+/// the change log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Sequence number of the write
+    pub sequence: u64,
+    /// Name of the named tree holding the key, or None for the default tree
+    pub tree: Option<String>,
+    pub key: Vec<u8>,
+    pub kind: ChangeKind,
+}
+
+/// What a change did to its key. A key that expired without being purged
+/// counts as missing, so setting it again is an insert.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The key was added with a number
+    Insert(u64),
+    /// The value of the key was replaced by a number
+    Update(u64),
+    /// The key was added with an object
+    InsertObject(Vec<u8>),
+    /// The value of the key was replaced by an object
+    UpdateObject(Vec<u8>),
+    /// The key was deleted, along with its value
+    Delete,
+}
+
+// Every record starts with the tree, followed by the key and the kind of
+// change. Names and keys use the self-delimiting `Key` encoding of bytes.
+const DEFAULT_TREE: u8 = 0x00;
+const NAMED_TREE: u8 = 0x01;
+
+const INSERT: u8 = 0x00;
+const UPDATE: u8 = 0x01;
+const INSERT_OBJECT: u8 = 0x02;
+const UPDATE_OBJECT: u8 = 0x03;
+const DELETE: u8 = 0x04;
+
+/// Encode a change as the record kept in the change log
+pub(crate) fn encode_change(tree: Option<&[u8]>, key: &[u8], kind: &ChangeKind) -> Vec<u8> {
+    let mut record = vec![];
+    match tree {
+        Some(name) => {
+            record.push(NAMED_TREE);
+            name.to_vec().encode_key(&mut record);
+        }
+        None => record.push(DEFAULT_TREE),
+    }
+    key.to_vec().encode_key(&mut record);
+
+    match kind {
+        ChangeKind::Insert(value) => {
+            record.push(INSERT);
+            record.extend_from_slice(&value.to_be_bytes());
+        }
+        ChangeKind::Update(value) => {
+            record.push(UPDATE);
+            record.extend_from_slice(&value.to_be_bytes());
+        }
+        ChangeKind::InsertObject(object) => {
+            record.push(INSERT_OBJECT);
+            record.extend_from_slice(object);
+        }
+        ChangeKind::UpdateObject(object) => {
+            record.push(UPDATE_OBJECT);
+            record.extend_from_slice(object);
+        }
+        ChangeKind::Delete => record.push(DELETE),
+    }
+
+    record
+}
+
+/// Decode a record of the change log
+pub(crate) fn decode_change(sequence: u64, record: &[u8]) -> Result<Change, Error> {
+    let (tree, rest) = match record.split_first() {
+        Some((&NAMED_TREE, rest)) => {
+            let (name, rest) = String::decode_key(rest)?;
+            (Some(name), rest)
+        }
+        Some((&DEFAULT_TREE, rest)) => (None, rest),
+        _ => return Err(Error::ValueParseError),
+    };
+    let (key, rest) = Vec::<u8>::decode_key(rest)?;
+
+    let kind = match rest.split_first() {
+        Some((&INSERT, value)) => ChangeKind::Insert(decode_number(value)?),
+        Some((&UPDATE, value)) => ChangeKind::Update(decode_number(value)?),
+        Some((&INSERT_OBJECT, object)) => ChangeKind::InsertObject(object.to_vec()),
+        Some((&UPDATE_OBJECT, object)) => ChangeKind::UpdateObject(object.to_vec()),
+        Some((&DELETE, [])) => ChangeKind::Delete,
+        _ => return Err(Error::ValueParseError),
+    };
+
+    Ok(Change {
+        sequence,
+        tree,
+        key,
+        kind,
+    })
+}
+
+fn decode_number(bytes: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        bytes.try_into().map_err(|_| Error::ValueParseError)?,
+    ))
+}
@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+
+/// Decides the order of keys in a tree.
+///
+/// The name of the comparator is recorded in the database file when it is
+/// created, and opening the file with a comparator of another name fails.
+/// A comparator must therefore never change its order without also
+/// changing its name.
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Whether keys are compared lexicographically, one byte after another.
+    /// Such keys can be compared piecewise: by equally long prefixes first,
+    /// and by what follows them only if the prefixes are equal. Leaves then
+    /// skip their common key prefix, and only read long keys from disk when
+    /// their inline prefix can't decide.
+    fn is_prefix_ordered(&self) -> bool {
+        false
+    }
+
+    /// A key between two keys, such that `left <= key < right`, which is
+    /// used as the separator of two nodes. The shorter the separator, the
+    /// more of them fit in an internal node.
+    fn separator(&self, left: &[u8], _right: &[u8]) -> Vec<u8> {
+        left.to_owned()
+    }
+}
+
+/// Orders keys lexicographically by their bytes. This is the default.
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "inefficax.bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn is_prefix_ordered(&self) -> bool {
+        true
+    }
+
+    fn separator(&self, left: &[u8], right: &[u8]) -> Vec<u8> {
+        let common = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+        if common == left.len() {
+            // The left key is a prefix of the right one
+            return left.to_owned();
+        }
+
+        // One byte past the common prefix of the right key is enough, unless
+        // that is the whole right key
+        if common + 1 < right.len() {
+            return right[..=common].to_owned();
+        }
+
+        // Otherwise increment the first byte of the left key which can be
+        // incremented while staying below the right key, and drop the rest
+        for idx in common..left.len() {
+            let byte = left[idx];
+            if byte == 0xff || (idx == common && byte + 1 >= right[idx]) {
+                continue;
+            }
+
+            let mut separator = left[..=idx].to_owned();
+            separator[idx] = byte + 1;
+            return separator;
+        }
+
+        left.to_owned()
+    }
+}
+
+/// Orders keys by their bytes, in reverse.
+pub struct Reverse;
+
+impl Comparator for Reverse {
+    fn name(&self) -> &str {
+        "inefficax.reverse"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn is_prefix_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// Orders keys by their bytes, ignoring ASCII case. Keys which only differ
+/// in case are the same key.
+pub struct CaseInsensitive;
+
+impl Comparator for CaseInsensitive {
+    fn name(&self) -> &str {
+        "inefficax.case_insensitive"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+
+    fn is_prefix_ordered(&self) -> bool {
+        true
+    }
+}
+
+/// Orders keys by their bytes, except for runs of ASCII digits which are
+/// compared by their numeric value, so that `n2` comes before `n10`.
+/// Numbers which only differ in leading zeros are ordered by their bytes.
+pub struct Numeric;
+
+impl Comparator for Numeric {
+    fn name(&self) -> &str {
+        "inefficax.numeric"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (mut i, mut j) = (0, 0);
+
+        while i < a.len() && j < b.len() {
+            if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+                let a_start = i;
+                while i < a.len() && a[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let b_start = j;
+                while j < b.len() && b[j].is_ascii_digit() {
+                    j += 1;
+                }
+
+                // Without leading zeros, a longer number is a larger number
+                let a_num = trim_leading_zeros(&a[a_start..i]);
+                let b_num = trim_leading_zeros(&b[b_start..j]);
+                let ord = a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            } else {
+                let ord = a[i].cmp(&b[j]);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+
+        (a.len() - i).cmp(&(b.len() - j)).then(a.cmp(b))
+    }
+}
+
+fn trim_leading_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|d| **d == b'0').count();
+    &digits[zeros..]
+}
+
+mod test {
+    #[test]
+    fn test_numeric_comparator() {
+        use super::{Comparator, Numeric};
+        use std::cmp::Ordering;
+
+        let mut keys = vec![
+            "n10", "n2", "n1", "m100", "n02", "n", "n1a", "n1b2", "n1b10",
+        ];
+        keys.sort_by(|a, b| Numeric.compare(a.as_bytes(), b.as_bytes()));
+
+        assert_eq!(
+            keys,
+            vec!["m100", "n", "n1", "n1a", "n1b2", "n1b10", "n02", "n2", "n10"]
+        );
+        assert_eq!(Numeric.compare(b"n02", b"n2"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_bytewise_separator() {
+        use super::{Bytewise, Comparator};
+
+        let cases: [(&[u8], &[u8], &[u8]); 6] = [
+            (b"abc1000", b"abc2000", b"abc2"),
+            (b"abc1", b"abc2", b"abc1"),
+            (b"abc1", b"abc3", b"abc2"),
+            (b"abc19", b"abc2", b"abc1:"),
+            (b"abc", b"abcd", b"abc"),
+            (b"ab\xff\xff", b"ac", b"ab\xff\xff"),
+        ];
+        for (left, right, expected) in cases {
+            let separator = Bytewise.separator(left, right);
+            assert_eq!(separator, expected);
+            assert!(left <= &separator[..] && &separator[..] < right);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::{cmp::Ordering, ops::Bound};
+
+use crate::{
+    comparator::Comparator,
+    error::Error,
+    node::LeafValue,
+    node_view::NodeView,
+    pager::{Offset, Pager},
+};
+
+/// Walks the key-value pairs of a tree in key order, between two bounds.
+///
+/// Leaves are not linked together, so the cursor keeps the path from the
+/// root down to the current leaf and climbs it to find the next leaf.
+pub struct Cursor<'a> {
+    pager: &'a Pager,
+    comparator: &'a dyn Comparator,
+    /// Internal nodes from the root down, with the index of the visited child
+    path: Vec<(NodeView<'a>, usize)>,
+    leaf: Option<(NodeView<'a>, usize)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(
+        pager: &'a Pager,
+        comparator: &'a dyn Comparator,
+        root_offset: &Offset,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Self, Error> {
+        let mut cursor = Self {
+            pager,
+            comparator,
+            path: vec![],
+            leaf: None,
+            start,
+            end,
+        };
+        cursor.seek(root_offset)?;
+
+        Ok(cursor)
+    }
+
+    /// Descend from some node to the first leaf which may hold keys within
+    /// the start bound
+    fn seek(&mut self, offset: &Offset) -> Result<(), Error> {
+        let start = match &self.start {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key.to_owned()),
+            Bound::Unbounded => None,
+        };
+
+        let mut offset = offset.to_owned();
+        loop {
+            let node = NodeView::new(self.pager.get_page(&offset)?)?;
+            let search = match &start {
+                Some(key) => node.search(key, self.comparator, self.pager)?,
+                None => Err(0),
+            };
+
+            if node.is_leaf() {
+                let idx = match search {
+                    Ok(idx) if matches!(self.start, Bound::Excluded(_)) => idx + 1,
+                    Ok(idx) | Err(idx) => idx,
+                };
+
+                self.leaf = Some((node, idx));
+                return Ok(());
+            }
+
+            let idx = search.unwrap_or_else(|x| x);
+            offset = node.child(idx)?;
+            self.path.push((node, idx));
+        }
+    }
+
+    /// Move to the first key of the next leaf, returning false at the end of
+    /// the tree
+    fn next_leaf(&mut self) -> Result<bool, Error> {
+        while let Some((node, idx)) = self.path.pop() {
+            if idx + 1 < node.child_count() {
+                let mut offset = node.child(idx + 1)?;
+                self.path.push((node, idx + 1));
+
+                // Descend to the leftmost leaf of the next child
+                loop {
+                    let node = NodeView::new(self.pager.get_page(&offset)?)?;
+                    if node.is_leaf() {
+                        self.leaf = Some((node, 0));
+                        return Ok(true);
+                    }
+
+                    offset = node.child(0)?;
+                    self.path.push((node, 0));
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, LeafValue)>, Error> {
+        loop {
+            let (node, idx) = match &self.leaf {
+                Some((node, idx)) => (node, *idx),
+                None => return Ok(None),
+            };
+
+            if idx >= node.key_count() {
+                if !self.next_leaf()? {
+                    self.leaf = None;
+                }
+                continue;
+            }
+
+            let key = node.key(idx, self.pager)?.into_owned();
+            let value = node.value(idx)?;
+            if let Some((_, idx)) = &mut self.leaf {
+                *idx += 1;
+            }
+
+            let past_end = match &self.end {
+                Bound::Included(end) => self.comparator.compare(&key, end) == Ordering::Greater,
+                Bound::Excluded(end) => self.comparator.compare(&key, end) != Ordering::Less,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                self.leaf = None;
+                self.path.clear();
+                return Ok(None);
+            }
+
+            return Ok(Some((key, value)));
+        }
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = Result<(Vec<u8>, LeafValue), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Don't keep walking a tree which can't be read
+            self.leaf = None;
+            self.path.clear();
+        }
+
+        entry.transpose()
+    }
+}
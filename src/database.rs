@@ -0,0 +1,225 @@
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use crate::{
+    btree::{is_internal_tree, BTree, TreeRoot},
+    error::Error,
+    options::Options,
+};
+
+/// A file holding any number of named trees, whose roots are kept in a
+/// catalog tree. The trees share the pager, the free list and every commit.
+///
+/// The tree of a plain `BTree::open` stays available as the default tree.
+pub struct Database {
+    btree: BTree,
+}
+
+impl Database {
+    pub fn open(db_fp: &Path) -> Result<Self, Error> {
+        Self::open_with_options(db_fp, Options::default())
+    }
+
+    pub fn open_with_options(db_fp: &Path, options: Options) -> Result<Self, Error> {
+        let mut btree = BTree::open_with_options(db_fp, options)?;
+        btree.create_catalog()?;
+
+        Ok(Self { btree })
+    }
+
+    /// Open the tree with some name, creating it if it doesn't exist yet.
+    /// Names starting with a zero byte are reserved.
+    pub fn open_tree(&mut self, name: &str) -> Result<NamedTree<'_>, Error> {
+        check_tree_name(name)?;
+        self.btree.create_tree(name.as_bytes())?;
+
+        Ok(NamedTree::new(&mut self.btree, name.as_bytes().to_vec()))
+    }
+
+    /// Delete the tree with some name, freeing all of its pages
+    pub fn drop_tree(&mut self, name: &str) -> Result<(), Error> {
+        check_tree_name(name)?;
+        self.btree.drop_tree(name.as_bytes())
+    }
+
+    /// Names of every named tree, in bytewise order
+    pub fn tree_names(&self) -> Result<Vec<String>, Error> {
+        self.btree
+            .tree_names()?
+            .into_iter()
+            .map(|name| String::from_utf8(name).map_err(|_| Error::KeyParseError))
+            .collect()
+    }
+
+    /// The tree whose root is kept in the file config, as opened by
+    /// `BTree::open`
+    pub fn default_tree(&mut self) -> &mut BTree {
+        &mut self.btree
+    }
+}
+
+/// Keep the internal trees of the catalog out of reach of user names
+fn check_tree_name(name: &str) -> Result<(), Error> {
+    if is_internal_tree(name.as_bytes()) {
+        return Err(Error::ReservedTreeName(name.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// A handle to one named tree of a `Database`, with all of the operations of
+/// a `BTree`
+pub struct NamedTree<'a> {
+    btree: &'a mut BTree,
+}
+
+impl<'a> NamedTree<'a> {
+    fn new(btree: &'a mut BTree, name: Vec<u8>) -> Self {
+        btree.root = TreeRoot::Named(name);
+        Self { btree }
+    }
+}
+
+impl Deref for NamedTree<'_> {
+    type Target = BTree;
+
+    fn deref(&self) -> &BTree {
+        self.btree
+    }
+}
+
+impl DerefMut for NamedTree<'_> {
+    fn deref_mut(&mut self) -> &mut BTree {
+        self.btree
+    }
+}
+
+impl Drop for NamedTree<'_> {
+    fn drop(&mut self) {
+        self.btree.root = TreeRoot::Default;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_named_trees() {
+        use super::Database;
+
+        let path = std::env::temp_dir().join("inefficax_test_named_trees.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path).unwrap();
+
+        for name in ["users", "orders", "logs"] {
+            let mut tree = db.open_tree(name).unwrap();
+            for i in 0..2000u64 {
+                tree.insert(format!("{}/{:05}", name, i), i).unwrap();
+            }
+        }
+        db.default_tree().insert("default", 1).unwrap();
+        // Expiry trees are internal, and dropped along with their tree
+        db.open_tree("logs")
+            .unwrap()
+            .insert_with_expiry("logs/expiring", 1, std::time::SystemTime::now())
+            .unwrap();
+
+        assert_eq!(db.tree_names().unwrap(), vec!["logs", "orders", "users"]);
+        assert_eq!(db.default_tree().len().unwrap(), 1);
+        assert_eq!(db.open_tree("users").unwrap().len().unwrap(), 2000);
+        assert_eq!(
+            db.open_tree("orders")
+                .unwrap()
+                .search("orders/00042")
+                .unwrap(),
+            Some(42)
+        );
+        assert_eq!(
+            db.open_tree("orders")
+                .unwrap()
+                .search("users/00042")
+                .unwrap(),
+            None
+        );
+
+        let size = db.default_tree().get_file_size().unwrap();
+        db.drop_tree("logs").unwrap();
+        assert_eq!(db.tree_names().unwrap(), vec!["orders", "users"]);
+        assert!(db.drop_tree("logs").is_err());
+
+        // The pages of the dropped tree are reused
+        let mut tree = db.open_tree("events").unwrap();
+        for i in 0..2000u64 {
+            tree.insert(format!("events/{:05}", i), i).unwrap();
+        }
+        drop(tree);
+        assert_eq!(db.default_tree().get_file_size().unwrap(), size);
+
+        drop(db);
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.tree_names().unwrap(), vec!["events", "orders", "users"]);
+        assert_eq!(db.open_tree("events").unwrap().len().unwrap(), 2000);
+        assert_eq!(db.default_tree().search("default").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_tree_indexes() {
+        use super::Database;
+        use crate::error::Error;
+
+        let path = std::env::temp_dir().join("inefficax_test_tree_indexes.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path).unwrap();
+
+        let first_byte = |object: &[u8]| object.first().map(|&b| vec![b]);
+        let keys = |tree: &crate::BTree| -> Vec<Vec<u8>> {
+            tree.search_index("by_byte", "a")
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+
+        // Indexes of different trees share a name, without taking any
+        // tree name
+        db.default_tree()
+            .create_index("by_byte", first_byte)
+            .unwrap();
+        db.default_tree()
+            .insert_object("default", b"abc".to_vec())
+            .unwrap();
+        let mut users = db.open_tree("users").unwrap();
+        users.create_index("by_byte", first_byte).unwrap();
+        users.insert_object("user", b"axe".to_vec()).unwrap();
+        drop(users);
+        db.open_tree("by_byte").unwrap().insert("tree", 1).unwrap();
+
+        assert_eq!(db.tree_names().unwrap(), vec!["by_byte", "users"]);
+        assert_eq!(keys(db.default_tree()), vec![b"default".to_vec()]);
+        assert_eq!(
+            keys(&db.open_tree("users").unwrap()),
+            vec![b"user".to_vec()]
+        );
+        assert!(matches!(
+            db.open_tree("\0index/by_byte"),
+            Err(Error::ReservedTreeName(_))
+        ));
+        assert!(matches!(
+            db.drop_tree("\0expiry"),
+            Err(Error::ReservedTreeName(_))
+        ));
+
+        // Dropping a tree drops its indexes, and leaves the others alone
+        db.drop_tree("users").unwrap();
+        let mut users = db.open_tree("users").unwrap();
+        assert!(matches!(
+            users.search_index("by_byte", "a"),
+            Err(Error::IndexNotFound(_))
+        ));
+        users.create_index("by_byte", first_byte).unwrap();
+        assert_eq!(keys(&users), Vec::<Vec<u8>>::new());
+        drop(users);
+        assert_eq!(keys(db.default_tree()), vec![b"default".to_vec()]);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::error::Error;
+
+/// A type which can be used as the key of a `Tree`.
+///
+/// Keys are encoded so that comparing the bytes of two encoded keys gives
+/// the same order as comparing the keys themselves. This only holds with
+/// the default bytewise comparator.
+///
+/// Every encoding is self-delimiting, so that keys can be concatenated into
+/// composite keys. Tuples of keys are encoded this way, and are ordered by
+/// their first component, then their second, and so on.
+pub trait Key: Sized {
+    /// Append the encoded key to some bytes
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decode a key from the start of some bytes, returning the key and
+    /// the bytes following it
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error>;
+
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_key(&mut out);
+        out
+    }
+
+    fn from_key_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        match Self::decode_key(bytes)? {
+            (key, []) => Ok(key),
+            _ => Err(Error::KeyParseError),
+        }
+    }
+}
+
+/// A type which can be stored as the value of a `Tree`
+pub trait Value: Sized {
+    fn encode_value(&self) -> Vec<u8>;
+
+    fn decode_value(bytes: &[u8]) -> Result<Self, Error>;
+}
+
+// Unsigned integers are big-endian, so that their bytes sort like numbers
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {$(
+        impl Key for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                if bytes.len() < SIZE {
+                    return Err(Error::KeyParseError);
+                }
+
+                let (head, rest) = bytes.split_at(SIZE);
+                Ok((<$t>::from_be_bytes(head.try_into().unwrap()), rest))
+            }
+        }
+
+        impl Value for $t {
+            fn encode_value(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode_value(bytes: &[u8]) -> Result<Self, Error> {
+                Ok(<$t>::from_be_bytes(
+                    bytes.try_into().map_err(|_| Error::ValueParseError)?,
+                ))
+            }
+        }
+    )*};
+}
+
+// Signed integers have their sign bit flipped, so that negative numbers
+// sort before positive ones
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Key for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                flipped.encode_key(out);
+            }
+
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                let (flipped, rest) = <$u>::decode_key(bytes)?;
+                Ok(((flipped ^ (1 << (<$u>::BITS - 1))) as $t, rest))
+            }
+        }
+
+        impl Value for $t {
+            fn encode_value(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode_value(bytes: &[u8]) -> Result<Self, Error> {
+                Ok(<$t>::from_be_bytes(
+                    bytes.try_into().map_err(|_| Error::ValueParseError)?,
+                ))
+            }
+        }
+    )*};
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// Bytes end with a 0x00 0x00 terminator, and every 0x00 within them is
+// escaped as 0x00 0xff. The terminator sorts before any escaped byte, so
+// a shorter string sorts first whatever follows it in a composite key.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x00;
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &b in bytes {
+        out.push(b);
+        if b == ESCAPE {
+            out.push(ESCAPED_ZERO);
+        }
+    }
+    out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+fn decode_bytes(bytes: &[u8]) -> Result<(Vec<u8>, &[u8]), Error> {
+    let mut out = vec![];
+    let mut idx = 0;
+    while let Some(&b) = bytes.get(idx) {
+        if b != ESCAPE {
+            out.push(b);
+            idx += 1;
+            continue;
+        }
+
+        match bytes.get(idx + 1) {
+            Some(&TERMINATOR) => return Ok((out, &bytes[idx + 2..])),
+            Some(&ESCAPED_ZERO) => out.push(0x00),
+            _ => return Err(Error::KeyParseError),
+        }
+        idx += 2;
+    }
+
+    // Missing terminator
+    Err(Error::KeyParseError)
+}
+
+impl Key for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        decode_bytes(bytes)
+    }
+}
+
+impl Value for Vec<u8> {
+    fn encode_value(&self) -> Vec<u8> {
+        self.to_owned()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(bytes.to_owned())
+    }
+}
+
+impl Key for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (bytes, rest) = decode_bytes(bytes)?;
+        Ok((
+            String::from_utf8(bytes).map_err(|_| Error::KeyParseError)?,
+            rest,
+        ))
+    }
+}
+
+impl Value for String {
+    fn encode_value(&self) -> Vec<u8> {
+        self.as_bytes().to_owned()
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Self, Error> {
+        String::from_utf8(bytes.to_owned()).map_err(|_| Error::ValueParseError)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Key),+> Key for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(out);)+
+            }
+
+            #[allow(non_snake_case)]
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+                let rest = bytes;
+                $(let ($name, rest) = $name::decode_key(rest)?;)+
+                Ok((($($name,)+), rest))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+
+/// A key made of the first components of the tuple key `K`, whose encoding
+/// is a prefix of the encoding of every key starting with these components
+pub trait KeyPrefix<K: Key>: Key {}
+
+impl<K: Key> KeyPrefix<K> for K {}
+
+macro_rules! impl_prefix {
+    (($($prefix:ident),+) of ($($name:ident),+)) => {
+        impl<$($name: Key),+> KeyPrefix<($($name,)+)> for ($($prefix,)+) {}
+    };
+}
+
+impl_prefix!((A) of (A, B));
+impl_prefix!((A) of (A, B, C));
+impl_prefix!((A, B) of (A, B, C));
+impl_prefix!((A) of (A, B, C, D));
+impl_prefix!((A, B) of (A, B, C, D));
+impl_prefix!((A, B, C) of (A, B, C, D));
+impl_prefix!((A) of (A, B, C, D, E));
+impl_prefix!((A, B) of (A, B, C, D, E));
+impl_prefix!((A, B, C) of (A, B, C, D, E));
+impl_prefix!((A, B, C, D) of (A, B, C, D, E));
+
+mod test {
+    #[test]
+    fn test_key_encoding_order() {
+        use super::Key;
+
+        let mut keys: Vec<(i32, String, Vec<u8>)> = vec![];
+        for n in [-300, -1, 0, 1, 2, 10, 300] {
+            for s in ["", "a", "a\0", "a\0b", "ab", "b"] {
+                for b in [vec![], vec![0x00], vec![0x00, 0xff], vec![0xff]] {
+                    keys.push((n, s.to_owned(), b));
+                }
+            }
+        }
+
+        let mut encoded: Vec<Vec<u8>> = keys.iter().map(|k| k.to_key_bytes()).collect();
+        encoded.sort();
+        let decoded: Vec<(i32, String, Vec<u8>)> = encoded
+            .iter()
+            .map(|e| Key::from_key_bytes(e).unwrap())
+            .collect();
+
+        keys.sort();
+        assert_eq!(decoded, keys);
+    }
+}
@@ -1,7 +1,7 @@
 #[derive(Debug)]
 pub enum Error {
     // Only used on delete and update, search returns None instead
-    KeyNotFound(String),
+    KeyNotFound(Vec<u8>),
     UnexpectedError(String),
     InvalidRootOffset,
     InternalNodeNoChild,
@@ -9,7 +9,43 @@ pub enum Error {
     InvalidNodeKind,
     NodeParseError,
     KeyParseError,
+    /// The key is longer than `KEY_MAX_SIZE`, a quarter of a page
     KeyOverflowError,
+    /// Keys can't be empty, their length is stored as a nonzero varint
+    EmptyKey,
+    ValueParseError,
+    /// The object is longer than `OBJECT_MAX_SIZE`, as stored objects take
+    /// a single page
+    ValueOverflowError,
+    /// The key holds an object where a number was expected, or the other
+    /// way around
+    ValueKindMismatch,
+    /// The file was created with another comparator than the one given
+    ComparatorMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Bulk loading only works on an empty tree
+    TreeNotEmpty,
+    /// Bulk loaded keys must be strictly increasing, which `key` isn't
+    UnsortedInput {
+        previous: Vec<u8>,
+        key: Vec<u8>,
+    },
+    /// Fill factors are fractions of a page, in `(0, 1]`
+    InvalidFillFactor(f64),
+    /// No tree of the database has this name
+    TreeNotFound(String),
+    /// Tree names starting with a zero byte are kept for internal trees
+    ReservedTreeName(String),
+    /// No secondary index with this name was declared
+    IndexNotFound(String),
+    /// The change log was not enabled for this file
+    ChangeLogDisabled,
+    /// A backup is incomplete, or does not belong in a chain of backups
+    InvalidBackup(String),
+    /// The file is not a database, or has a format this version can't read
+    InvalidFormat(String),
     FileSystemError(std::io::Error),
 }
 
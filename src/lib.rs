@@ -1,10 +1,28 @@
+pub mod backup;
+pub mod batch;
 pub mod btree;
+pub mod change_log;
+pub mod comparator;
+mod cursor;
+pub mod database;
+pub mod encoding;
 pub mod error;
 mod node;
+mod node_view;
+pub mod options;
 mod page;
 mod page_layout;
 mod pager;
+pub mod tree;
 
-pub use btree::BTree;
+pub use backup::Backup;
+pub use batch::WriteBatch;
+pub use btree::{BTree, Versioned};
+pub use change_log::{Change, ChangeKind};
+pub use comparator::Comparator;
+pub use database::{Database, NamedTree};
+pub use encoding::{Key, KeyPrefix, Value};
 pub use error::Error;
-pub use page_layout::PAGE_SIZE;
+pub use options::{Options, SyncMode};
+pub use page_layout::{KEY_MAX_SIZE, OBJECT_MAX_SIZE, PAGE_SIZE};
+pub use tree::Tree;
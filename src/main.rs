@@ -1,14 +1,14 @@
 use rand::seq::SliceRandom;
 use std::{path::Path, time::Instant};
 
-use inefficax::{BTree, PAGE_SIZE};
+use inefficax::{Tree, PAGE_SIZE};
 
 fn main() {
     let test_size: u32 = 10_000;
     let mut keys: Vec<u64> = (1..test_size as u64 + 1).collect();
     let mut rng = rand::thread_rng();
 
-    let mut db = BTree::open(Path::new("./db")).unwrap();
+    let mut db: Tree<u64, String> = Tree::open(Path::new("./db")).unwrap();
 
     keys.shuffle(&mut rng);
     let start_time = Instant::now();
@@ -19,11 +19,8 @@ fn main() {
         //         e
         //     })
         //     .unwrap()
-        db.insert_object(
-            format!("n{:1}", n * 1000),
-            format!("Key value: {:10}", n).into(),
-        )
-        .unwrap()
+        db.insert(&(n * 1000), &format!("Key value: {:10}", n))
+            .unwrap()
     }
     let elapsed = start_time.elapsed();
     println!(
@@ -37,8 +34,8 @@ fn main() {
     for n in &keys {
         // assert_eq!(db.search(&format!("n{:1}", n * 1000)).unwrap(), Some(*n));
         assert_eq!(
-            db.search_object(&format!("n{:1}", n * 1000)).unwrap(),
-            Some(format!("Key value: {:10}", n).into())
+            db.search(&(n * 1000)).unwrap(),
+            Some(format!("Key value: {:10}", n))
         );
     }
     let elapsed = start_time.elapsed();
@@ -50,10 +47,9 @@ fn main() {
 
     keys.shuffle(&mut rng);
     let start_time = Instant::now();
-    for idx in 0..keys.len() {
-        let n = keys[idx];
+    for n in &keys {
         // db.delete(&format!("n{:1}", n * 1000)).unwrap();
-        db.delete_object(&format!("n{:1}", n * 1000)).unwrap();
+        db.delete(&(n * 1000)).unwrap();
     }
     let elapsed = start_time.elapsed();
     println!(
@@ -62,6 +58,7 @@ fn main() {
         elapsed.checked_div(test_size).unwrap()
     );
 
+    let db = db.btree();
     println!("\tTree depth: {}", db.get_depth().unwrap());
     let c = db.count_nodes().unwrap();
     println!("\tNode count: {}", c);
@@ -1,14 +1,14 @@
-use std::{cmp::Ordering, vec};
+use std::vec;
 
 use crate::{
     error::Error,
-    page::Page,
+    page::{varint_size, write_varint, Page},
     page_layout::{
-        FromByte, ToByte, INTERNAL_CHILD_COUNT_OFFSET, INTERNAL_CHILD_COUNT_SIZE,
-        INTERNAL_HEADER_SIZE, IS_ROOT_OFFSET, KEY_MAX_SIZE, LEAF_HEADER_SIZE,
-        LEAF_KEY_COUNT_OFFSET, LEAF_KEY_COUNT_SIZE, LEAF_NEXT_OFFSET, LEAF_NEXT_SIZE,
-        LEAF_PREVIOUS_OFFSET, LEAF_PREVIOUS_SIZE, NODE_KIND_OFFSET, PAGE_SIZE,
-        PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE, PTR_SIZE, VALUE_SIZE,
+        ToByte, INTERNAL_CHILD_COUNT_OFFSET, INTERNAL_CHILD_COUNT_SIZE, INTERNAL_CHILD_SIZE,
+        INTERNAL_HEADER_SIZE, IS_ROOT_OFFSET, KEY_INLINE_PREFIX_SIZE, KEY_MAX_SIZE, KEY_SPILL_SIZE,
+        LEAF_HEADER_SIZE, LEAF_KEY_COUNT_OFFSET, LEAF_KEY_COUNT_SIZE, NODE_KIND_OFFSET, PAGE_SIZE,
+        PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE, PTR_SIZE, SLOT_SIZE, SUBTREE_COUNT_SIZE,
+        VALUE_KIND_SIZE, VALUE_SIZE,
     },
     pager::Offset,
 };
@@ -26,18 +26,28 @@ impl Node {
             parent_offset,
         }
     }
+
+    /// Number of key-value pairs in the subtree of this node
+    pub fn entry_count(&self) -> u64 {
+        match &self.node_kind {
+            NodeKind::Internal { counts, .. } => counts.iter().sum(),
+            NodeKind::Leaf {
+                key_value_pairs, ..
+            } => key_value_pairs.len() as u64,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum NodeKind {
     Internal {
-        keys: Vec<String>,
+        keys: Vec<Vec<u8>>,
         children: Vec<Offset>,
+        /// Number of key-value pairs in the subtree of every child
+        counts: Vec<u64>,
         occupied_space: usize,
     },
     Leaf {
-        next: Option<Offset>,
-        previous: Option<Offset>,
         key_value_pairs: Vec<KeyValuePair>,
         occupied_space: usize,
     },
@@ -49,11 +59,10 @@ impl From<&NodeKind> for u8 {
             NodeKind::Internal {
                 keys: _,
                 children: _,
+                counts: _,
                 occupied_space: _,
             } => 0x00,
             NodeKind::Leaf {
-                next: _,
-                previous: _,
                 key_value_pairs: _,
                 occupied_space: _,
             } => 0x01,
@@ -68,11 +77,10 @@ impl TryFrom<u8> for NodeKind {
             0x00 => Ok(Self::Internal {
                 keys: vec![],
                 children: vec![],
+                counts: vec![],
                 occupied_space: 0,
             }),
             0x01 => Ok(Self::Leaf {
-                next: None,
-                previous: None,
                 key_value_pairs: vec![],
                 occupied_space: 0,
             }),
@@ -81,24 +89,49 @@ impl TryFrom<u8> for NodeKind {
     }
 }
 
-#[derive(Eq, Debug, Clone)]
-pub struct KeyValuePair {
-    pub key: String,
-    pub value: u64,
+/// The value of a key in a leaf node
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum LeafValue {
+    /// A plain number, as given to `BTree::insert`
+    Number(u64),
+    /// An object kept in the object store, too large to be inline
+    Object(Offset),
+    /// A small object kept in the leaf, next to its key
+    Inline(Vec<u8>),
 }
 
-impl Ord for KeyValuePair {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key)
+impl From<&LeafValue> for u8 {
+    fn from(value: &LeafValue) -> Self {
+        match value {
+            LeafValue::Number(_) => 0x00,
+            LeafValue::Object(_) => 0x01,
+            LeafValue::Inline(_) => 0x02,
+        }
     }
 }
 
-impl PartialOrd for KeyValuePair {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl LeafValue {
+    /// Space used by the value in a leaf node, including its kind
+    pub fn size(&self) -> usize {
+        VALUE_KIND_SIZE
+            + match self {
+                LeafValue::Number(_) | LeafValue::Object(_) => VALUE_SIZE,
+                LeafValue::Inline(bytes) => varint_size(bytes.len()) + bytes.len(),
+            }
     }
 }
 
+#[derive(Eq, Debug, Clone)]
+pub struct KeyValuePair {
+    pub key: Vec<u8>,
+    /// Object holding the whole key, for keys too long to be kept inline
+    pub key_overflow: Option<Offset>,
+    pub value: LeafValue,
+    /// Sequence number of the write which set the value, or zero in the
+    /// internal trees
+    pub sequence: u64,
+}
+
 impl PartialEq for KeyValuePair {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key && self.value == other.value
@@ -106,129 +139,81 @@ impl PartialEq for KeyValuePair {
 }
 
 impl KeyValuePair {
-    pub fn new(key: String, value: u64) -> KeyValuePair {
-        KeyValuePair { key, value }
+    pub fn new(key: Vec<u8>, value: LeafValue) -> KeyValuePair {
+        KeyValuePair {
+            key,
+            key_overflow: None,
+            value,
+            sequence: 0,
+        }
     }
 }
 
-impl TryFrom<Page> for Node {
-    type Error = Error;
-    fn try_from(page: Page) -> Result<Self, Self::Error> {
-        let raw = page.get_data();
-        let node_kind = NodeKind::try_from(raw[NODE_KIND_OFFSET])?;
-        let is_root = raw[IS_ROOT_OFFSET].from_byte();
-        let parent_offset = if is_root {
-            None
+impl KeyValuePair {
+    /// Space used by the pair in a leaf node, including its slot
+    pub fn size(&self) -> usize {
+        let overflow_size = if self.key.len() > KEY_SPILL_SIZE {
+            PTR_SIZE
         } else {
-            Some(Offset(page.get_usize_from_offset(PARENT_POINTER_OFFSET)?))
+            0
         };
 
-        match node_kind {
-            NodeKind::Internal {
-                mut keys,
-                mut children,
-                occupied_space: _,
-            } => {
-                let child_count = page.get_usize_from_offset(INTERNAL_CHILD_COUNT_OFFSET)?;
-
-                let mut offset = INTERNAL_HEADER_SIZE;
-
-                // Number of keys is one less than the number of children
-                for _ in 1..child_count {
-                    let key_length = raw[offset] as usize; // in bytes
-                    offset += 1;
-                    if key_length == 0 {
-                        return Err(Error::KeyParseError);
-                    }
-
-                    let bytes = raw[offset..offset + key_length].to_owned();
-                    let key = String::from_utf8(bytes).map_err(|_| Error::KeyParseError)?;
-                    offset += key_length;
-
-                    keys.push(key);
-                }
-
-                for _ in 0..child_count {
-                    // TODO: Error here should be virtually impossible, since it is checked in the node serialization (?)
-                    let child = page.get_usize_from_offset(offset).map_err(|_| {
-                        Error::UnexpectedError(
-                            "Failed to get usize when reading child offset in internal node"
-                                .to_string(),
-                        )
-                    })?;
-                    offset += PTR_SIZE;
-
-                    children.push(Offset(child));
-                }
+        SLOT_SIZE
+            + varint_size(self.key.len())
+            + self.inline_key().len()
+            + overflow_size
+            + self.value.size()
+            + varint_size(self.sequence as usize)
+    }
 
-                let occupied_space = offset + 1;
+    /// The part of the key stored in the leaf node itself
+    pub fn inline_key(&self) -> &[u8] {
+        if self.key.len() > KEY_SPILL_SIZE {
+            &self.key[..KEY_INLINE_PREFIX_SIZE]
+        } else {
+            &self.key
+        }
+    }
+}
 
-                Ok(Node {
-                    parent_offset,
-                    node_kind: NodeKind::Internal {
-                        keys,
-                        children,
-                        occupied_space,
-                    },
-                })
-            }
-            NodeKind::Leaf {
-                next: _,
-                previous: _,
-                key_value_pairs: _,
-                occupied_space: _,
-            } => {
-                let next_addr = page.get_usize_from_offset(LEAF_NEXT_OFFSET)?;
-                let prev_addr = page.get_usize_from_offset(LEAF_PREVIOUS_OFFSET)?;
-                let number_of_keys = page.get_usize_from_offset(LEAF_KEY_COUNT_OFFSET)?;
-
-                let mut key_value_pairs = vec![];
-                let mut idx = LEAF_HEADER_SIZE;
-                for _ in 0..number_of_keys {
-                    let key_length = raw[idx] as usize; // in bytes
-                    if key_length == 0 {
-                        return Err(Error::KeyParseError);
-                    }
+/// Length of the prefix shared by the inline bytes of every key of a leaf
+pub fn common_prefix_length(key_value_pairs: &[KeyValuePair]) -> usize {
+    let mut pairs = key_value_pairs.iter();
+    let first = match pairs.next() {
+        Some(first) => first.inline_key(),
+        None => return 0,
+    };
+
+    pairs.fold(first.len(), |length, kv| {
+        shared_prefix_length(&first[..length], kv.inline_key())
+    })
+}
 
-                    let bytes = raw[idx + 1..idx + 1 + key_length].to_owned();
-                    let key = String::from_utf8(bytes).map_err(|_| Error::KeyParseError)?;
+pub fn shared_prefix_length(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
 
-                    let offset = idx + 1 + key_length;
-                    let value = page.get_usize_from_offset(offset).map_err(|_| {
-                        Error::UnexpectedError("Failed to get value (overflow)".to_owned())
-                    })? as u64;
-                    idx = offset + PTR_SIZE;
+/// Space used by a leaf node holding some pairs. Their common prefix is
+/// stored once, after the header.
+pub fn leaf_size(key_value_pairs: &[KeyValuePair]) -> usize {
+    prefixed_leaf_size(
+        common_prefix_length(key_value_pairs),
+        key_value_pairs.iter().map(|kv| kv.size()).sum(),
+        key_value_pairs.len(),
+    )
+}
 
-                    key_value_pairs.push(KeyValuePair { key, value });
-                }
+/// Space used by a leaf from the total size of its pairs, once their common
+/// prefix is stored only once
+pub fn prefixed_leaf_size(prefix_length: usize, pairs_size: usize, pair_count: usize) -> usize {
+    LEAF_HEADER_SIZE + varint_size(prefix_length) + prefix_length + pairs_size
+        - pair_count * prefix_length
+}
 
-                let occupied_space = key_value_pairs
-                    .iter()
-                    .map(|x| 1 + x.key.as_bytes().len() + VALUE_SIZE)
-                    .sum::<usize>()
-                    + LEAF_HEADER_SIZE
-                    + 1; // THIS PLUS 1 IS REALLY IMPORTANT AND MUST NOT BE REMOVED. (todo: find out why (: )
-
-                Ok(Node {
-                    parent_offset,
-                    node_kind: NodeKind::Leaf {
-                        next: if next_addr == 0 {
-                            None
-                        } else {
-                            Some(Offset(next_addr))
-                        },
-                        previous: if prev_addr == 0 {
-                            None
-                        } else {
-                            Some(Offset(prev_addr))
-                        },
-                        key_value_pairs,
-                        occupied_space,
-                    },
-                })
-            }
-        }
-    }
+/// Space used by a key in an internal node, including its slot and the
+/// child to the right of it.
+pub fn internal_key_size(key: &[u8]) -> usize {
+    SLOT_SIZE + varint_size(key.len()) + key.len() + INTERNAL_CHILD_SIZE
 }
 
 impl TryFrom<&Node> for Page {
@@ -237,7 +222,7 @@ impl TryFrom<&Node> for Page {
     fn try_from(node: &Node) -> Result<Self, Self::Error> {
         let mut data = [0x00; PAGE_SIZE];
 
-        data[IS_ROOT_OFFSET] = (node.parent_offset == None).to_byte();
+        data[IS_ROOT_OFFSET] = node.parent_offset.is_none().to_byte();
         data[NODE_KIND_OFFSET] = u8::from(&node.node_kind);
 
         if let Some(po) = &node.parent_offset {
@@ -249,34 +234,26 @@ impl TryFrom<&Node> for Page {
             NodeKind::Internal {
                 keys,
                 children,
+                counts,
                 occupied_space: _,
             } => {
+                if counts.len() != children.len() {
+                    return Err(Error::UnexpectedError(format!(
+                        "Node has {} children but {} subtree counts",
+                        children.len(),
+                        counts.len()
+                    )));
+                }
+
                 //  Child count
                 data[INTERNAL_CHILD_COUNT_OFFSET
                     ..INTERNAL_CHILD_COUNT_OFFSET + INTERNAL_CHILD_COUNT_SIZE]
                     .clone_from_slice(&children.len().to_be_bytes());
 
-                // Keys
-                let mut offset = INTERNAL_HEADER_SIZE;
-                for key in keys {
-                    let key_bytes = key.as_bytes();
-                    let key_length = key_bytes.len();
-                    if key_length > KEY_MAX_SIZE {
-                        return Err(Error::KeyOverflowError);
-                    }
-
-                    // Key length
-                    data[offset] = key_length as u8;
-                    offset += 1;
-
-                    // Key
-                    data[offset..offset + key_length].clone_from_slice(key_bytes);
-                    offset += key_length;
-                }
-
                 // Child offsets
+                let mut offset = INTERNAL_HEADER_SIZE;
                 for child in children {
-                    if offset + PTR_SIZE >= PAGE_SIZE {
+                    if offset + PTR_SIZE > PAGE_SIZE {
                         return Err(Error::UnexpectedError(format!(
                             "Node has too many children - overflowing: {} children ({})",
                             children.len(),
@@ -288,39 +265,80 @@ impl TryFrom<&Node> for Page {
                     data[offset..offset + PTR_SIZE].clone_from_slice(&offset_bytes);
                     offset += PTR_SIZE;
                 }
+
+                // Subtree counts
+                for count in counts {
+                    if offset + SUBTREE_COUNT_SIZE > PAGE_SIZE {
+                        return Err(Error::UnexpectedError(format!(
+                            "Node has too many children - overflowing: {} children ({})",
+                            children.len(),
+                            offset
+                        )));
+                    }
+
+                    data[offset..offset + SUBTREE_COUNT_SIZE]
+                        .clone_from_slice(&count.to_be_bytes());
+                    offset += SUBTREE_COUNT_SIZE;
+                }
+
+                // Keys, behind one slot per key
+                let mut slot = offset;
+                offset += keys.len() * SLOT_SIZE;
+                for key in keys {
+                    let key_bytes = key.as_slice();
+                    let key_length = key_bytes.len();
+                    if key_length > KEY_MAX_SIZE {
+                        return Err(Error::KeyOverflowError);
+                    }
+
+                    if offset + varint_size(key_length) + key_length > PAGE_SIZE {
+                        return Err(Error::UnexpectedError(format!(
+                            "Node has too many keys - overflowing: {} keys ({})",
+                            keys.len(),
+                            offset
+                        )));
+                    }
+
+                    // Slot
+                    data[slot..slot + SLOT_SIZE].clone_from_slice(&(offset as u16).to_be_bytes());
+                    slot += SLOT_SIZE;
+
+                    // Key length
+                    offset += write_varint(&mut data, offset, key_length);
+
+                    // Key
+                    data[offset..offset + key_length].clone_from_slice(key_bytes);
+                    offset += key_length;
+                }
             }
             NodeKind::Leaf {
-                next,
-                previous,
                 key_value_pairs,
                 occupied_space: _,
             } => {
-                // Next pointer
-                if let Some(next) = next {
-                    data[LEAF_NEXT_OFFSET..LEAF_NEXT_OFFSET + LEAF_NEXT_SIZE]
-                        .clone_from_slice(&next.0.to_be_bytes());
-                }
-
-                // Previous pointer
-                if let Some(previous) = previous {
-                    data[LEAF_PREVIOUS_OFFSET..LEAF_PREVIOUS_OFFSET + LEAF_PREVIOUS_SIZE]
-                        .clone_from_slice(&previous.0.to_be_bytes());
-                }
-
                 // Key count
                 data[LEAF_KEY_COUNT_OFFSET..LEAF_KEY_COUNT_OFFSET + LEAF_KEY_COUNT_SIZE]
                     .clone_from_slice(&key_value_pairs.len().to_be_bytes());
 
-                // Key value pairs
+                // Common prefix of the keys, left out of every cell
+                let prefix_length = common_prefix_length(key_value_pairs);
                 let mut offset = LEAF_HEADER_SIZE;
+                offset += write_varint(&mut data, offset, prefix_length);
+                if let Some(first) = key_value_pairs.first() {
+                    data[offset..offset + prefix_length]
+                        .clone_from_slice(&first.key[..prefix_length]);
+                    offset += prefix_length;
+                }
+
+                // Key value pairs, behind one slot per pair
+                let mut slot = offset;
+                offset += key_value_pairs.len() * SLOT_SIZE;
                 for pair in key_value_pairs {
-                    let key_bytes = pair.key.as_bytes();
-                    let key_length = key_bytes.len();
+                    let key_length = pair.key.len();
                     if key_length > KEY_MAX_SIZE {
                         return Err(Error::KeyOverflowError);
                     }
 
-                    if offset + key_length + 1 + VALUE_SIZE >= PAGE_SIZE {
+                    if offset + pair.size() - SLOT_SIZE - prefix_length > PAGE_SIZE {
                         return Err(Error::UnexpectedError(format!(
                             "Leaf node has too many children - overflowing: {} children ({})",
                             key_value_pairs.len(),
@@ -328,14 +346,43 @@ impl TryFrom<&Node> for Page {
                         )));
                     }
 
-                    data[offset] = key_length as u8;
-                    offset += 1;
-                    data[offset..offset + key_length].clone_from_slice(key_bytes);
-                    offset += key_length;
+                    data[slot..slot + SLOT_SIZE].clone_from_slice(&(offset as u16).to_be_bytes());
+                    slot += SLOT_SIZE;
+
+                    offset += write_varint(&mut data, offset, key_length);
+                    let suffix = &pair.inline_key()[prefix_length..];
+                    data[offset..offset + suffix.len()].clone_from_slice(suffix);
+                    offset += suffix.len();
+
+                    // Long keys are followed by the object holding the whole key
+                    if key_length > KEY_SPILL_SIZE {
+                        let overflow = pair.key_overflow.as_ref().ok_or(Error::KeyOverflowError)?;
+                        data[offset..offset + PTR_SIZE].clone_from_slice(&overflow.0.to_be_bytes());
+                        offset += PTR_SIZE;
+                    }
+
+                    // Value kind, followed by the value itself
+                    data[offset] = u8::from(&pair.value);
+                    offset += VALUE_KIND_SIZE;
+                    match &pair.value {
+                        LeafValue::Number(value) => {
+                            data[offset..offset + VALUE_SIZE]
+                                .clone_from_slice(&value.to_be_bytes());
+                            offset += VALUE_SIZE;
+                        }
+                        LeafValue::Object(object) => {
+                            data[offset..offset + VALUE_SIZE]
+                                .clone_from_slice(&(object.0 as u64).to_be_bytes());
+                            offset += VALUE_SIZE;
+                        }
+                        LeafValue::Inline(bytes) => {
+                            offset += write_varint(&mut data, offset, bytes.len());
+                            data[offset..offset + bytes.len()].clone_from_slice(bytes);
+                            offset += bytes.len();
+                        }
+                    }
 
-                    let value_bytes = pair.value.to_be_bytes();
-                    data[offset..offset + VALUE_SIZE].clone_from_slice(&value_bytes);
-                    offset += VALUE_SIZE;
+                    offset += write_varint(&mut data, offset, pair.sequence as usize);
                 }
             }
         }
@@ -358,8 +405,6 @@ impl TryFrom<&Node> for Page {
 //     fn serialize_and_deserialize_leaf_node() -> Result<(), Error> {
 //         let some_leaf = Node::new(
 //             NodeKind::Leaf {
-//                 next: None,
-//                 previous: None,
 //                 key_value_pairs: vec![],
 //                 occupied_space: 0,
 //             },
@@ -449,8 +494,6 @@ impl TryFrom<&Node> for Page {
 
 //         let some_node = Node::new(
 //             NodeKind::Leaf {
-//                 next: None,
-//                 previous: None,
 //                 key_value_pairs: kvp,
 //                 occupied_space: actual_occupied_space,
 //             },
@@ -0,0 +1,450 @@
+use std::{borrow::Cow, cmp::Ordering};
+
+use crate::{
+    comparator::Comparator,
+    error::Error,
+    node::{internal_key_size, leaf_size, KeyValuePair, LeafValue, Node, NodeKind},
+    page::{read_usize, read_varint, PageRef},
+    page_layout::{
+        FromByte, INTERNAL_CHILD_COUNT_OFFSET, INTERNAL_CHILD_SIZE, INTERNAL_HEADER_SIZE,
+        IS_ROOT_OFFSET, KEY_INLINE_PREFIX_SIZE, KEY_SPILL_SIZE, LEAF_HEADER_SIZE,
+        LEAF_KEY_COUNT_OFFSET, NODE_GENERATION_OFFSET, NODE_KIND_OFFSET, PAGE_SIZE,
+        PARENT_POINTER_OFFSET, PTR_SIZE, SLOT_SIZE, SUBTREE_COUNT_SIZE, VALUE_KIND_SIZE,
+        VALUE_SIZE,
+    },
+    pager::{Offset, Pager},
+};
+
+/// A read-only view of a node, reading keys, values and children straight
+/// from the page bytes instead of deserializing the whole node.
+///
+/// Every key is reached through the slot array following the header, which
+/// holds the offset of each key's cell. This makes it possible to binary
+/// search the keys without touching more than log(n) of them.
+///
+/// Leaves store the prefix shared by all their keys once, in front of the
+/// slots, and only the rest of each key in its cell.
+pub struct NodeView<'a> {
+    page: PageRef<'a>,
+    is_leaf: bool,
+    key_count: usize,
+    prefix_offset: usize,
+    prefix_length: usize,
+    slots_offset: usize,
+}
+
+impl<'a> NodeView<'a> {
+    pub fn new(page: PageRef<'a>) -> Result<Self, Error> {
+        let (is_leaf, key_count, prefix_offset, prefix_length, slots_offset) =
+            match page[NODE_KIND_OFFSET] {
+                0x00 => {
+                    let child_count = read_usize(&page, INTERNAL_CHILD_COUNT_OFFSET)?;
+                    if child_count == 0 {
+                        return Err(Error::InternalNodeNoChild);
+                    }
+
+                    // The slots follow the child offsets and subtree counts
+                    let slots_offset = INTERNAL_HEADER_SIZE + child_count * INTERNAL_CHILD_SIZE;
+                    (false, child_count - 1, slots_offset, 0, slots_offset)
+                }
+                0x01 => {
+                    // The slots follow the common prefix of the keys
+                    let (prefix_length, varint_length) = read_varint(&page, LEAF_HEADER_SIZE)?;
+                    let prefix_offset = LEAF_HEADER_SIZE + varint_length;
+                    (
+                        true,
+                        read_usize(&page, LEAF_KEY_COUNT_OFFSET)?,
+                        prefix_offset,
+                        prefix_length,
+                        prefix_offset + prefix_length,
+                    )
+                }
+                _ => return Err(Error::InvalidNodeKind),
+            };
+
+        if slots_offset + key_count * SLOT_SIZE > PAGE_SIZE {
+            return Err(Error::NodeParseError);
+        }
+
+        Ok(Self {
+            page,
+            is_leaf,
+            key_count,
+            prefix_offset,
+            prefix_length,
+            slots_offset,
+        })
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.is_leaf
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.key_count
+    }
+
+    /// Generation of the commit which wrote the node
+    pub fn generation(&self) -> Result<u64, Error> {
+        Ok(read_usize(&self.page, NODE_GENERATION_OFFSET)? as u64)
+    }
+
+    pub fn parent_offset(&self) -> Result<Option<Offset>, Error> {
+        if self.page[IS_ROOT_OFFSET].from_byte() {
+            Ok(None)
+        } else {
+            Ok(Some(Offset(read_usize(&self.page, PARENT_POINTER_OFFSET)?)))
+        }
+    }
+
+    /// Offset of the cell holding the key at idx
+    fn cell_offset(&self, idx: usize) -> Result<usize, Error> {
+        if idx >= self.key_count {
+            return Err(Error::NodeParseError);
+        }
+
+        let slot = self.slots_offset + idx * SLOT_SIZE;
+        Ok(u16::from_be_bytes([self.page[slot], self.page[slot + 1]]) as usize)
+    }
+
+    /// The prefix shared by every key of a leaf, which is not repeated in
+    /// their cells. Always empty for internal nodes.
+    pub fn prefix(&self) -> &[u8] {
+        &self.page[self.prefix_offset..self.prefix_offset + self.prefix_length]
+    }
+
+    /// Length of the key at idx, along with the offset of the rest of its
+    /// inline bytes, following the common prefix
+    fn key_header(&self, idx: usize) -> Result<(usize, usize), Error> {
+        let cell = self.cell_offset(idx)?;
+        let (key_length, varint_length) = read_varint(&self.page, cell)?;
+        if key_length == 0 {
+            return Err(Error::KeyParseError);
+        }
+
+        Ok((key_length, cell + varint_length))
+    }
+
+    /// Length of the part of a key which is stored inline
+    fn inline_key_length(&self, key_length: usize) -> usize {
+        if self.is_leaf && key_length > KEY_SPILL_SIZE {
+            KEY_INLINE_PREFIX_SIZE
+        } else {
+            key_length
+        }
+    }
+
+    /// The inline bytes of the key at idx, without the common prefix
+    fn key_suffix(&self, idx: usize) -> Result<&[u8], Error> {
+        let (key_length, offset) = self.key_header(idx)?;
+        let suffix_length = self
+            .inline_key_length(key_length)
+            .checked_sub(self.prefix_length)
+            .ok_or(Error::KeyParseError)?;
+
+        self.page
+            .get(offset..offset + suffix_length)
+            .ok_or(Error::NodeParseError)
+    }
+
+    /// The bytes of the key at idx stored in this node. For long keys in a
+    /// leaf this is only a prefix of the key.
+    pub fn inline_key(&self, idx: usize) -> Result<Cow<'_, [u8]>, Error> {
+        let suffix = self.key_suffix(idx)?;
+        if self.prefix_length == 0 {
+            return Ok(Cow::Borrowed(suffix));
+        }
+
+        Ok(Cow::Owned([self.prefix(), suffix].concat()))
+    }
+
+    /// The object holding the whole key at idx, if it was too long to be
+    /// stored inline
+    pub fn key_overflow(&self, idx: usize) -> Result<Option<Offset>, Error> {
+        let (key_length, offset) = self.key_header(idx)?;
+        if !self.is_leaf || key_length <= KEY_SPILL_SIZE {
+            return Ok(None);
+        }
+
+        Ok(Some(Offset(read_usize(
+            &self.page,
+            offset + KEY_INLINE_PREFIX_SIZE - self.prefix_length,
+        )?)))
+    }
+
+    /// The whole key at idx, read from its overflow object if necessary
+    pub fn key(&self, idx: usize, pager: &Pager) -> Result<Cow<'_, [u8]>, Error> {
+        match self.key_overflow(idx)? {
+            Some(overflow) => Ok(Cow::Owned(pager.get_object(&overflow)?)),
+            None => self.inline_key(idx),
+        }
+    }
+
+    /// Value of the key at idx, only valid for leaf nodes
+    pub fn value(&self, idx: usize) -> Result<LeafValue, Error> {
+        Ok(self.value_entry(idx)?.0)
+    }
+
+    /// Sequence number of the write which set the value of the key at idx,
+    /// only valid for leaf nodes
+    pub fn sequence(&self, idx: usize) -> Result<u64, Error> {
+        let (_, offset) = self.value_entry(idx)?;
+        Ok(read_varint(&self.page, offset)?.0 as u64)
+    }
+
+    /// Value of the key at idx, along with the offset following it
+    fn value_entry(&self, idx: usize) -> Result<(LeafValue, usize), Error> {
+        let (key_length, mut offset) = self.key_header(idx)?;
+        offset += self.inline_key_length(key_length) - self.prefix_length;
+        if self.is_leaf && key_length > KEY_SPILL_SIZE {
+            offset += PTR_SIZE;
+        }
+
+        let value_kind = *self.page.get(offset).ok_or(Error::NodeParseError)?;
+        offset += VALUE_KIND_SIZE;
+        match value_kind {
+            0x00 => Ok((
+                LeafValue::Number(read_usize(&self.page, offset)? as u64),
+                offset + VALUE_SIZE,
+            )),
+            0x01 => Ok((
+                LeafValue::Object(Offset(read_usize(&self.page, offset)?)),
+                offset + VALUE_SIZE,
+            )),
+            0x02 => {
+                let (length, varint_length) = read_varint(&self.page, offset)?;
+                offset += varint_length;
+                Ok((
+                    LeafValue::Inline(
+                        self.page
+                            .get(offset..offset + length)
+                            .ok_or(Error::NodeParseError)?
+                            .to_owned(),
+                    ),
+                    offset + length,
+                ))
+            }
+            _ => Err(Error::NodeParseError),
+        }
+    }
+
+    /// Number of children, only valid for internal nodes
+    pub fn child_count(&self) -> usize {
+        self.key_count + 1
+    }
+
+    /// Child at idx, only valid for internal nodes
+    pub fn child(&self, idx: usize) -> Result<Offset, Error> {
+        if idx > self.key_count {
+            return Err(Error::InternalNodeNoChild);
+        }
+
+        Ok(Offset(read_usize(
+            &self.page,
+            INTERNAL_HEADER_SIZE + idx * PTR_SIZE,
+        )?))
+    }
+
+    pub fn children(&self) -> Result<Vec<Offset>, Error> {
+        (0..self.child_count()).map(|idx| self.child(idx)).collect()
+    }
+
+    /// Number of key-value pairs in the subtree of the child at idx, only
+    /// valid for internal nodes
+    pub fn subtree_count(&self, idx: usize) -> Result<u64, Error> {
+        if idx > self.key_count {
+            return Err(Error::InternalNodeNoChild);
+        }
+
+        let offset =
+            INTERNAL_HEADER_SIZE + self.child_count() * PTR_SIZE + idx * SUBTREE_COUNT_SIZE;
+        Ok(read_usize(&self.page, offset)? as u64)
+    }
+
+    /// Number of key-value pairs in the subtree of this node
+    pub fn entry_count(&self) -> Result<u64, Error> {
+        if self.is_leaf {
+            return Ok(self.key_count as u64);
+        }
+
+        (0..self.child_count())
+            .map(|idx| self.subtree_count(idx))
+            .sum()
+    }
+
+    /// Binary search the keys of this node, like `slice::binary_search_by`
+    pub fn search(
+        &self,
+        key: &[u8],
+        comparator: &dyn Comparator,
+        pager: &Pager,
+    ) -> Result<Result<usize, usize>, Error> {
+        // Keys compared piecewise are first compared by the common prefix,
+        // and then only by what follows it
+        let prefix_ordered = comparator.is_prefix_ordered();
+        let key = if prefix_ordered {
+            let prefix = self.prefix();
+            match comparator.compare(prefix, &key[..key.len().min(prefix.len())]) {
+                Ordering::Less => return Ok(Err(self.key_count)),
+                Ordering::Greater => return Ok(Err(0)),
+                Ordering::Equal => &key[prefix.len()..],
+            }
+        } else {
+            key
+        };
+
+        let mut low = 0;
+        let mut high = self.key_count;
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let ord = if prefix_ordered {
+                self.compare_suffix(mid, key, comparator, pager)?
+            } else {
+                comparator.compare(&self.key(mid, pager)?, key)
+            };
+
+            match ord {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+
+        Ok(Err(low))
+    }
+
+    /// Compare what follows the common prefix in the key at idx with the
+    /// same part of another key. Spilled keys are only read from their
+    /// overflow object when their inline bytes can't decide.
+    fn compare_suffix(
+        &self,
+        idx: usize,
+        suffix: &[u8],
+        comparator: &dyn Comparator,
+        pager: &Pager,
+    ) -> Result<Ordering, Error> {
+        let inline_suffix = self.key_suffix(idx)?;
+        let overflow = match self.key_overflow(idx)? {
+            Some(overflow) => overflow,
+            None => return Ok(comparator.compare(inline_suffix, suffix)),
+        };
+
+        let ord = comparator.compare(
+            inline_suffix,
+            &suffix[..suffix.len().min(inline_suffix.len())],
+        );
+        if ord != Ordering::Equal {
+            return Ok(ord);
+        }
+
+        let key = pager.get_object(&overflow)?;
+        Ok(comparator.compare(&key[self.prefix_length..], suffix))
+    }
+
+    /// Materialize the full node, to be modified
+    pub fn to_node(&self, pager: &Pager) -> Result<Node, Error> {
+        let parent_offset = self.parent_offset()?;
+
+        let node_kind = if self.is_leaf {
+            let mut key_value_pairs = Vec::with_capacity(self.key_count);
+            for idx in 0..self.key_count {
+                key_value_pairs.push(KeyValuePair {
+                    key: self.key(idx, pager)?.into_owned(),
+                    key_overflow: self.key_overflow(idx)?,
+                    value: self.value(idx)?,
+                    sequence: self.sequence(idx)?,
+                });
+            }
+
+            NodeKind::Leaf {
+                occupied_space: leaf_size(&key_value_pairs),
+                key_value_pairs,
+            }
+        } else {
+            let mut keys = Vec::with_capacity(self.key_count);
+            for idx in 0..self.key_count {
+                keys.push(self.key(idx, pager)?.into_owned());
+            }
+
+            NodeKind::Internal {
+                occupied_space: INTERNAL_HEADER_SIZE
+                    + INTERNAL_CHILD_SIZE
+                    + keys.iter().map(|k| internal_key_size(k)).sum::<usize>(),
+                keys,
+                children: self.children()?,
+                counts: (0..self.child_count())
+                    .map(|idx| self.subtree_count(idx))
+                    .collect::<Result<_, _>>()?,
+            }
+        };
+
+        Ok(Node::new(node_kind, parent_offset))
+    }
+}
+
+mod test {
+    #[test]
+    fn test_search_leaf_view() {
+        use super::NodeView;
+        use crate::{
+            comparator::Bytewise,
+            node::{KeyValuePair, LeafValue, Node, NodeKind},
+            options::Options,
+            page::{Page, PageRef},
+            pager::Pager,
+        };
+
+        let path = std::env::temp_dir().join("inefficax_test_leaf_view.db");
+        let _ = std::fs::remove_file(&path);
+        let mut pager = Pager::open(&path, &Options::default()).unwrap();
+
+        // A key too long to be inline, sharing its inline prefix with "d..."
+        let long_key = [vec![b'd'; 300], vec![b'e']].concat();
+        let mut long_pair =
+            KeyValuePair::new(long_key.clone(), LeafValue::Inline(b"long".to_vec()));
+        long_pair.key_overflow = Some(pager.write_object(&long_key).unwrap());
+
+        let mut key_value_pairs: Vec<KeyValuePair> = ["b", "d", "f"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| KeyValuePair::new(k.as_bytes().to_owned(), LeafValue::Number(i as u64)))
+            .collect();
+        key_value_pairs.insert(2, long_pair);
+
+        let node = Node::new(
+            NodeKind::Leaf {
+                key_value_pairs,
+                occupied_space: 0,
+            },
+            None,
+        );
+
+        let view = NodeView::new(PageRef::Owned(Page::try_from(&node).unwrap())).unwrap();
+        assert_eq!(view.search(b"d", &Bytewise, &pager).unwrap(), Ok(1));
+        assert_eq!(view.value(1).unwrap(), LeafValue::Number(1));
+        assert_eq!(view.search(b"a", &Bytewise, &pager).unwrap(), Err(0));
+        assert_eq!(view.search(b"e", &Bytewise, &pager).unwrap(), Err(3));
+        assert_eq!(view.search(b"g", &Bytewise, &pager).unwrap(), Err(4));
+
+        assert_eq!(view.search(&long_key, &Bytewise, &pager).unwrap(), Ok(2));
+        assert_eq!(view.inline_key(2).unwrap().len(), 64);
+        assert_eq!(view.value(2).unwrap(), LeafValue::Inline(b"long".to_vec()));
+        match (view.to_node(&pager).unwrap().node_kind, node.node_kind) {
+            (
+                NodeKind::Leaf {
+                    key_value_pairs: read,
+                    ..
+                },
+                NodeKind::Leaf {
+                    key_value_pairs: written,
+                    ..
+                },
+            ) => assert_eq!(read, written),
+            _ => panic!("Expected leaf nodes"),
+        }
+
+        drop(pager);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::comparator::{Bytewise, Comparator};
+
+/// Options used when opening a database file.
+#[derive(Clone)]
+pub struct Options {
+    /// Read pages through a memory map of the database file, borrowing
+    /// them instead of copying every page into a buffer. The map is
+    /// recreated whenever the file has grown past it.
+    pub mmap: bool,
+    /// When committed writes are synced to disk.
+    pub sync_mode: SyncMode,
+    /// Order of the keys. Must be the same comparator the file was
+    /// created with, which is checked by its name.
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mmap: false,
+            sync_mode: SyncMode::default(),
+            comparator: Arc::new(Bytewise),
+        }
+    }
+}
+
+/// Durability policy, enforced by the pager on every commit.
+#[derive(Clone, Debug, Default)]
+pub enum SyncMode {
+    /// Sync on every commit. Pages are synced before the root page which
+    /// references them, so an acknowledged write survives a power loss.
+    EveryCommit,
+    /// Group commit: sync once `interval` has passed since the last sync,
+    /// or once `ops` commits have been written since then, whichever comes
+    /// first. Unsynced commits are also synced when the database is closed.
+    ///
+    /// Both are only checked on commits, as there is no background thread:
+    /// once commits stop, the last group stays unsynced until the next
+    /// commit, `BTree::flush` or `BTree::close`. Call `flush` when going
+    /// idle to bound how long a write may stay unsynced.
+    Group { interval: Duration, ops: usize },
+    /// Never sync, leaving it to the operating system.
+    #[default]
+    Never,
+}
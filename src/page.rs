@@ -1,3 +1,5 @@
+use std::ops::Deref;
+
 use crate::{
     error::Error,
     page_layout::{PAGE_SIZE, PTR_SIZE},
@@ -24,18 +26,9 @@ impl Page {
         *self.data
     }
 
-    /// gets a usize from some offset
-    pub fn get_usize_from_offset(&self, offset: usize) -> Result<usize, Error> {
-        if offset >= PAGE_SIZE - PTR_SIZE {
-            return Err(Error::UnexpectedError(
-                "Outside of page when getting usize".to_owned(),
-            ));
-        }
-
-        let bytes = &self.data[offset..offset + PTR_SIZE];
-        Ok(usize::from_be_bytes([
-            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
-        ]))
+    /// as_bytes borrows the underlying array.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..]
     }
 }
 
@@ -44,3 +37,102 @@ impl From<[u8; PAGE_SIZE]> for Page {
         Self::new(data)
     }
 }
+
+/// A page as returned by the pager: either borrowed, e.g. straight from
+/// the memory map, or read from the file into an owned buffer.
+pub enum PageRef<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Page),
+}
+
+impl Deref for PageRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageRef::Borrowed(data) => data,
+            PageRef::Owned(page) => page.as_bytes(),
+        }
+    }
+}
+
+/// gets a usize from some offset of a page sized buffer
+pub fn read_usize(data: &[u8], offset: usize) -> Result<usize, Error> {
+    if offset + PTR_SIZE > data.len() {
+        return Err(Error::UnexpectedError(
+            "Outside of page when getting usize".to_owned(),
+        ));
+    }
+
+    let bytes = &data[offset..offset + PTR_SIZE];
+    Ok(usize::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Number of bytes needed to write a value as a varint
+pub fn varint_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+/// Writes a value as a LEB128 varint at some offset, seven bits per byte
+/// with the high bit set on every byte but the last. Returns the number of
+/// bytes written.
+pub fn write_varint(data: &mut [u8], offset: usize, mut value: usize) -> usize {
+    let mut idx = offset;
+    while value >= 0x80 {
+        data[idx] = (value as u8 & 0x7f) | 0x80;
+        value >>= 7;
+        idx += 1;
+    }
+    data[idx] = value as u8;
+
+    idx + 1 - offset
+}
+
+/// Reads a varint from some offset, returning the value and the number of
+/// bytes it took up.
+pub fn read_varint(data: &[u8], offset: usize) -> Result<(usize, usize), Error> {
+    let mut value = 0;
+    let mut idx = offset;
+    loop {
+        let byte = *data.get(idx).ok_or(Error::NodeParseError)?;
+        let shift = 7 * (idx - offset);
+        if shift >= usize::BITS as usize {
+            return Err(Error::NodeParseError);
+        }
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        idx += 1;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, idx - offset));
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn test_varint() {
+        use super::{read_varint, varint_size, write_varint};
+
+        let mut data = [0_u8; 32];
+        let mut offset = 0;
+        let values = [0, 1, 0x7f, 0x80, 300, 0x3fff, 0x4000, usize::MAX];
+        for v in values {
+            let written = write_varint(&mut data, offset, v);
+            assert_eq!(written, varint_size(v));
+            offset += written;
+        }
+
+        let mut offset = 0;
+        for v in values {
+            let (read, size) = read_varint(&data, offset).unwrap();
+            assert_eq!(read, v);
+            offset += size;
+        }
+    }
+}
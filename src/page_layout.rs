@@ -4,8 +4,25 @@ use std::mem::size_of;
 pub const PAGE_SIZE: usize = 8192;
 pub const PTR_SIZE: usize = size_of::<usize>();
 
-pub const KEY_MAX_SIZE: usize = 0xff; // Length must fit in one byte
+/// Largest key, in bytes. Key lengths are varints, but separator keys are
+/// kept whole in internal nodes, so a key may take up at most a quarter of a
+/// page.
+pub const KEY_MAX_SIZE: usize = PAGE_SIZE / 4;
+// Longer keys spill into an overflow object in leaf nodes, keeping only a
+// prefix inline for comparisons, followed by a pointer to the whole key
+pub const KEY_SPILL_SIZE: usize = 0xff;
+pub const KEY_INLINE_PREFIX_SIZE: usize = 64;
 pub const VALUE_SIZE: usize = size_of::<u64>();
+// Leaf values start with a byte telling numbers, object pointers and inline
+// objects apart. Objects up to VALUE_INLINE_MAX_SIZE are inline, with a varint
+// length, while larger ones go to the object store.
+pub const VALUE_KIND_SIZE: usize = 1;
+pub const VALUE_INLINE_MAX_SIZE: usize = 0xff;
+/// Largest object, in bytes. Stored objects take a single page, after their
+/// length.
+pub const OBJECT_MAX_SIZE: usize = PAGE_SIZE - PTR_SIZE;
+// Offset of a key's cell within the page
+pub const SLOT_SIZE: usize = size_of::<u16>();
 
 // Node header
 pub const IS_ROOT_SIZE: usize = 1;
@@ -14,27 +31,42 @@ pub const NODE_KIND_SIZE: usize = 1;
 pub const NODE_KIND_OFFSET: usize = 1;
 pub const PARENT_POINTER_SIZE: usize = PTR_SIZE;
 pub const PARENT_POINTER_OFFSET: usize = 2;
-pub const NODE_HEADER_SIZE: usize = IS_ROOT_SIZE + NODE_KIND_SIZE + PARENT_POINTER_SIZE;
+// Generation of the commit which wrote the node, stamped by the pager. Nodes
+// are never changed in place, so the pages written since some commit are the
+// nodes of a later generation, and the objects of their leaves.
+pub const NODE_GENERATION_SIZE: usize = size_of::<u64>();
+pub const NODE_GENERATION_OFFSET: usize = PARENT_POINTER_OFFSET + PARENT_POINTER_SIZE;
+pub const NODE_HEADER_SIZE: usize =
+    IS_ROOT_SIZE + NODE_KIND_SIZE + PARENT_POINTER_SIZE + NODE_GENERATION_SIZE;
 
 // Leaf node layout
-pub const LEAF_NEXT_SIZE: usize = PTR_SIZE;
-pub const LEAF_NEXT_OFFSET: usize = NODE_HEADER_SIZE;
-pub const LEAF_PREVIOUS_SIZE: usize = PTR_SIZE;
-pub const LEAF_PREVIOUS_OFFSET: usize = LEAF_NEXT_OFFSET + LEAF_NEXT_SIZE;
+// Reserved and always zero. Leaves are not linked to their siblings, as a copy
+// on write of one leaf would have to copy its neighbours to update their links.
+pub const LEAF_RESERVED_SIZE: usize = 2 * PTR_SIZE;
+pub const LEAF_RESERVED_OFFSET: usize = NODE_HEADER_SIZE;
 pub const LEAF_KEY_COUNT_SIZE: usize = PTR_SIZE;
-pub const LEAF_KEY_COUNT_OFFSET: usize = LEAF_PREVIOUS_OFFSET + LEAF_PREVIOUS_SIZE;
+pub const LEAF_KEY_COUNT_OFFSET: usize = LEAF_RESERVED_OFFSET + LEAF_RESERVED_SIZE;
 pub const LEAF_HEADER_SIZE: usize = LEAF_KEY_COUNT_OFFSET + LEAF_KEY_COUNT_SIZE;
-// results in 8192-10-24=8158 bytes of key-value data
+// results in 8192-18-24=8150 bytes of slots and key-value data
+// The header is followed by the common prefix of all keys, as a varint length
+// and the prefix bytes. The slots follow, holding the offset of every
+// key-value cell in key order. The cells leave out the common prefix, and end
+// with the sequence number of the write which set the value, as a varint.
 
 // Internal node layout
 pub const INTERNAL_CHILD_COUNT_SIZE: usize = PTR_SIZE;
 pub const INTERNAL_CHILD_COUNT_OFFSET: usize = NODE_HEADER_SIZE;
 pub const INTERNAL_HEADER_SIZE: usize = NODE_HEADER_SIZE + PTR_SIZE;
-// results in 8192-10-8=8174 bytes of key-child data
+// results in 8192-18-8=8166 bytes of key-child data
+// The child offsets come first, then the number of key-value pairs in the
+// subtree of every child, followed by one slot per key and the keys
+pub const SUBTREE_COUNT_SIZE: usize = size_of::<u64>();
+pub const INTERNAL_CHILD_SIZE: usize = PTR_SIZE + SUBTREE_COUNT_SIZE;
 
 /// Wrappers for converting byte to bool and back.
 /// The convention used throughout the index file is: one is true; otherwise - false.
 pub trait FromByte {
+    #[allow(clippy::wrong_self_convention)]
     fn from_byte(&self) -> bool;
 }
 
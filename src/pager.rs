@@ -1,20 +1,25 @@
 use crate::{
+    comparator::{Bytewise, Comparator},
     error::Error,
-    page::Page,
-    page_layout::{PAGE_SIZE, PTR_SIZE},
+    options::{Options, SyncMode},
+    page::{read_usize, Page, PageRef},
+    page_layout::{
+        NODE_GENERATION_OFFSET, NODE_GENERATION_SIZE, OBJECT_MAX_SIZE, PAGE_SIZE, PTR_SIZE,
+    },
 };
+use memmap2::Mmap;
 use std::{
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
+    mem::size_of,
     path::Path,
+    sync::Arc,
+    time::Instant,
 };
 
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub struct Offset(pub usize);
 
-#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug)]
-pub struct ObjectAddress(pub usize);
-
 impl From<usize> for Offset {
     fn from(v: usize) -> Self {
         Self(v)
@@ -23,39 +28,88 @@ impl From<usize> for Offset {
 
 pub struct Pager {
     file: File,
+    /// Read-only map of the file, only present when opened with `Options::mmap`
+    map: Option<Mmap>,
     pages_allocated: usize,
     curser: usize,
+    sync_mode: SyncMode,
+    /// Commits written since the file was last synced
+    pub(crate) unsynced_commits: usize,
+    last_sync: Instant,
     pub(crate) config: Config,
+    /// Config as of the last commit, which `rollback` goes back to
+    committed: Config,
+    /// Pages allocated since the last commit, freed again by `rollback`
+    allocated: Vec<Offset>,
+    /// Shared with the pins of the backups in progress, commits being pinned
+    /// for as long as any pin is alive
+    pins: Arc<()>,
+    /// Pages freed while a commit is pinned, which it may still reference.
+    /// They are only freed once nothing is pinned.
+    pinned_free_queue: FreeQueue,
 }
 
 impl Pager {
-    pub fn open(fp: &Path) -> Result<Self, Error> {
+    pub fn open(fp: &Path, options: &Options) -> Result<Self, Error> {
         let fd = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
-            // .truncate(true)
+            .truncate(false)
             .open(fp)?;
 
         let file_len = fd.metadata()?.len() as usize;
         let mut s = Self {
             file: fd,
+            map: None,
             pages_allocated: file_len / PAGE_SIZE,
             curser: 0,
+            sync_mode: options.sync_mode.clone(),
+            unsynced_commits: 0,
+            last_sync: Instant::now(),
             config: Config::default(),
+            committed: Config::default(),
+            allocated: vec![],
+            pins: Arc::new(()),
+            pinned_free_queue: FreeQueue::new(),
         };
 
         // println!("Pages allocated: {}", s.pages_allocated);
 
+        let comparator = options.comparator.name();
+        if comparator.len() > COMPARATOR_NAME_MAX_SIZE {
+            return Err(Error::UnexpectedError(format!(
+                "Comparator name is too long: {}",
+                comparator
+            )));
+        }
+
         if s.pages_allocated != 0 {
-            s.config = Config::try_from(s.get_page(&Offset(0))?)?;
+            s.config = Config::try_from(&*s.get_page(&Offset(0))?)?;
+
+            // Files without a recorded comparator were created before it
+            // could be chosen, and are bytewise
+            let found = s.config.comparator.as_deref().unwrap_or(Bytewise.name());
+            if found != comparator {
+                return Err(Error::ComparatorMismatch {
+                    expected: comparator.to_owned(),
+                    found: found.to_owned(),
+                });
+            }
         } else {
+            s.config.comparator = Some(comparator.to_owned());
             s.write_config()?;
         }
+        s.committed = s.config.clone();
+
         // Get the cursor based on how long the file is
         // TODO: Replace the cursor with gc
         s.curser = s.file.metadata()?.len() as usize;
 
+        if options.mmap {
+            s.map = Some(s.map_file()?);
+        }
+
         Ok(s)
     }
 
@@ -63,27 +117,69 @@ impl Pager {
         Ok(self.file.metadata()?.len())
     }
 
-    // TODO: Probably should have a function which does not read the full page
-    pub fn get_page(&mut self, offset: &Offset) -> std::io::Result<Page> {
+    /// Get a page, borrowing it from the memory map if there is one.
+    /// Pages written after the file was last mapped are read from the file.
+    pub fn get_page(&self, offset: &Offset) -> std::io::Result<PageRef<'_>> {
+        if let Some(data) = self
+            .map
+            .as_ref()
+            .and_then(|m| m.get(offset.0..offset.0 + PAGE_SIZE))
+        {
+            return Ok(PageRef::Borrowed(data));
+        }
+
         let mut page: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-        self.file.read_exact(&mut page)?;
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset.0 as u64))?;
+        file.read_exact(&mut page)?;
 
-        Ok(Page::new(page))
+        Ok(PageRef::Owned(Page::new(page)))
     }
 
-    pub fn get_page_partial(&mut self, offset: &Offset, len: usize) -> std::io::Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(offset.0 as u64))?;
+    pub fn get_page_partial(&self, offset: &Offset, len: usize) -> std::io::Result<Vec<u8>> {
+        if let Some(data) = self
+            .map
+            .as_ref()
+            .and_then(|m| m.get(offset.0..offset.0 + len))
+        {
+            return Ok(data.to_owned());
+        }
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset.0 as u64))?;
 
         let mut page = vec![0u8; len];
-        self.file.read_exact(&mut page)?;
+        file.read_exact(&mut page)?;
 
         Ok(page)
     }
 
+    fn map_file(&self) -> Result<Mmap, Error> {
+        // SAFETY: The map is read-only and the file is only ever modified
+        // through this pager. Pages are never truncated away while mapped.
+        Ok(unsafe { Mmap::map(&self.file)? })
+    }
+
+    /// Map the file again if it has grown past the current map
+    fn remap(&mut self) -> Result<(), Error> {
+        if let Some(map) = &self.map {
+            if map.len() < self.curser {
+                self.map = Some(self.map_file()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a node to a new page, stamped with the generation of the
+    /// ongoing commit. Objects are written with `write_object` instead.
     pub fn write_page(&mut self, page: &Page) -> Result<Offset, Error> {
+        let mut data = page.get_data();
+        data[NODE_GENERATION_OFFSET..NODE_GENERATION_OFFSET + NODE_GENERATION_SIZE]
+            .clone_from_slice(&(self.config.generation + 1).to_be_bytes());
+
         let offset = self.alloc_page()?;
-        self.write_page_at_offset(&offset, page)?;
+        self.write_page_at_offset(&offset, &Page::new(data))?;
         Ok(offset)
     }
 
@@ -98,7 +194,7 @@ impl Pager {
         assert!(page.len() < PAGE_SIZE);
 
         self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&page)?;
+        self.file.write_all(page)?;
 
         Ok(())
     }
@@ -107,15 +203,169 @@ impl Pager {
         self.write_page_at_offset(&Offset(0), &Page::from(&self.config))
     }
 
-    pub fn set_root_page(&mut self, root_page: Offset) -> Result<(), Error> {
+    /// Switch to a new root page. It is not written until the next commit.
+    pub fn set_root_page(&mut self, root_page: Offset) {
         self.config.root_page = Some(root_page);
-        self.write_config()
+    }
+
+    /// Take the next sequence number. It is not written until the next
+    /// commit.
+    pub fn next_sequence(&mut self) -> u64 {
+        self.config.sequence += 1;
+        self.config.sequence
+    }
+
+    /// Sequence number of the last write
+    pub fn sequence(&self) -> u64 {
+        self.config.sequence
+    }
+
+    /// Generation of the last commit. Every commit has the next generation.
+    pub fn generation(&self) -> u64 {
+        self.config.generation
+    }
+
+    /// Switch to a new root page for the catalog of named trees, like
+    /// `set_root_page`
+    pub fn set_catalog_page(&mut self, catalog_page: Offset) {
+        self.config.catalog_page = Some(catalog_page);
+    }
+
+    /// Commit the current root page, then free the pages it replaced.
+    /// This is where the sync mode is enforced.
+    pub fn commit(&mut self, free_queue: FreeQueue) -> Result<(), Error> {
+        // The pages referenced by the new root must be on disk before the root
+        if let SyncMode::EveryCommit = self.sync_mode {
+            self.file.sync_data()?;
+        }
+
+        self.config.generation += 1;
+        self.write_config()?;
+        self.unsynced_commits += 1;
+
+        let should_sync = match &self.sync_mode {
+            SyncMode::EveryCommit => true,
+            SyncMode::Group { interval, ops } => {
+                self.unsynced_commits >= *ops || self.last_sync.elapsed() >= *interval
+            }
+            SyncMode::Never => false,
+        };
+        if should_sync {
+            self.file.sync_data()?;
+            self.unsynced_commits = 0;
+            self.last_sync = Instant::now();
+        }
+
+        // The old pages can only be reused once the new root is written,
+        // since the previous root still references them until then
+        if self.is_pinned() {
+            for offset in free_queue.q() {
+                self.pinned_free_queue.add(offset);
+            }
+        } else {
+            // Pins dropped without being released leave their pages to the
+            // next commit
+            let mut free_queue = free_queue;
+            for offset in std::mem::replace(&mut self.pinned_free_queue, FreeQueue::new()).q() {
+                free_queue.add(offset);
+            }
+            self.free_and_write_config(free_queue)?;
+        }
+        self.committed = self.config.clone();
+        self.allocated.clear();
+
+        self.remap()
+    }
+
+    /// Drop everything written since the last commit, switching back to its
+    /// roots and sequence number and freeing the pages allocated since.
+    /// None of them is referenced by a commit, pinned or not.
+    pub fn rollback(&mut self) -> Result<(), Error> {
+        self.config.root_page = self.committed.root_page.to_owned();
+        self.config.catalog_page = self.committed.catalog_page.to_owned();
+        self.config.sequence = self.committed.sequence;
+
+        let mut free_queue = FreeQueue::new();
+        for offset in std::mem::take(&mut self.allocated) {
+            free_queue.add(offset);
+        }
+        self.free_and_write_config(free_queue)
+    }
+
+    fn free_and_write_config(&mut self, free_queue: FreeQueue) -> Result<(), Error> {
+        if free_queue.q.is_empty() {
+            return Ok(());
+        }
+
+        let first_free_page = self.config.first_free_page.to_owned();
+        self.free_pages(free_queue)?;
+        if self.config.first_free_page != first_free_page {
+            self.write_config()?;
+        }
+
+        // The free list is linked through the freed pages, which have to be
+        // on disk along with the config pointing at them
+        if let SyncMode::EveryCommit = self.sync_mode {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Pin the last commit, keeping all of its pages until the pin is
+    /// released, and get its config. The pages freed in the meantime are not
+    /// reused.
+    ///
+    /// Pages freed while pinned are only kept in memory, and are lost to the
+    /// free list if the file is closed before being unpinned.
+    pub fn pin(&mut self) -> (Config, CommitPin) {
+        (self.config.clone(), CommitPin(Arc::clone(&self.pins)))
+    }
+
+    /// Whether `pin` was handed out by this pager
+    pub fn holds(&self, pin: &CommitPin) -> bool {
+        Arc::ptr_eq(&self.pins, &pin.0)
+    }
+
+    /// Release a commit pinned by `pin`, freeing the pages it kept once
+    /// nothing is pinned anymore. Dropping the pin releases it as well, its
+    /// pages being freed by the next commit.
+    pub fn unpin(&mut self, pin: CommitPin) -> Result<(), Error> {
+        drop(pin);
+        if !self.is_pinned() {
+            let free_queue = std::mem::replace(&mut self.pinned_free_queue, FreeQueue::new());
+            self.free_and_write_config(free_queue)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.pins) > 1
+    }
+
+    /// Sync all writes to disk
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        self.unsynced_commits = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Sync all writes to disk, if there are commits which are not synced yet
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.unsynced_commits > 0 {
+            self.sync()?;
+        }
+
+        Ok(())
     }
 
     fn alloc_page(&mut self) -> Result<Offset, Error> {
         if let Some(ffp) = self.config.first_free_page.to_owned() {
             // Find the first free page after ffp
-            let new_ffp = self.get_page(&ffp)?.get_usize_from_offset(0)?;
+            let new_ffp = read_usize(&self.get_page(&ffp)?, 0)?;
             // If there is none, we set first_free_page to None
             // Otherwise we use the next offset as our new ffp
             self.config.first_free_page = if new_ffp == 0 {
@@ -124,6 +374,7 @@ impl Pager {
                 Some(Offset(new_ffp))
             };
 
+            self.allocated.push(ffp.to_owned());
             Ok(ffp)
         } else {
             // If there is no available free page, we need to expand our file
@@ -132,6 +383,7 @@ impl Pager {
             // TODO: We may need to write 0s to the page - just to be safe!
             let alloc_ptr = self.curser;
             self.curser += PAGE_SIZE;
+            self.allocated.push(Offset(alloc_ptr));
             Ok(Offset(alloc_ptr))
         }
     }
@@ -219,81 +471,169 @@ impl Pager {
             // our first, referencing 0 (AKA last free page)
             self.write_page_at_offset(offset, &Page::new_empty())?;
             self.config.first_free_page = Some(offset.to_owned());
-            self.write_config()?;
         }
 
         Ok(())
     }
 
-    /// Write an object to disk and get the new offset
-    pub fn write_object(&mut self, object: &Vec<u8>) -> Result<Offset, Error> {
-        // TODO: This has to be more efficient in the future
-        if object.len() > PAGE_SIZE - PTR_SIZE {
-            todo!()
+    /// Write an object to disk and get the new offset. Objects take a single
+    /// page, and can't be longer than `OBJECT_MAX_SIZE`.
+    pub fn write_object(&mut self, object: &[u8]) -> Result<Offset, Error> {
+        if object.len() > OBJECT_MAX_SIZE {
+            return Err(Error::ValueOverflowError);
         }
 
         let mut data = [0_u8; PAGE_SIZE];
         data[0..PTR_SIZE].clone_from_slice(&object.len().to_be_bytes());
-        data[PTR_SIZE..PTR_SIZE + object.len()].clone_from_slice(&object);
-        self.write_page(&Page::from(data))
-    }
+        data[PTR_SIZE..PTR_SIZE + object.len()].clone_from_slice(object);
 
-    /// Free an object, deallocating the space
-    pub fn free_object(&mut self, offset: Offset) -> Result<(), Error> {
-        self.free_page(&offset)
+        let offset = self.alloc_page()?;
+        self.write_page_at_offset(&offset, &Page::from(data))?;
+        Ok(offset)
     }
 
     /// Get an object from an offset
-    pub fn get_object(&mut self, offset: &Offset) -> Result<Vec<u8>, Error> {
+    pub fn get_object(&self, offset: &Offset) -> Result<Vec<u8>, Error> {
         let p = self.get_page(offset)?;
-        let len = p.get_usize_from_offset(0)?;
+        let len = read_usize(&p, 0)?;
 
-        let out = p.get_data()[PTR_SIZE..PTR_SIZE + len].to_owned();
+        let out = p[PTR_SIZE..PTR_SIZE + len].to_owned();
 
         Ok(out)
     }
 }
 
+impl Drop for Pager {
+    fn drop(&mut self) {
+        // Don't leave the tail of a group commit unsynced on close. Errors
+        // cannot be reported from here, `BTree::close` reports them instead.
+        if let SyncMode::Group { .. } = self.sync_mode {
+            let _ = self.flush();
+        }
+    }
+}
+
 // fn make_pointer_page(ptr: usize) -> Page {
 //     let mut data = [0; PAGE_SIZE];
 //     data[0..PTR_SIZE].clone_from_slice(&ptr.to_be_bytes());
 //     Page::from(data)
 // }
 
+const COMPARATOR_NAME_OFFSET: usize = 2 * PTR_SIZE;
+const COMPARATOR_NAME_MAX_SIZE: usize = 0xff; // Length must fit in one byte
+const CATALOG_PAGE_OFFSET: usize = COMPARATOR_NAME_OFFSET + 1 + COMPARATOR_NAME_MAX_SIZE;
+const SEQUENCE_OFFSET: usize = CATALOG_PAGE_OFFSET + PTR_SIZE;
+const GENERATION_OFFSET: usize = SEQUENCE_OFFSET + PTR_SIZE;
+// The config page is told apart from other files by its magic, followed by
+// the version of the file format. Files written before the magic was added
+// have zeros in its place, and are rejected as their nodes are laid out in
+// another way.
+const MAGIC_OFFSET: usize = GENERATION_OFFSET + PTR_SIZE;
+const MAGIC: &[u8; 8] = b"inefficx";
+const FORMAT_VERSION_OFFSET: usize = MAGIC_OFFSET + MAGIC.len();
+const FORMAT_VERSION_SIZE: usize = size_of::<u32>();
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Default)]
 pub struct Config {
     pub(crate) root_page: Option<Offset>,
     first_free_page: Option<Offset>,
+    /// Name of the comparator the file was created with
+    comparator: Option<String>,
+    /// Root of the catalog tree, mapping the names of trees to their roots
+    pub(crate) catalog_page: Option<Offset>,
+    /// Sequence number of the last write
+    sequence: u64,
+    /// Number of commits, each stamping the nodes it writes
+    pub(crate) generation: u64,
 }
 
-impl Default for Config {
-    fn default() -> Self {
+impl Config {
+    /// Config of a compacted copy of the file, whose trees were moved to
+    /// other offsets, and which has no free pages
+    pub(crate) fn relocated(
+        &self,
+        root_page: Option<Offset>,
+        catalog_page: Option<Offset>,
+    ) -> Self {
         Self {
-            root_page: None,
+            root_page,
             first_free_page: None,
+            comparator: self.comparator.clone(),
+            catalog_page,
+            sequence: self.sequence,
+            generation: self.generation,
         }
     }
 }
 
-impl TryFrom<Page> for Config {
+impl TryFrom<&[u8]> for Config {
     type Error = Error;
-    fn try_from(page: Page) -> Result<Self, Self::Error> {
-        let root_page = page.get_usize_from_offset(0)?;
+    fn try_from(page: &[u8]) -> Result<Self, Self::Error> {
+        let magic = page
+            .get(MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len())
+            .ok_or(Error::NodeParseError)?;
+        let version = page
+            .get(FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE)
+            .ok_or(Error::NodeParseError)?;
+        let version = u32::from_be_bytes(version.try_into().unwrap());
+        if magic == [0; MAGIC.len()] {
+            return Err(Error::InvalidFormat(
+                "the file was written by an older version, with another node layout".to_owned(),
+            ));
+        }
+        if magic != MAGIC {
+            return Err(Error::InvalidFormat(
+                "the file is not a database".to_owned(),
+            ));
+        }
+        if version != FORMAT_VERSION {
+            return Err(Error::InvalidFormat(format!(
+                "unknown format version {}, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let root_page = read_usize(page, 0)?;
         let root_page = if root_page == 0 {
             None
         } else {
             Some(Offset(root_page))
         };
 
-        let first_free_page = page.get_usize_from_offset(PTR_SIZE)?;
+        let first_free_page = read_usize(page, PTR_SIZE)?;
         let first_free_page = if first_free_page == 0 {
             None
         } else {
             Some(Offset(first_free_page))
         };
 
+        let comparator_length = page[COMPARATOR_NAME_OFFSET] as usize;
+        let comparator = if comparator_length == 0 {
+            None
+        } else {
+            let start = COMPARATOR_NAME_OFFSET + 1;
+            let name = page[start..start + comparator_length].to_owned();
+            Some(String::from_utf8(name).map_err(|_| Error::NodeParseError)?)
+        };
+
+        let catalog_page = read_usize(page, CATALOG_PAGE_OFFSET)?;
+        let catalog_page = if catalog_page == 0 {
+            None
+        } else {
+            Some(Offset(catalog_page))
+        };
+
+        let sequence = read_usize(page, SEQUENCE_OFFSET)? as u64;
+        let generation = read_usize(page, GENERATION_OFFSET)? as u64;
+
         Ok(Config {
             root_page,
             first_free_page,
+            comparator,
+            catalog_page,
+            sequence,
+            generation,
         })
     }
 }
@@ -302,16 +642,36 @@ impl From<&Config> for Page {
     fn from(cfg: &Config) -> Self {
         let mut data = [0x00; PAGE_SIZE];
         if let Some(rp) = &cfg.root_page {
-            data[0..0 + PTR_SIZE].clone_from_slice(&rp.0.to_be_bytes());
+            data[0..PTR_SIZE].clone_from_slice(&rp.0.to_be_bytes());
         }
         if let Some(ffp) = &cfg.first_free_page {
             data[PTR_SIZE..2 * PTR_SIZE].clone_from_slice(&ffp.0.to_be_bytes());
         }
+        if let Some(name) = &cfg.comparator {
+            let start = COMPARATOR_NAME_OFFSET + 1;
+            data[COMPARATOR_NAME_OFFSET] = name.len() as u8;
+            data[start..start + name.len()].clone_from_slice(name.as_bytes());
+        }
+        if let Some(cp) = &cfg.catalog_page {
+            data[CATALOG_PAGE_OFFSET..CATALOG_PAGE_OFFSET + PTR_SIZE]
+                .clone_from_slice(&cp.0.to_be_bytes());
+        }
+        data[SEQUENCE_OFFSET..SEQUENCE_OFFSET + PTR_SIZE]
+            .clone_from_slice(&cfg.sequence.to_be_bytes());
+        data[GENERATION_OFFSET..GENERATION_OFFSET + PTR_SIZE]
+            .clone_from_slice(&cfg.generation.to_be_bytes());
+        data[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()].clone_from_slice(MAGIC);
+        data[FORMAT_VERSION_OFFSET..FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE]
+            .clone_from_slice(&FORMAT_VERSION.to_be_bytes());
 
         Page::new(data)
     }
 }
 
+/// A commit pinned by `Pager::pin`, until released by `Pager::unpin` or
+/// dropped
+pub struct CommitPin(Arc<()>);
+
 pub struct FreeQueue {
     q: Vec<Offset>,
 }
@@ -335,6 +695,146 @@ impl FreeQueue {
 }
 
 mod test {
+    #[test]
+    fn test_mmap_reads_pages_written_after_open() {
+        use super::{FreeQueue, Offset, Pager};
+        use crate::{options::Options, page::Page, PAGE_SIZE};
+
+        let path = std::env::temp_dir().join("inefficax_test_mmap.db");
+        let _ = std::fs::remove_file(&path);
+
+        let options = Options {
+            mmap: true,
+            ..Default::default()
+        };
+        let mut pager = Pager::open(&path, &options).unwrap();
+        let mut data = [0_u8; PAGE_SIZE];
+        data[0] = 42;
+        let offset = pager.write_page(&Page::from(data)).unwrap();
+
+        // Not yet mapped, read from the file
+        assert_eq!(pager.get_page(&offset).unwrap()[0], 42);
+
+        // Committing remaps the grown file
+        pager.set_root_page(offset.clone());
+        pager.commit(FreeQueue::new()).unwrap();
+        assert!(matches!(
+            pager.get_page(&offset).unwrap(),
+            crate::page::PageRef::Borrowed(_)
+        ));
+        assert_eq!(pager.get_page(&offset).unwrap()[0], 42);
+        assert_eq!(pager.config.root_page, Some(Offset(PAGE_SIZE)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_format_version() {
+        use super::{Pager, FORMAT_VERSION_OFFSET, MAGIC, MAGIC_OFFSET};
+        use crate::{error::Error, options::Options};
+        use std::{
+            fs::OpenOptions,
+            io::{Seek, SeekFrom, Write},
+        };
+
+        let path = std::env::temp_dir().join("inefficax_test_format_version.db");
+        let _ = std::fs::remove_file(&path);
+        let overwrite = |offset: usize, bytes: &[u8]| {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            file.write_all(bytes).unwrap();
+        };
+        let options = Options::default();
+        drop(Pager::open(&path, &options).unwrap());
+
+        // Files from before the magic have zeros in its place, and nodes
+        // laid out in another way
+        overwrite(MAGIC_OFFSET, &[0; 12]);
+        assert!(matches!(
+            Pager::open(&path, &options),
+            Err(Error::InvalidFormat(message)) if message.contains("older version")
+        ));
+
+        overwrite(MAGIC_OFFSET, MAGIC);
+        overwrite(FORMAT_VERSION_OFFSET, &2_u32.to_be_bytes());
+        assert!(matches!(
+            Pager::open(&path, &options),
+            Err(Error::InvalidFormat(message)) if message.contains("version 2")
+        ));
+        overwrite(MAGIC_OFFSET, b"notadb!!");
+        assert!(matches!(
+            Pager::open(&path, &options),
+            Err(Error::InvalidFormat(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sync_modes() {
+        use super::{FreeQueue, Pager};
+        use crate::options::{Options, SyncMode};
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join("inefficax_test_sync_modes.db");
+        let open = |sync_mode| {
+            let _ = std::fs::remove_file(&path);
+            let options = Options {
+                sync_mode,
+                ..Default::default()
+            };
+            Pager::open(&path, &options).unwrap()
+        };
+
+        // A group is synced once it has `ops` commits
+        let mut pager = open(SyncMode::Group {
+            interval: Duration::from_secs(3600),
+            ops: 3,
+        });
+        pager.commit(FreeQueue::new()).unwrap();
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 2);
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+
+        // The tail of a group stays unsynced until flushed
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 1);
+        pager.flush().unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+        drop(pager);
+
+        // Or once the interval has passed
+        let mut pager = open(SyncMode::Group {
+            interval: Duration::ZERO,
+            ops: 1000,
+        });
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+        drop(pager);
+
+        let mut pager = open(SyncMode::EveryCommit);
+        let offset = pager.write_object(&[1; 100]).unwrap();
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+        let mut fq = FreeQueue::new();
+        fq.add(offset.clone());
+        pager.commit(fq).unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+        assert_eq!(pager.config.first_free_page, Some(offset));
+        drop(pager);
+
+        let mut pager = open(SyncMode::Never);
+        pager.commit(FreeQueue::new()).unwrap();
+        pager.commit(FreeQueue::new()).unwrap();
+        assert_eq!(pager.unsynced_commits, 2);
+        pager.sync().unwrap();
+        assert_eq!(pager.unsynced_commits, 0);
+        drop(pager);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_free_queue() {
         use super::{FreeQueue, Offset};
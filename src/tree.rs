@@ -0,0 +1,266 @@
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{
+    btree::{prefix_range, BTree, Neighbor, ObjectRange},
+    encoding::{Key, KeyPrefix, Value},
+    error::Error,
+    options::Options,
+};
+
+/// A typed wrapper over a `BTree`, encoding keys with `Key` and storing
+/// values as objects encoded with `Value`.
+///
+/// Key order is only preserved by the default bytewise comparator.
+pub struct Tree<K, V> {
+    btree: BTree,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Key, V: Value> Tree<K, V> {
+    pub fn open(db_fp: &Path) -> Result<Self, Error> {
+        Ok(Self::from(BTree::open(db_fp)?))
+    }
+
+    pub fn open_with_options(db_fp: &Path, options: Options) -> Result<Self, Error> {
+        Ok(Self::from(BTree::open_with_options(db_fp, options)?))
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        self.btree
+            .insert_object(key.to_key_bytes(), value.encode_value())
+    }
+
+    /// Insert a value which expires at some time, see `BTree::insert_with_expiry`
+    pub fn insert_with_expiry(
+        &mut self,
+        key: &K,
+        value: &V,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        self.btree
+            .insert_object_with_expiry(key.to_key_bytes(), value.encode_value(), expires_at)
+    }
+
+    /// Delete every expired key, returning the number of deleted keys
+    pub fn purge_expired(&mut self) -> Result<u64, Error> {
+        self.btree.purge_expired()
+    }
+
+    pub fn search(&mut self, key: &K) -> Result<Option<V>, Error> {
+        self.btree
+            .search_object(key.to_key_bytes())?
+            .map(|bytes| V::decode_value(&bytes))
+            .transpose()
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<(), Error> {
+        self.btree.delete_object(key.to_key_bytes())
+    }
+
+    /// Fill an empty tree with pairs sorted by key, see `BTree::bulk_load`
+    pub fn bulk_load(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+    ) -> Result<(), Error> {
+        self.btree.bulk_load_objects(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.to_key_bytes(), value.encode_value())),
+            fill_factor,
+        )
+    }
+
+    /// Delete every key within a range, returning the number of deleted keys
+    pub fn delete_range(&mut self, range: impl RangeBounds<K>) -> Result<u64, Error> {
+        let start = encoded_bound(range.start_bound());
+        let end = encoded_bound(range.end_bound());
+
+        self.btree.delete_range((start, end))
+    }
+
+    /// Iterate over the keys within a range and their values, in key order
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<Range<'_, K, V>, Error> {
+        let start = encoded_bound(range.start_bound());
+        let end = encoded_bound(range.end_bound());
+
+        Ok(Range {
+            objects: self.btree.range_objects((start, end))?,
+            _types: PhantomData,
+        })
+    }
+
+    /// Iterate over the keys starting with some prefix, in key order. For
+    /// tuple keys, the prefix is a tuple of their first components, such as
+    /// `(tenant_id,)` or `(tenant_id, table)` for `(tenant_id, table, row_id)`
+    /// keys.
+    ///
+    /// Since every component is self-delimiting, a prefix only matches whole
+    /// components, so that the prefix `("ab",)` does not match `("abc", 1)`.
+    pub fn scan_prefix<P: KeyPrefix<K>>(&self, prefix: &P) -> Result<Range<'_, K, V>, Error> {
+        Ok(Range {
+            objects: self
+                .btree
+                .range_objects(prefix_range(&prefix.to_key_bytes()))?,
+            _types: PhantomData,
+        })
+    }
+
+    /// Number of keys in the tree, purging the expired ones first
+    pub fn len(&mut self) -> Result<u64, Error> {
+        self.btree.len()
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        self.btree.is_empty()
+    }
+
+    /// Number of keys within a range, without walking the keys
+    pub fn count_range(&mut self, range: impl RangeBounds<K>) -> Result<u64, Error> {
+        let start = encoded_bound(range.start_bound());
+        let end = encoded_bound(range.end_bound());
+
+        self.btree.count_range((start, end))
+    }
+
+    /// Number of keys smaller than a key
+    pub fn rank(&mut self, key: &K) -> Result<u64, Error> {
+        self.btree.rank(key.to_key_bytes())
+    }
+
+    /// The key at some position in key order, starting from 0
+    pub fn nth(&mut self, n: u64) -> Result<Option<K>, Error> {
+        self.btree
+            .nth(n)?
+            .map(|key| K::from_key_bytes(&key))
+            .transpose()
+    }
+
+    /// The first key and its value
+    pub fn first(&self) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::First)
+    }
+
+    /// The last key and its value
+    pub fn last(&self) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::Last)
+    }
+
+    /// The greatest key smaller than or equal to a key, and its value
+    pub fn floor(&self, key: &K) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::Floor(&key.to_key_bytes()))
+    }
+
+    /// The smallest key greater than or equal to a key, and its value
+    pub fn ceiling(&self, key: &K) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::Ceiling(&key.to_key_bytes()))
+    }
+
+    /// The greatest key strictly smaller than a key, and its value
+    pub fn lower(&self, key: &K) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::Lower(&key.to_key_bytes()))
+    }
+
+    /// The smallest key strictly greater than a key, and its value
+    pub fn higher(&self, key: &K) -> Result<Option<(K, V)>, Error> {
+        self.neighbor(Neighbor::Higher(&key.to_key_bytes()))
+    }
+
+    fn neighbor(&self, neighbor: Neighbor) -> Result<Option<(K, V)>, Error> {
+        self.btree
+            .neighbor_object(neighbor)?
+            .map(decode_entry)
+            .transpose()
+    }
+
+    /// The untyped tree, for what isn't covered by the typed interface
+    pub fn btree(&mut self) -> &mut BTree {
+        &mut self.btree
+    }
+
+    pub fn into_inner(self) -> BTree {
+        self.btree
+    }
+}
+
+impl<K: Key, V: Value> From<BTree> for Tree<K, V> {
+    fn from(btree: BTree) -> Self {
+        Self {
+            btree,
+            _types: PhantomData,
+        }
+    }
+}
+
+/// Iterator over the keys in a range of a `Tree` and their values
+pub struct Range<'a, K, V> {
+    objects: ObjectRange<'a>,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Key, V: Value> Iterator for Range<'_, K, V> {
+    type Item = Result<(K, V), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.objects.next()?.and_then(decode_entry))
+    }
+}
+
+fn decode_entry<K: Key, V: Value>((key, value): (Vec<u8>, Vec<u8>)) -> Result<(K, V), Error> {
+    Ok((K::from_key_bytes(&key)?, V::decode_value(&value)?))
+}
+
+fn encoded_bound<K: Key>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_key_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_key_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+mod test {
+    #[test]
+    fn test_scan_tuple_prefix() {
+        use super::Tree;
+
+        let path = std::env::temp_dir().join("inefficax_test_tuple_prefix.db");
+        let _ = std::fs::remove_file(&path);
+        let mut db: Tree<(u32, String, u64), String> = Tree::open(&path).unwrap();
+
+        for tenant in [1, 2, 256] {
+            for table in ["ab", "ab\0", "abc", "b"] {
+                for row in [1, 2, 10, 300] {
+                    let key = (tenant, table.to_owned(), row);
+                    db.insert(&key, &format!("{:?}", key)).unwrap();
+                }
+            }
+        }
+
+        let rows = |db: &Tree<(u32, String, u64), String>, prefix| -> Vec<u64> {
+            db.scan_prefix(&prefix)
+                .unwrap()
+                .map(|kv| kv.unwrap().0 .2)
+                .collect()
+        };
+
+        // Only whole components match, whatever bytes the strings hold
+        assert_eq!(rows(&db, (2, "ab".to_owned())), vec![1, 2, 10, 300]);
+        assert_eq!(rows(&db, (2, "ab\0".to_owned())), vec![1, 2, 10, 300]);
+
+        let tenant: Vec<(u32, String, u64)> = db
+            .scan_prefix(&(2,))
+            .unwrap()
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        assert_eq!(tenant.len(), 16);
+        assert!(tenant.windows(2).all(|w| w[0] < w[1]));
+        assert!(tenant.iter().all(|k| k.0 == 2));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}